@@ -0,0 +1,139 @@
+use std::io;
+use std::io::{Cursor, Error, ErrorKind, Read};
+
+use crate::characters::{read_character_data, CharacterMaps};
+use crate::fonts::FontIndex;
+use crate::language::Language;
+
+///
+/// The character maps, font and language files of a release archive
+///
+pub struct LanguageBundle {
+    pub maps: CharacterMaps,
+    pub fonts: Option<FontIndex>,
+    pub languages: Vec<(String, Language)>,
+}
+
+///
+/// Read a tar archive holding a CharacterMaps.xml, optionally a .bft font
+/// file, and .bin language files, without extracting it to disk
+///
+pub fn read_tar_archive<R: Read>(reader: &mut R) -> io::Result<LanguageBundle> {
+    let members = read_tar_members(reader)?;
+
+    let maps = match members.iter().find(|(name, _)| name.ends_with(".xml")) {
+        Some((_, data)) => read_character_data(data).map_err(|x| Error::new(ErrorKind::InvalidData, x))?,
+        None => return Err(Error::new(ErrorKind::NotFound, "No character maps in archive")),
+    };
+
+    let fonts = match members.iter().find(|(name, _)| name.ends_with(".bft")) {
+        Some((_, data)) => Some(FontIndex::from_bytes(data)?),
+        None => None,
+    };
+
+    let mut languages = Vec::new();
+    for (name, data) in &members {
+        if name.ends_with(".bin") {
+            let language = Language::create_from_file(&mut Cursor::new(data), maps.clone())?;
+            languages.push((name.clone(), language));
+        }
+    }
+
+    Ok(LanguageBundle { maps, fonts, languages })
+}
+
+///
+/// Read the regular files of a tar archive as (name, contents)
+///
+fn read_tar_members<R: Read>(reader: &mut R) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut members = Vec::new();
+    let mut header = [0; 512];
+
+    loop {
+        reader.read_exact(&mut header)?;
+        // The archive ends with zero filled blocks
+        if header.iter().all(|x| *x == 0) {
+            break;
+        }
+        let name = tar_string(&header[0..100]);
+        let prefix = tar_string(&header[345..500]);
+        let size = tar_octal(&header[124..136])?;
+        let type_flag = header[156];
+
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data)?;
+
+        // Member data is padded to a whole block
+        let padding = (512 - size % 512) % 512;
+        io::copy(&mut reader.take(padding as u64), &mut io::sink())?;
+
+        if type_flag == b'0' || type_flag == 0 {
+            let name = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            members.push((name, data));
+        }
+    }
+    Ok(members)
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+fn tar_octal(field: &[u8]) -> io::Result<usize> {
+    let text = tar_string(field);
+    match usize::from_str_radix(text.trim(), 8) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, format!("Bad tar size field '{}'", text))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    ///
+    /// A tar archive of the files, without checksums as they are not checked
+    ///
+    fn tar_of(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, data) in files {
+            let mut header = [0; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+            header[156] = b'0';
+            archive.extend(header);
+            archive.extend(data);
+            archive.resize(archive.len().div_ceil(512) * 512, 0);
+        }
+        archive.resize(archive.len() + 1024, 0);
+        archive
+    }
+
+    #[test]
+    fn bundle_from_an_in_memory_archive() {
+        let archive = tar_of(&[
+            ("release/CharacterMaps.xml", fs::read("CharacterMaps.xml").unwrap()),
+            ("release/fonts.bft", fs::read("fonts.bft").unwrap()),
+            ("release/languageV3.bin", fs::read("languageV3.bin").unwrap()),
+        ]);
+        let bundle = read_tar_archive(&mut Cursor::new(archive)).unwrap();
+        assert!(bundle.fonts.is_some());
+        assert_eq!(bundle.languages.len(), 1);
+        let (name, language) = &bundle.languages[0];
+        assert_eq!(name, "release/languageV3.bin");
+
+        let maps = crate::characters::read_character_file("CharacterMaps.xml").unwrap();
+        let expected = crate::language::read_language_file("languageV3.bin", maps).unwrap();
+        assert_eq!(language.get_file_crc(), expected.get_file_crc());
+        assert_eq!(language.flatten().len(), expected.flatten().len());
+    }
+
+    #[test]
+    fn archive_without_character_maps() {
+        let archive = tar_of(&[("languageV3.bin", fs::read("languageV3.bin").unwrap())]);
+        let error = read_tar_archive(&mut Cursor::new(archive)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::blob::BlobRegions;
+use crate::flatten::{IdPath, ProductKey};
+use crate::language::Language;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+///
+/// Entries of one region compared against a reference Language
+///
+#[derive(Default, Debug)]
+pub struct RegionCoverage {
+    pub identical: usize, // Same text as the reference, likely untranslated
+    pub differing: usize,
+    pub unmatched: usize, // Not in the reference, or failed to decode
+}
+
+#[derive(Default, Debug)]
+pub struct CoverageStats {
+    pub regions: HashMap<BlobRegions, RegionCoverage>,
+}
+
+impl CoverageStats {
+    pub fn identical(&self) -> usize {
+        self.regions.values().map(|x| x.identical).sum()
+    }
+
+    pub fn differing(&self) -> usize {
+        self.regions.values().map(|x| x.differing).sum()
+    }
+
+    pub fn unmatched(&self) -> usize {
+        self.regions.values().map(|x| x.unmatched).sum()
+    }
+}
+
+impl Language {
+    ///
+    /// Compare the strings with those of a reference Language (of another
+    /// locale), matching entries by IdPath
+    ///
+    pub fn translation_coverage(&self, reference: &Language) -> CoverageStats {
+        let mut reference_text = HashMap::new();
+        for entry in reference.flatten() {
+            if let Some(text) = entry.decoded() {
+                reference_text.insert(entry.path, text);
+            }
+        }
+
+        let mut stats = CoverageStats::default();
+        for entry in self.flatten() {
+            let region = stats.regions.entry(entry.path.region()).or_default();
+            match (entry.decoded(), reference_text.get(&entry.path)) {
+                (Some(text), Some(reference)) if text == *reference => region.identical += 1,
+                (Some(_), Some(_)) => region.differing += 1,
+                _ => region.unmatched += 1,
+            }
+        }
+        stats
+    }
+
+    ///
+    /// Strings stored more than once with identical bytes, that the packer
+    /// could have pooled. Each is returned with the offsets holding it and
+    /// the bytes (including any terminators) wasted, most wasteful first.
+    ///
+    pub fn pooling_opportunities(&self) -> Vec<(String, Vec<u32>, usize)> {
+        let mut copies = HashMap::<Vec<u8>, (String, usize, Vec<u32>)>::new();
+        for entry in self.flatten() {
+            let mut strings = vec![(entry.caption_off, entry.caption)];
+            if let Some(tooltip) = entry.tooltip {
+                strings.push((entry.tooltip_off, tooltip));
+            }
+            for (off, text) in strings {
+                let text = match text {
+                    Ok(x) if off != 0 => x,
+                    _ => continue,
+                };
+                let bytes = self.get_raw_bytes(off, entry.str_len);
+                // A string of the maximum length has no terminator
+                let stored_len = bytes.len().min((entry.str_len as usize).saturating_sub(1)) + 1;
+                let (_, _, offsets) = copies.entry(bytes).or_insert_with(|| (text, stored_len, Vec::new()));
+                if !offsets.contains(&off) {
+                    offsets.push(off);
+                }
+            }
+        }
+
+        let mut opportunities = Vec::new();
+        for (text, stored_len, mut offsets) in copies.into_values() {
+            if offsets.len() > 1 {
+                offsets.sort();
+                let wasted = stored_len * (offsets.len() - 1);
+                opportunities.push((text, offsets, wasted));
+            }
+        }
+        opportunities.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        opportunities
+    }
+
+    ///
+    /// Groups of captions that are the same when trimmed and case folded,
+    /// but are not identical, e.g. "Enable" and "enable ". Groups are
+    /// sorted by their folded caption, and their entries by location.
+    ///
+    pub fn near_duplicate_captions(&self) -> Vec<Vec<(IdPath, String)>> {
+        let mut folded = BTreeMap::<String, Vec<(IdPath, String)>>::new();
+        for entry in self.flatten() {
+            if let Ok(caption) = entry.caption {
+                folded.entry(caption.trim().to_lowercase()).or_default().push((entry.path, caption));
+            }
+        }
+
+        let mut groups = Vec::new();
+        for (_, mut group) in folded {
+            if group.iter().any(|(_, caption)| *caption != group[0].1) {
+                group.sort();
+                groups.push(group);
+            }
+        }
+        groups
+    }
+
+    ///
+    /// A hash of the decoded content, the (location, caption, tooltip) of
+    /// every entry in location order, so files that differ only in their
+    /// layout fingerprint the same. FNV-1a, so the value is stable.
+    ///
+    pub fn content_fingerprint(&self) -> u64 {
+        let mut entries = self.flatten();
+        entries.sort_by_key(|x| x.path);
+
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut add = |bytes: &[u8]| {
+            for byte in bytes.iter().chain([0u8].iter()) {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+        for entry in entries {
+            add(entry.path.to_string().as_bytes());
+            for text in [Some(entry.caption), entry.tooltip] {
+                match text {
+                    Some(Ok(x)) => add(format!("+{}", x).as_bytes()),
+                    // The error names the offset, which is layout
+                    Some(Err(_)) => add(b"!"),
+                    None => add(b""),
+                }
+            }
+        }
+        hash
+    }
+
+    ///
+    /// Groups of products whose mode trees decode the same but are stored
+    /// separately, at different offsets, rather than shared. Each product
+    /// is returned with the offset of its ModeIndex.
+    ///
+    pub fn duplicate_mode_subtrees(&self) -> Vec<Vec<(ProductKey, u32)>> {
+        let tree = self.to_tree();
+        let mut groups: Vec<(usize, Vec<(ProductKey, u32)>)> = Vec::new();
+        for (i, (product, entry)) in tree.products.iter().zip(self.get_products().iter()).enumerate() {
+            let modes_off = entry.get_modes_off();
+            if modes_off == 0 {
+                continue;
+            }
+            match groups.iter_mut().find(|(first, _)| tree.products[*first].modes == product.modes) {
+                Some((_, group)) => group.push((product.key, modes_off)),
+                None => groups.push((i, vec![(product.key, modes_off)])),
+            }
+        }
+
+        let mut duplicates = Vec::new();
+        for (_, group) in groups {
+            if group.iter().any(|(_, off)| *off != group[0].1) {
+                duplicates.push(group);
+            }
+        }
+        duplicates
+    }
+
+    ///
+    /// The entries whose tooltip offset is their caption offset, a packer
+    /// bug that makes the tooltip repeat the caption
+    ///
+    pub fn caption_equals_tooltip(&self) -> Vec<IdPath> {
+        self.flatten().into_iter()
+            .filter(|entry| entry.tooltip_off != 0 && entry.tooltip_off == entry.caption_off)
+            .map(|entry| entry.path)
+            .collect()
+    }
+
+    ///
+    /// The parameters whose unit reference is not in the units index, the
+    /// device shows no unit symbol for them. Only schemas with a unit
+    /// reference in the parameter entry can have any.
+    ///
+    pub fn dangling_unit_refs(&self) -> Vec<(IdPath, u16)> {
+        let mut refs = Vec::new();
+        for product in self.get_products().iter() {
+            let (derivative_id_low, derivative_id_high) = product.get_derivative_ids();
+            let product_key = ProductKey {
+                product_id: product.get_product_id(),
+                derivative_id_low,
+                derivative_id_high,
+            };
+            for (mode, mode_entry) in product.get_modes().iter() {
+                for (menu, menu_entry) in mode_entry.get_menus().iter() {
+                    for (param, param_entry) in menu_entry.get_params().iter() {
+                        if let Some(unit) = param_entry.get_units_ref() {
+                            refs.push((IdPath::Param { product: product_key, mode, menu, param }, unit));
+                        }
+                    }
+                }
+            }
+        }
+        self.missing_units(refs)
+    }
+
+    ///
+    /// The unit references of refs whose unit is not in the units index
+    ///
+    fn missing_units(&self, refs: Vec<(IdPath, u16)>) -> Vec<(IdPath, u16)> {
+        let units: HashSet<u16> = self.get_units().iter().map(|(unit, _)| unit).collect();
+        refs.into_iter().filter(|(_, unit)| !units.contains(unit)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+    use crate::conversion::little_endian_4_bytes;
+    use crate::fixtures::{parse_edited, v4_units_file};
+    use crate::options::ParseOptions;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+
+    #[test]
+    fn partly_translated_copy_differs_where_edited() {
+        let original = parse_edited("languageV3.bin", |_| {}).unwrap();
+        let mut edited_offs = HashSet::new();
+        for entry in original.flatten() {
+            if entry.path.region() == BlobRegions::Parameters && entry.caption.as_ref().is_ok_and(|x| !x.starts_with('X')) {
+                edited_offs.insert(entry.caption_off);
+                if edited_offs.len() == 3 {
+                    break;
+                }
+            }
+        }
+        let translated = parse_edited("languageV3.bin", |data| {
+            for off in &edited_offs {
+                data[*off as usize] = b'X';
+            }
+        }).unwrap();
+
+        let expected = original.flatten().into_iter()
+            .filter(|entry| edited_offs.contains(&entry.caption_off) || edited_offs.contains(&entry.tooltip_off))
+            .count();
+        let stats = translated.translation_coverage(&original);
+        assert!(expected >= 3);
+        assert_eq!(stats.differing(), expected);
+        assert_eq!(stats.unmatched(), 0);
+        assert_eq!(stats.identical(), original.flatten().len() - expected);
+        assert_eq!(stats.regions[&BlobRegions::Parameters].differing, expected);
+    }
+
+    #[test]
+    fn string_at_three_offsets_wastes_two_copies() {
+        let original = parse_edited("languageV3.bin", |_| {}).unwrap();
+        let mut offs = Vec::new();
+        for entry in original.flatten() {
+            let long = entry.caption.as_ref().is_ok_and(|x| x.len() >= 3);
+            if entry.path.region() == BlobRegions::Parameters && long && !offs.contains(&entry.caption_off) {
+                offs.push(entry.caption_off);
+                if offs.len() == 3 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(offs.len(), 3);
+        let duplicated = parse_edited("languageV3.bin", |data| {
+            for off in &offs {
+                data[*off as usize..*off as usize + 4].copy_from_slice(b"Zqx\0");
+            }
+        }).unwrap();
+
+        let opportunities = duplicated.pooling_opportunities();
+        let (_, found_offs, wasted) = opportunities.iter().find(|(text, _, _)| text == "Zqx").unwrap();
+        offs.sort();
+        assert_eq!(*found_offs, offs);
+        assert_eq!(*wasted, 8);
+    }
+
+    #[test]
+    fn on_and_on_space_are_grouped() {
+        let original = parse_edited("languageV3.bin", |_| {}).unwrap();
+        let mut units = Vec::new();
+        for (unit, entry) in original.get_units().iter() {
+            let long = entry.caption().is_ok_and(|x| x.len() >= 3);
+            // Strings may share a tail, keep the edits apart
+            if long && units.iter().all(|(_, off)| entry.get_caption_off().abs_diff(*off) >= 4) {
+                units.push((unit, entry.get_caption_off()));
+            }
+        }
+        let ((on, on_off), (on_space, on_space_off)) = (units[0], units[1]);
+        let edited = parse_edited("languageV3.bin", |data| {
+            data[on_off as usize..on_off as usize + 3].copy_from_slice(b"On\0");
+            data[on_space_off as usize..on_space_off as usize + 4].copy_from_slice(b"on \0");
+        }).unwrap();
+
+        let groups = edited.near_duplicate_captions();
+        let group = groups.iter().find(|group| group.iter().any(|(path, _)| *path == IdPath::Unit(on))).unwrap();
+        assert!(group.contains(&(IdPath::Unit(on), "On".to_string())));
+        assert!(group.contains(&(IdPath::Unit(on_space), "on ".to_string())));
+        assert!(group.iter().all(|(_, caption)| caption.trim().to_lowercase() == "on"));
+    }
+
+    #[test]
+    fn moved_string_keeps_the_fingerprint() {
+        let original = parse_edited("languageV3.bin", |_| {}).unwrap();
+        let (unit, entry) = original.get_units().iter().next().unwrap();
+        let caption_off = entry.get_caption_off();
+        let text = original.get_raw_bytes(caption_off, entry.get_str_len());
+
+        // Copy the unit's caption to the end of the file and point its V3
+        // index entry (id then 3 byte offset) at the copy
+        let moved = parse_edited("languageV3.bin", |data| {
+            let mut index_entry = unit.to_le_bytes().to_vec();
+            index_entry.extend(&caption_off.to_le_bytes()[..3]);
+            let at: Vec<usize> = (0..data.len() - 5).filter(|i| data[*i..*i + 5] == index_entry[..]).collect();
+            assert_eq!(at.len(), 1);
+            let new_off = data.len() as u32;
+            data[at[0] + 2..at[0] + 5].copy_from_slice(&new_off.to_le_bytes()[..3]);
+            data.extend(&text);
+            data.push(0);
+            let file_len = data.len() as u32;
+            data[0..4].copy_from_slice(&file_len.to_le_bytes());
+        }).unwrap();
+        assert_ne!(moved.get_units().get(unit).unwrap().get_caption_off(), caption_off);
+        assert_eq!(moved.content_fingerprint(), original.content_fingerprint());
+
+        let edited = parse_edited("languageV3.bin", |data| data[caption_off as usize] = b'X').unwrap();
+        assert_ne!(edited.content_fingerprint(), original.content_fingerprint());
+    }
+
+    #[test]
+    fn separately_stored_identical_modes_are_flagged() {
+        let original = parse_edited("languageV3.bin", |_| {}).unwrap();
+        let products: Vec<(ProductKey, u32)> = original.get_products().iter()
+            .map(|x| (ProductKey {
+                product_id: x.get_product_id(),
+                derivative_id_low: x.get_derivative_ids().0,
+                derivative_id_high: x.get_derivative_ids().1,
+            }, x.get_modes_off()))
+            .collect();
+        let (first, first_off) = products[0];
+        let (second, second_off) = products.iter().copied()
+            .find(|(_, off)| *off != 0 && *off != first_off)
+            .unwrap();
+
+        // Copy the first product's V3 mode index (count, length and 3 byte
+        // offsets) to the end of the file, and point the second product at it
+        let copied = parse_edited("languageV3.bin", |data| {
+            let off = first_off as usize;
+            let mode_index = data[off..off + 2 + 3 * data[off] as usize].to_vec();
+            let new_off = data.len() as u32;
+            data.extend(mode_index);
+            let file_len = data.len() as u32;
+            data[0..4].copy_from_slice(&file_len.to_le_bytes());
+
+            let products_off = little_endian_4_bytes(&[data[36], data[37], data[38], 0]) as usize;
+            let entry = (0..data[products_off] as usize)
+                .map(|i| products_off + 2 + 11 * i)
+                .find(|entry| data[entry + 8..entry + 11] == second_off.to_le_bytes()[..3])
+                .unwrap();
+            assert_eq!(data[entry..entry + 2], second.product_id.to_le_bytes());
+            data[entry + 8..entry + 11].copy_from_slice(&new_off.to_le_bytes()[..3]);
+        }).unwrap();
+
+        let groups = copied.duplicate_mode_subtrees();
+        let group = groups.iter().find(|group| group.iter().any(|(key, _)| *key == second)).unwrap();
+        assert!(group.contains(&(first, first_off)), "{:?}", group);
+        assert!(!original.duplicate_mode_subtrees().iter().any(|group| group.iter().any(|(key, _)| *key == second)));
+    }
+
+    #[test]
+    fn caption_reused_as_tooltip_is_flagged() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut Cursor::new(v4_units_file()), maps, &options).unwrap();
+        assert_eq!(lang.get_units().get(2).unwrap().to_string(), Ok("Same / Other".to_string()));
+        assert_eq!(lang.caption_equals_tooltip(), [IdPath::Unit(1)]);
+    }
+
+    #[test]
+    fn param_with_a_missing_unit_is_flagged() {
+        let lang = parse_edited("languageV3.bin", |_| {}).unwrap();
+        assert!(lang.dangling_unit_refs().is_empty());
+
+        // No schema stores a unit id yet, so check references as one would
+        let params: Vec<IdPath> = lang.flatten().into_iter()
+            .map(|entry| entry.path)
+            .filter(|path| matches!(path, IdPath::Param { .. }))
+            .take(2)
+            .collect();
+        let (unit, _) = lang.get_units().iter().next().unwrap();
+        let missing = (0..=u16::MAX).find(|id| lang.get_units().get(*id).is_none()).unwrap();
+        let refs = vec![(params[0], unit), (params[1], missing)];
+        assert_eq!(lang.missing_units(refs), [(params[1], missing)]);
+    }
+}
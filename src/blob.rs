@@ -1,13 +1,23 @@
-use std::fs::File;
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
+use crate::conversion::{crc32, little_endian_4_bytes};
 use crate::characters::CharacterMaps;
+use crate::diagnostics::{panic_message, Diagnostic, Severity};
+use crate::options::ParseOptions;
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+///
+/// The header CRC covers the file from this offset to the end, everything
+/// after the file length and the CRC itself
+///
+pub const CRC_COVERS_FROM: usize = 8;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum BlobRegions {
     Empty,
     Header,
@@ -20,30 +30,68 @@ pub enum BlobRegions {
     Mnemonics,
     KeypadStrs,
     Text,
+    Other(u16), // A section not yet understood, labelled by whoever is exploring it
     Invalid
 }
 
 ///
-/// Collect some stats
+/// Collect some stats. They are kept behind a Mutex, as the blob is
+/// shared (Arc) by every RawBlob, so strings decoded from several threads
+/// are all counted. Each decode locks the stats only while it updates them.
+/// A string keeps the set of offsets it was found at, rather than a count,
+/// so the duplicates do not depend on the order the strings are decoded in.
 ///
 struct Stats {
     regions: Vec<BlobRegions>,
-    string_offsets : HashMap<String, (u32, u32)>,
+    string_offsets : HashMap<String, HashSet<u32>>, // String => offsets it is stored at
+    entry_layouts: HashMap<BlobRegions, u8>, // Index entry length validated per region
+    total_entries: u32, // Index entries over all the tables, see ParseLimits
+    decoded_offsets: HashSet<u32>, // Distinct string offsets decoded, see ParseLimits
+    timings: Option<Timings>,
+}
+
+///
+/// The string and region statistics of a parsed blob
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobStats {
+    pub duplicates: Vec<(String, u32)>, // Strings stored at more than one offset, with the number of extra copies
+    pub regions: Vec<(usize, usize, BlobRegions)>, // Runs of bytes (start, end exclusive) of one region, in file order
+    pub unused_bytes: usize, // Bytes in no region, not counting the zero padding at the end
+}
+
+///
+/// Time spent in each region, excluding the time spent in regions
+/// nested within it
+///
+struct Timings {
+    elapsed: HashMap<BlobRegions, Duration>,
+    stack: Vec<(BlobRegions, Instant, Duration)>,
 }
 
 struct _Blob {
     data: Vec<u8>,
     maps: CharacterMaps,
-    stats: RefCell<Stats>
+    options: ParseOptions,
+    stats: Mutex<Stats>,
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    tooltip_cache: Mutex<HashMap<u32, Result<String, String>>>,
 }
 
+///
+/// Callback given the FileBlob as it is dropped
+///
+pub type StatsCallback = Box<dyn Fn(&FileBlob)>;
+
 pub struct FileBlob {
-    data: Rc<_Blob>,
+    data: Arc<_Blob>,
     pos: usize,
+    on_drop: Option<StatsCallback>,
 }
 
 pub struct RawBlob {
-    data: Rc<_Blob>,
+    data: Arc<_Blob>,
+    region: BlobRegions, // The region whose entries hold the strings
 }
 
 impl FileBlob {
@@ -51,46 +99,227 @@ impl FileBlob {
         self.pos = pos as usize;
     }
 
-    pub fn freeze(&mut self) -> RawBlob {
+    pub fn get_pos(&self) -> u32 {
+        self.pos as u32
+    }
+
+    pub fn add_diagnostic(&self, severity: Severity, region: BlobRegions, location: u32, message: &str) {
+        self.data.add_diagnostic(severity, region, location, message);
+    }
+
+    ///
+    /// The max string length for region, the schema's default unless
+    /// overridden by the parse options
+    ///
+    pub fn max_str_len(&self, region: BlobRegions, default: u16) -> u16 {
+        match self.data.options.max_str_lens.get(&region) {
+            Some(x) => *x,
+            None => default,
+        }
+    }
+
+    ///
+    /// The max number of mnemonics in an index, default unless
+    /// overridden by the parse options
+    ///
+    pub fn max_mnemonics(&self, default: u16) -> u16 {
+        self.data.options.max_mnemonics.unwrap_or(default)
+    }
+
+    ///
+    /// The max number of modes of a product, default unless overridden
+    /// by the parse options
+    ///
+    pub fn max_modes(&self, default: u8) -> u8 {
+        self.data.options.max_modes.unwrap_or(default)
+    }
+
+    ///
+    /// Should mnemonics with no caption be dropped rather than kept
+    ///
+    pub fn skip_empty_mnemonics(&self) -> bool {
+        self.data.options.skip_empty_mnemonics
+    }
+
+    ///
+    /// An InvalidData error if an index of num_entries entries, read at
+    /// the current position, would exceed the ParseLimits. Checked before
+    /// anything is allocated for the entries.
+    ///
+    pub fn check_entries(&self, region: BlobRegions, num_entries: u32) -> io::Result<()> {
+        let limits = &self.data.options.limits;
+        if num_entries > limits.max_entries {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} index of {} entries exceeds the limit of {} at offset {}", region, num_entries, limits.max_entries, self.pos),
+            ));
+        }
+        let mut stats = self.data.stats();
+        stats.total_entries += num_entries;
+        if stats.total_entries > limits.max_total_entries {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} index entries in total exceeds the limit of {} reading {:?} at offset {}", stats.total_entries, limits.max_total_entries, region, self.pos),
+            ));
+        }
+        Ok(())
+    }
+
+    ///
+    /// May an index entry be longer than the schema's, with the extra
+    /// trailing bytes skipped
+    ///
+    pub fn lenient_entry_len(&self) -> bool {
+        self.data.options.lenient_entry_len
+    }
+
+    ///
+    /// Number of bytes after the current position
+    ///
+    pub fn get_remaining(&self) -> u32 {
+        self.data.data.len().saturating_sub(self.pos) as u32
+    }
+
+    ///
+    /// An InvalidData error if offset, read from the index at index_range,
+    /// points back into that index, as the parse would then be self referential
+    ///
+    pub fn check_offset_outside(&self, region: BlobRegions, index_range: &Range<u32>, offset: u32) -> io::Result<()> {
+        if index_range.contains(&offset) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} offset {} points back into its index at {} to {}",
+                    region, offset, index_range.start, index_range.end
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Note the index entry length validated for region, empty indexes
+    /// (length 0) are not noted
+    ///
+    pub fn add_entry_layout(&self, region: BlobRegions, idx_entry_len: u8) {
+        if idx_entry_len != 0 {
+            self.data.stats().entry_layouts.insert(region, idx_entry_len);
+        }
+    }
+
+    ///
+    /// The index entry length used by each region
+    ///
+    pub fn entry_layouts(&self) -> HashMap<BlobRegions, u8> {
+        self.data.stats().entry_layouts.clone()
+    }
+
+    pub fn get_diagnostics(&self) -> Vec<Diagnostic> {
+        lock(&self.data.diagnostics).clone()
+    }
+
+    pub fn freeze(&mut self, region: BlobRegions) -> RawBlob {
         RawBlob {
             data: self.data.clone(),
+            region,
         }
     }
 
-    fn read_exact(&mut self, buf: &mut [u8], region: BlobRegions)  {
+    ///
+    /// Step over len bytes, accounting them to region
+    ///
+    pub fn skip(&mut self, len: u32, region: BlobRegions) {
+        let pos = self.pos;
+        self.pos = pos + len as usize;
+        self.data.add_region(pos, self.pos, region);
+    }
+
+    ///
+    /// The (accounted, unaccounted) bytes, accounted bytes belong to a
+    /// region. Zero bytes at the end of the file are padding, so neither.
+    ///
+    pub fn account_for_bytes(&self) -> (usize, usize) {
+        self.data.account_for_bytes()
+    }
+
+    ///
+    /// The bytes tagged with region, all its ranges concatenated in offset order
+    ///
+    pub fn region_bytes(&self, region: BlobRegions) -> Vec<u8> {
+        self.data.region_bytes(region)
+    }
+
+    ///
+    /// The bytes within radius of off, and the offset of the first of them,
+    /// as a small snippet of the file to reproduce a decode failure
+    ///
+    pub fn extract_context(&self, off: u32, radius: u32) -> (u32, Vec<u8>) {
+        self.data.extract_context(off, radius)
+    }
+
+    ///
+    /// Check every byte, apart from the trailing padding, belongs to a region
+    ///
+    pub fn check_all_bytes_accounted(&self) -> Result<(), String> {
+        self.data.check_all_bytes_accounted()
+    }
+
+    pub fn get_maps(&self) -> &CharacterMaps {
+        &self.data.maps
+    }
+
+    ///
+    /// Fill buf from the current position, accounting the bytes to region.
+    /// Reading past the end of the file is an UnexpectedEof error naming
+    /// the region and offset.
+    ///
+    fn read_exact(&mut self, buf: &mut [u8], region: BlobRegions) -> io::Result<()> {
         let to_read = buf.len();
         let pos = self.pos;
 
-        for i in 0..to_read {
-            buf[i] = self.data.data[pos + i];
+        match self.data.data.get(pos..pos + to_read) {
+            Some(bytes) => buf.copy_from_slice(bytes),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Unexpected EOF reading {:?} at offset {}", region, pos),
+                ));
+            }
         }
         self.pos = pos + to_read;
 
-        self.data.add_region(pos, pos + to_read, region)
+        self.data.add_region(pos, pos + to_read, region);
+        Ok(())
     }
 
-    pub fn read_le_4bytes(&mut self, region: BlobRegions) -> u32 {
-		let mut values = [0; 4];
-   		self.read_exact(&mut values, region);
-		return (values[0] as u32) | ((values[1] as u32) << 8) | ((values[2] as u32) << 16) | ((values[3] as u32) << 24);
+    ///
+    /// Read N bytes for the read_le helpers, a read past the end of the
+    /// file is an UnexpectedEof error for the loader to return
+    ///
+    fn read_array<const N: usize>(&mut self, region: BlobRegions) -> io::Result<[u8; N]> {
+        let mut values = [0; N];
+        self.read_exact(&mut values, region)?;
+        Ok(values)
+    }
+
+    pub fn read_le_4bytes(&mut self, region: BlobRegions) -> io::Result<u32> {
+		let values = self.read_array::<4>(region)?;
+		return Ok((values[0] as u32) | ((values[1] as u32) << 8) | ((values[2] as u32) << 16) | ((values[3] as u32) << 24));
 	}
 	
-	pub fn read_le_3bytes(&mut self, region: BlobRegions) -> u32 {
-		let mut values = [0; 3];
-   		self.read_exact(&mut values, region);
-		return (values[0] as u32) | ((values[1] as u32) << 8) | ((values[2] as u32) << 16);
+	pub fn read_le_3bytes(&mut self, region: BlobRegions) -> io::Result<u32> {
+		let values = self.read_array::<3>(region)?;
+		return Ok((values[0] as u32) | ((values[1] as u32) << 8) | ((values[2] as u32) << 16));
 	}
 	
-	pub fn read_le_2bytes(&mut self, region: BlobRegions) -> u16 {
-		let mut values = [0; 2];
-   		self.read_exact(&mut values, region);
-		return (values[0] as u16) | ((values[1] as u16) << 8);
+	pub fn read_le_2bytes(&mut self, region: BlobRegions) -> io::Result<u16> {
+		let values = self.read_array::<2>(region)?;
+		return Ok((values[0] as u16) | ((values[1] as u16) << 8));
 	}
 	
-	pub fn read_byte(&mut self, region: BlobRegions) -> u8 {
-		let mut values = [0; 1];
-   		self.read_exact(&mut values, region);
-		return values[0];
+	pub fn read_byte(&mut self, region: BlobRegions) -> io::Result<u8> {
+		let values = self.read_array::<1>(region)?;
+		return Ok(values[0]);
 	}
 
 
@@ -98,11 +327,12 @@ impl FileBlob {
     ///
     /// Reads the whole file into Blob
     ///
-    pub fn load(
-        fp: &mut File,
+    pub fn load<R: Read + Seek>(
+        fp: &mut R,
         expected_size: u32,
         expected_crc: u32,
         maps: CharacterMaps,
+        options: ParseOptions,
     ) -> io::Result<FileBlob> {
         fp.seek(SeekFrom::Start(0))?;
         let mut buf = [0; 2048];
@@ -122,107 +352,500 @@ impl FileBlob {
             };
         }
         let size = data.len();
-        if size != expected_size as usize {
-            panic!("File length incorrect");
+        if !options.skip_crc_check {
+            if size != expected_size as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("File is {} bytes but the header says {}", size, expected_size),
+                ));
+            }
+            let crc = crc32(data.get(CRC_COVERS_FROM..).unwrap_or_default());
+            if crc != expected_crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("CRC mismatch, header has {:08X} but the file is {:08X}", expected_crc, crc),
+                ));
+            }
         }
-        let stats = Stats { regions: vec![BlobRegions::Empty; size], string_offsets : HashMap::<String, (u32,u32)>::new()};
-        let _blob = Rc::new(_Blob { data, maps, stats : RefCell::new(stats) });
+        let stats = Stats { regions: vec![BlobRegions::Empty; size], string_offsets : HashMap::new(), entry_layouts: HashMap::new(), total_entries: 0, decoded_offsets: HashSet::new(), timings: None};
+        let maps = maps.with_fresh_counts();
+        let _blob = Arc::new(_Blob { data, maps, options, stats : Mutex::new(stats), diagnostics: Mutex::new(Vec::new()), tooltip_cache: Mutex::new(HashMap::new()) });
 
         Result::Ok(FileBlob {
             data: _blob,
             pos: 0,
+            on_drop: None,
         })
     }
 
+    ///
+    /// Whether the CRC of the file matches the CRC in its header
+    ///
+    pub fn verify_crc(&self) -> bool {
+        let data = &self.data.data;
+        data.len() >= CRC_COVERS_FROM && crc32(&data[CRC_COVERS_FROM..]) == little_endian_4_bytes(&data[4..8])
+    }
+
+    ///
+    /// Whether the file is the length given in its header
+    ///
+    pub fn verify_length(&self) -> bool {
+        let data = &self.data.data;
+        data.len() >= 4 && data.len() == little_endian_4_bytes(&data[0..4]) as usize
+    }
+
+    ///
+    /// Capture the stats when the FileBlob is dropped, so that they are
+    /// still produced if the parse returns early or panics midway
+    ///
+    pub fn set_stats_on_drop(&mut self, callback: Option<StatsCallback>) {
+        self.on_drop = callback;
+    }
+
+    ///
+    /// The string and region statistics so far, as shown by display_stats
+    ///
+    pub fn get_stats(&self) -> BlobStats
+    {
+        self.data.get_stats()
+    }
+
     pub fn display_stats(&self)
     {
         self.data.display_stats();
     }
+
+    ///
+    /// Start recording the time taken parsing each region
+    ///
+    pub fn enable_timing(&mut self)
+    {
+        self.data.stats().timings = Some(Timings {
+            elapsed: HashMap::new(),
+            stack: Vec::new(),
+        });
+    }
+
+    ///
+    /// Run parse, accounting its time to region if timing is enabled
+    ///
+    pub fn time<T>(&mut self, region: BlobRegions, parse: impl FnOnce(&mut FileBlob) -> T) -> T
+    {
+        self.data.timer_start(region);
+        let result = parse(self);
+        self.data.timer_stop();
+        result
+    }
+
+    ///
+    /// Run parse, catching a parse error (returned or a panic) as an Err
+    /// with its message
+    ///
+    pub fn catch<T>(&mut self, parse: impl FnOnce(&mut FileBlob) -> io::Result<T>) -> Result<T, String>
+    {
+        match panic::catch_unwind(AssertUnwindSafe(|| parse(self))) {
+            Ok(result) => result.map_err(|x| x.to_string()),
+            Err(x) => Err(panic_message(x)),
+        }
+    }
+
+    ///
+    /// The region the byte at off was read as, Empty if it has not been read
+    ///
+    pub fn region_at(&self, off: u32) -> BlobRegions
+    {
+        self.data.stats().regions.get(off as usize).copied().unwrap_or(BlobRegions::Empty)
+    }
+
+    pub fn get_timings(&self) -> Vec<(BlobRegions, Duration)>
+    {
+        self.data.get_timings()
+    }
+}
+
+impl Drop for FileBlob {
+    fn drop(&mut self) {
+        if let Some(callback) = self.on_drop.take() {
+            callback(self);
+        }
+    }
 }
 
 impl Clone for RawBlob {
     fn clone(&self) -> RawBlob {
         RawBlob {
             data: self.data.clone(),
+            region: self.region,
         }
     }
 }
 
 impl RawBlob {
 
+    ///
+    /// Are the strings UTF-8, the region may override the file's encoding
+    ///
+    fn is_utf8(&self) -> bool {
+        match self.data.options.utf8_regions.get(&self.region) {
+            Some(is_utf8) => *is_utf8,
+            None => self.data.maps.is_utf8(),
+        }
+    }
+
     ///
     /// Get bytes that represent a string, from the blob
     ///
-    fn get_bytes(&self, off: u32, max_length: u16) -> Vec<u8> {
+    fn get_bytes(&self, off: u32, max_length: u16) -> Result<Vec<u8>, String> {
         let mut bytes = Vec::new();
         let buf = &self.data.data;
 
         let mut i = off as usize;
-        let end = i + (max_length as usize);
+        if i >= buf.len() {
+            return Err(format!("String offset {} is past the end of the file", off));
+        }
+        let end = (i + (max_length as usize)).min(buf.len());
+        let mut terminated = false;
+
+        // UTF-16 text has zero bytes, so is terminated by a zero code unit
+        if self.is_utf8() && Self::utf16_bom(&buf[i..]).is_some() {
+            while i + 1 < end {
+                if buf[i] == 0 && buf[i + 1] == 0 {
+                    i += 2;
+                    break;
+                }
+                bytes.push(buf[i]);
+                bytes.push(buf[i + 1]);
+                i += 2;
+            }
+            self.data.add_region(off as usize, i, BlobRegions::Text);
+            return Ok(bytes);
+        }
+
+        // Wide codes have zero bytes, so are terminated by a zero code
+        if let Some(width) = self.wide_width() {
+            while i + width <= end {
+                let code = &buf[i..i + width];
+                i += width;
+                if code.iter().all(|x| *x == 0) {
+                    break;
+                }
+                bytes.extend_from_slice(code);
+            }
+            self.data.add_region(off as usize, i, BlobRegions::Text);
+            return Ok(bytes);
+        }
 
         while i < end {
             let ch = buf[i];
             if ch == 0 {
                 i += 1;
+                terminated = true;
                 break;
             } else {
                 bytes.push(ch);
             }
             i += 1;
         }
+        if !terminated && self.data.options.repair_unterminated {
+            // Probably ran into the next section, keep only the leading text
+            let len = self.text_prefix_len(&bytes);
+            if len < bytes.len() {
+                self.data.add_diagnostic(Severity::Warning, BlobRegions::Text, off,
+                    &format!("Unterminated string, truncated to {} of {} bytes", len, bytes.len()));
+                bytes.truncate(len);
+                i = off as usize + len;
+            }
+        }
         // Note down what was in that region of the Blob for diagnostics.
         self.data.add_region(off as usize, i, BlobRegions::Text);
 
-        return bytes;
+        return Ok(bytes);
+    }
+
+    ///
+    /// The length of the leading bytes that are valid characters
+    ///
+    fn text_prefix_len(&self, bytes: &[u8]) -> usize {
+        let maps = &self.data.maps;
+        if self.is_utf8() {
+            return bytes.iter().position(|x| *x < 0x20).unwrap_or(bytes.len());
+        }
+        let mut i = 0;
+        while i < bytes.len() {
+            let ch1 = bytes[i];
+            if i + 1 < bytes.len() && ((bytes[i + 1] & 0xC0) == 0xC0) && ((ch1 & 0x01) == 0x01) {
+                let code = (((bytes[i + 1] as u16) & !0xC0) << 7) | ((ch1 >> 1) as u16);
+                if !maps.contains_code(2, code) {
+                    break;
+                }
+                i += 2;
+            } else if maps.contains_code(1, ch1 as u16) {
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        i
+    }
+
+    ///
+    /// The source codes of the characters of the string at off, the
+    /// character map codes or, for UTF-8, the unicode codepoints
+    ///
+    pub fn get_codes(&self, off: u32) -> Vec<u16> {
+        let buf = &self.data.data;
+        let start = (off as usize).min(buf.len());
+        let end = buf[start..].iter().position(|x| *x == 0).map_or(buf.len(), |x| start + x);
+        let bytes = &buf[start..end];
+
+        if self.is_utf8() {
+            return String::from_utf8_lossy(bytes).chars().map(|x| (x as u32).min(0xFFFF) as u16).collect();
+        }
+
+        let mut codes = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let ch1 = bytes[i];
+            if i + 1 < bytes.len() && ((bytes[i + 1] & 0xC0) == 0xC0) && ((ch1 & 0x01) == 0x01) {
+                codes.push((((bytes[i + 1] as u16) & !0xC0) << 7) | ((ch1 >> 1) as u16));
+                i += 2;
+            } else {
+                codes.push(ch1 as u16);
+                i += 1;
+            }
+        }
+        codes
+    }
+
+    ///
+    /// The bytes of the string at off, up to its NUL terminator or
+    /// max_length, without noting the region or decoding it
+    ///
+    pub fn get_raw_bytes(&self, off: u32, max_length: u16) -> Vec<u8> {
+        let buf = &self.data.data;
+        let start = (off as usize).min(buf.len());
+        let limit = (start + max_length as usize).min(buf.len());
+        let end = buf[start..limit].iter().position(|x| *x == 0).map_or(limit, |x| start + x);
+        buf[start..end].to_vec()
+    }
+
+    ///
+    /// The CRC recorded in the file header
+    ///
+    pub fn get_file_crc(&self) -> u32 {
+        little_endian_4_bytes(&self.data.data[4..8])
+    }
+
+    ///
+    /// Encode text with the character maps the blob is decoded with
+    ///
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        self.data.maps.encode(text)
+    }
+
+    ///
+    /// The (map id, code) of every character decoded so far, with the
+    /// number of times it was decoded
+    ///
+    pub fn get_char_usage(&self) -> HashMap<(u16, u32), u32> {
+        self.data.maps.char_usage()
+    }
+
+    ///
+    /// The index entry length used by each region
+    ///
+    pub fn entry_layouts(&self) -> HashMap<BlobRegions, u8> {
+        self.data.stats().entry_layouts.clone()
+    }
+
+    pub fn account_for_bytes(&self) -> (usize, usize) {
+        self.data.account_for_bytes()
+    }
+
+    pub fn check_all_bytes_accounted(&self) -> Result<(), String> {
+        self.data.check_all_bytes_accounted()
+    }
+
+    pub fn get_stats(&self) -> BlobStats {
+        self.data.get_stats()
+    }
+
+    pub fn get_timings(&self) -> Vec<(BlobRegions, Duration)> {
+        self.data.get_timings()
+    }
+
+    pub fn get_maps(&self) -> &CharacterMaps {
+        &self.data.maps
+    }
+
+    pub fn region_bytes(&self, region: BlobRegions) -> Vec<u8> {
+        self.data.region_bytes(region)
+    }
+
+    pub fn extract_context(&self, off: u32, radius: u32) -> (u32, Vec<u8>) {
+        self.data.extract_context(off, radius)
+    }
+
+    ///
+    /// Are tooltips left out of to_string, to be decoded on demand
+    ///
+    pub fn lazy_tooltips(&self) -> bool {
+        self.data.options.lazy_tooltips
+    }
+
+    ///
+    /// As get_string, but with lazy tooltips the result is cached so a
+    /// tooltip shared by many entries is decoded once
+    ///
+    pub fn get_tooltip(&self, off: u32, max_length: u16) -> Result<String, String> {
+        if !self.lazy_tooltips() {
+            return self.get_string(off, max_length);
+        }
+        if let Some(result) = lock(&self.data.tooltip_cache).get(&off) {
+            return result.clone();
+        }
+        let result = self.get_string(off, max_length);
+        lock(&self.data.tooltip_cache).insert(off, result.clone());
+        result
     }
 
     pub fn get_string(&self, off: u32, max_length: u16) -> Result<String, String> {
+        self.data.timer_start(BlobRegions::Text);
+        let result = self.decode_string(off, max_length);
+        self.data.timer_stop();
+        result
+    }
+
+    fn decode_string(&self, off: u32, max_length: u16) -> Result<String, String> {
         if off == 0 {
             return Result::Ok("[-- no string --]".to_string());
         }
-        let bytes = self.get_bytes(off, max_length);
+        let max_offsets = self.data.options.limits.max_offsets;
+        {
+            let mut stats = self.data.stats();
+            if stats.decoded_offsets.insert(off) && stats.decoded_offsets.len() as u32 > max_offsets {
+                return Err(format!("More than {} distinct string offsets", max_offsets));
+            }
+        }
+        let bytes = self.get_bytes(off, max_length)?;
         let len = bytes.len() as u32;
         if len == 0 {
-            self.data.add_string("", off, 1);
+            self.data.add_string("", off);
             return Result::Ok("[-- empty string --]".to_string());
         }
-        let result = self.bytes_to_string(bytes);
+        let result = match self.data.options.normalization {
+            Some(normalization) => self.bytes_to_string(bytes).map(|x| normalization.apply(&x)),
+            None => self.bytes_to_string(bytes),
+        };
         match &result {
-            Ok(x) => self.data.add_string(&x, off, len),
+            Ok(x) => self.data.add_string(&x, off),
             Err(_) => {}  
         }
         return result;
     }
 
 
+    ///
+    /// The bytes per code when the strings are little endian codes of a 3
+    /// or 4 byte map, the map's bytes per character. Only schema 4 text
+    /// that is not UTF-8, earlier schemas keep the packed 1 and 2 byte codes.
+    ///
+    fn wide_width(&self) -> Option<usize> {
+        if self.is_utf8() || !(self.data.maps.is_wide() || self.data.maps.is_utf8()) {
+            return None;
+        }
+        self.data.maps.wide_map().map(|(_, bytes_per)| bytes_per as usize)
+    }
+
+    ///
+    /// Is the string prefixed with a UTF-16 byte order mark, true if little endian
+    ///
+    fn utf16_bom(bytes: &[u8]) -> Option<bool> {
+        match bytes {
+            [0xFF, 0xFE, ..] => Some(true),
+            [0xFE, 0xFF, ..] => Some(false),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Decode a single byte code, from the shifted map after a shift out
+    ///
+    fn decode_byte(&self, ch: u8, shifted: bool) -> Option<String> {
+        match self.data.maps.get_shift_codes() {
+            Some(shift) if shifted => {
+                self.data.maps.decode_shifted_byte(shift.map_id, ch)
+            }
+            _ => {
+                self.data.maps.decode_byte(ch)
+            }
+        }
+    }
+
     fn bytes_to_string(&self, bytes : Vec<u8>) -> Result<String, String> {
-        if self.data.maps.is_utf8() {
-            return match String::from_utf8(bytes) {
-                Ok(x) => Ok(x),
-                Err(_) => Err("Failed to decode UTF-8 string".to_string()),
+        if self.is_utf8() {
+            // Some string pools have UTF-16 strings, each with its own BOM
+            if let Some(little_endian) = Self::utf16_bom(&bytes) {
+                let units: Vec<u16> = bytes[2..].chunks_exact(2).map(|x| {
+                    if little_endian {
+                        u16::from_le_bytes([x[0], x[1]])
+                    } else {
+                        u16::from_be_bytes([x[0], x[1]])
+                    }
+                }).collect();
+                return match String::from_utf16(&units) {
+                    Ok(x) => Ok(x),
+                    Err(_) => Err("Failed to decode UTF-16 string".to_string()),
+                };
+            }
+            return match std::str::from_utf8(&bytes) {
+                Ok(x) => Ok(x.to_string()),
+                Err(e) => {
+                    let pos = e.valid_up_to();
+                    Err(format!("Failed to decode UTF-8 string, invalid byte {:02X} at {} of {:02X?}", bytes[pos], pos, bytes))
+                }
             };
         }
 
+        if let Some(width) = self.wide_width() {
+            let mut result = String::new();
+            for code in bytes.chunks(width) {
+                let code = code.iter().rev().fold(0, |acc, x| (acc << 8) | *x as u32);
+                match self.data.maps.decode_wide(code) {
+                    Some(ch) => result += &ch,
+                    None => return Err(format!("No character for wide code {:X} from {:02X?}", code, bytes)),
+                }
+            }
+            return Ok(result);
+        }
+
         let mut result = String::new();
         let mut i = 0;
+        let shift_codes = self.data.maps.get_shift_codes();
+        let mut shifted = false;
 
         while i < bytes.len() {
             let ch1 = bytes[i];
             i += 1;
-            let unicode = if i < bytes.len() {
+            if let Some(shift) = shift_codes {
+                if ch1 == shift.shift_out || ch1 == shift.shift_in {
+                    shifted = ch1 == shift.shift_out;
+                    continue;
+                }
+            }
+            let (code, unicode) = if i < bytes.len() {
                 let ch2 = bytes[i];
                 if ((ch2 & 0xC0) == 0xC0) && ((ch1 & 0x01) == 0x01) {
                     i += 1;
-                    self.data
-                        .maps
-                        .decode_2bytes((((ch2 as u16) & !0xC0) << 7) | ((ch1 >> 1) as u16))
+                    let code = (((ch2 as u16) & !0xC0) << 7) | ((ch1 >> 1) as u16);
+                    (code, self.data.maps.decode_2bytes(code))
                 } else if (ch1 & 0xC0) == 0xC0 {
                     return Err(format!(
                         "Dangling half word character, string so far is {} from {:02X?}",
                         result, bytes
                     ));
                 } else {
-                    self.data.maps.decode_byte(ch1)
+                    (ch1 as u16, self.decode_byte(ch1, shifted))
                 }
             } else if (ch1 & 0xC0) == 0xC0 {
                 return Err(format!(
@@ -230,94 +853,390 @@ impl RawBlob {
                     result, bytes
                 ));
             } else {
-                self.data.maps.decode_byte(ch1)
+                (ch1 as u16, self.decode_byte(ch1, shifted))
             };
             result = match unicode {
                 Some(ch) => result + &ch,
-                None => result,
+                None => return Err(format!("No character for code {:X} from {:02X?}", code, bytes)),
             };
         }
         return Ok(result);
     }
 }
 
+///
+/// Lock one of the blob's shared fields, a panic while it was locked
+/// (a corrupt file) leaves it as it was
+///
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T>
+{
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl _Blob {
+    ///
+    /// Lock the stats, a panic while they were locked (a corrupt file)
+    /// leaves them as they were so they can still be displayed
+    ///
+    fn stats(&self) -> MutexGuard<'_, Stats>
+    {
+        lock(&self.stats)
+    }
+
     pub fn add_region(&self, start: usize, end: usize, _type: BlobRegions)
     {
-        let regions = &mut self.stats.borrow_mut().regions;
+        let regions = &mut self.stats().regions;
 
-        for i in start..end {
+        // A skip past the end of the file has no bytes there to account
+        let end = end.min(regions.len());
+        for i in start.min(end)..end {
             if regions[i] == BlobRegions::Empty {
                 regions[i] = _type;
             } else {
                 if regions[i] != _type {
-                    panic!("Region type mismatch")
+                    panic!("Region type mismatch {:?} != {:?}", regions[i], _type)
                 }
             }
         }
     }
 
-    pub fn add_string(&self, string: &str, off : u32, size : u32)
+    fn check_all_bytes_accounted(&self) -> Result<(), String>
+    {
+        match self.account_for_bytes() {
+            (_, 0) => Ok(()),
+            (accounted, unaccounted) => Err(format!("{} bytes unaccounted for, {} accounted", unaccounted, accounted)),
+        }
+    }
+
+    fn account_for_bytes(&self) -> (usize, usize)
+    {
+        let regions = &self.stats().regions;
+        let padding = self.data.iter().rev().zip(regions.iter().rev())
+            .take_while(|(byte, region)| **byte == 0 && **region == BlobRegions::Empty)
+            .count();
+        let unaccounted = regions.iter().filter(|x| **x == BlobRegions::Empty).count() - padding;
+        (regions.len() - unaccounted - padding, unaccounted)
+    }
+
+    fn region_bytes(&self, region: BlobRegions) -> Vec<u8>
+    {
+        let regions = &self.stats().regions;
+        self.data.iter().zip(regions.iter())
+            .filter(|(_, x)| **x == region)
+            .map(|(byte, _)| *byte)
+            .collect()
+    }
+
+    fn extract_context(&self, off: u32, radius: u32) -> (u32, Vec<u8>)
+    {
+        let len = self.data.len();
+        let start = (off.saturating_sub(radius) as usize).min(len);
+        let end = (off.saturating_add(radius) as usize).min(len);
+        (start as u32, self.data[start..end].to_vec())
+    }
+
+    pub fn add_string(&self, string: &str, off : u32)
+    {
+        self.stats().string_offsets.entry(string.to_string()).or_default().insert(off);
+    }
+
+    fn add_diagnostic(&self, severity: Severity, region: BlobRegions, location: u32, message: &str)
+    {
+        lock(&self.diagnostics).push(Diagnostic {
+            severity,
+            region,
+            location,
+            message: message.to_string(),
+        });
+    }
+
+    fn timer_start(&self, region: BlobRegions)
+    {
+        if let Some(timings) = &mut self.stats().timings {
+            timings.stack.push((region, Instant::now(), Duration::ZERO));
+        }
+    }
+
+    fn timer_stop(&self)
     {
-        let mut stats = self.stats.borrow_mut();
-        let string_off = &mut stats.string_offsets;
-        match string_off.get(string) {
-            Some(x) => {
-                let (orig_off, count) = *x;
-                if orig_off != off {
-                    string_off.remove(string);
-                    string_off.insert(string.to_string(), (orig_off, count + size));
+        if let Some(timings) = &mut self.stats().timings {
+            if let Some((region, start, nested)) = timings.stack.pop() {
+                let elapsed = start.elapsed();
+                *timings.elapsed.entry(region).or_insert(Duration::ZERO) += elapsed.saturating_sub(nested);
+                if let Some(parent) = timings.stack.last_mut() {
+                    parent.2 += elapsed;
                 }
-            },
-            None => {string_off.insert(string.to_string(), (off, 0));}
+            }
         }
     }
 
-    pub fn display_stats(&self)
+    fn get_timings(&self) -> Vec<(BlobRegions, Duration)>
     {
-        let stats = self.stats.borrow_mut();
-        let mut duplicate_count = 0;
-        for x in &stats.string_offsets {
-            let (string, (orig_off, count)) = x;
-            if *count > 1 {
-                duplicate_count += count - 1;
-                println!("{} duplicated {} times", string, count);
+        let mut result = Vec::new();
+        if let Some(timings) = &self.stats().timings {
+            for (region, elapsed) in &timings.elapsed {
+                result.push((*region, *elapsed));
             }
         }
-      
-        println!("Duplicate count {}", duplicate_count);
+        result.sort_by_key(|x| std::cmp::Reverse(x.1));
+        result
+    }
 
-        let mut unused = 0;
-        let mut current_region = BlobRegions::Invalid;
-        let mut pos = 0;
-        let mut region_start = 0;
-		let mut prelude = String::new();
+    ///
+    /// The string and region statistics gathered while parsing
+    ///
+    fn get_stats(&self) -> BlobStats
+    {
+        let (_, unused_bytes) = self.account_for_bytes();
+        let stats = self.stats();
 
-        for x in &stats.regions {
-            
-            let reg = *x;
+        let mut duplicates = Vec::new();
+        for (string, offsets) in &stats.string_offsets {
+            let copies = offsets.len() as u32 - 1;
+            if copies > 0 {
+                duplicates.push((string.clone(), copies));
+            }
+        }
+        duplicates.sort();
 
-            if reg == BlobRegions::Empty {
-                unused += 1;
+        let mut regions = Vec::new();
+        let mut region_start = 0;
+        for pos in 1..=stats.regions.len() {
+            if pos == stats.regions.len() || stats.regions[pos] != stats.regions[region_start] {
+                regions.push((region_start, pos, stats.regions[region_start]));
+                region_start = pos;
             }
-            if reg != current_region {
-                if pos > region_start {
-                    let text = format!("Region from {} to {} is {:?}", region_start, pos-1, current_region);
-					if current_region == BlobRegions::Empty {
-						println!("{}", prelude);
-						println!("{}", text);
-					} else {
-						prelude = text;
-					}
-                    current_region = reg;
-                    region_start = pos;
+        }
+
+        BlobStats { duplicates, regions, unused_bytes }
+    }
+
+    pub fn display_stats(&self)
+    {
+        for (region, elapsed) in self.get_timings() {
+            println!("{:?} took {:?}", region, elapsed);
+        }
+
+        let stats = self.get_stats();
+        let mut duplicate_count = 0;
+        for (string, copies) in &stats.duplicates {
+            duplicate_count += copies;
+            println!("{} duplicated {} times", string, copies);
+        }
+
+        println!("Duplicate count {}", duplicate_count);
+
+        let mut prelude = None;
+        for (start, end, region) in &stats.regions {
+            let text = format!("Region from {} to {} is {:?}", start, end - 1, region);
+            if *region == BlobRegions::Empty {
+                if let Some(prelude) = &prelude {
+                    println!("{}", prelude);
                 }
+                println!("{}", text);
+            } else {
+                prelude = Some(text);
             }
-            pos += 1;
         }
 
-        if unused > 0 {
-            println!("{} bytes unused, {} wasted duplication", unused, duplicate_count);
+        if stats.unused_bytes > 0 {
+            println!("{} bytes unused, {} duplicate copies", stats.unused_bytes, duplicate_count);
+        }
+    }
+}
+
+///
+/// A blob of data for a test of one index, without a language file
+/// header to check
+///
+#[cfg(test)]
+pub fn blob_from_bytes(data: &[u8], options: ParseOptions) -> FileBlob {
+    let maps = crate::characters::read_character_file("CharacterMaps.xml").unwrap();
+    blob_with_maps(data, maps, options)
+}
+
+#[cfg(test)]
+pub fn blob_with_maps(data: &[u8], maps: CharacterMaps, options: ParseOptions) -> FileBlob {
+    let options = ParseOptions { skip_crc_check: true, ..options };
+    FileBlob::load(&mut std::io::Cursor::new(data), 0, 0, maps, options).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::thread;
+
+    ///
+    /// The language file as a fresh blob, with the offset and max length
+    /// of every caption and tooltip in it
+    ///
+    fn load_strings(path: &str) -> (RawBlob, Vec<(u32, u16)>) {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file(path, maps.clone()).unwrap();
+        let mut strings = Vec::new();
+        for entry in lang.flatten() {
+            strings.push((entry.caption_off, entry.str_len));
+            if entry.tooltip_off != 0 {
+                strings.push((entry.tooltip_off, entry.str_len));
+            }
+        }
+
+        let data = fs::read(path).unwrap();
+        let crc = little_endian_4_bytes(&data[4..8]);
+        let mut fp = FileBlob::load(&mut Cursor::new(&data), data.len() as u32, crc, maps, ParseOptions::default()).unwrap();
+        (fp.freeze(BlobRegions::Text), strings)
+    }
+
+    #[test]
+    fn concurrent_decodes_give_the_serial_stats() {
+        let (serial, strings) = load_strings("languageV3.bin");
+        for (off, len) in &strings {
+            let _ = serial.get_string(*off, *len);
+        }
+
+        let (concurrent, _) = load_strings("languageV3.bin");
+        thread::scope(|scope| {
+            for chunk in strings.chunks(strings.len().div_ceil(4)) {
+                let blob = &concurrent;
+                scope.spawn(move || {
+                    for (off, len) in chunk.iter().rev() {
+                        let _ = blob.get_string(*off, *len);
+                    }
+                });
+            }
+        });
+
+        assert!(!serial.get_stats().duplicates.is_empty());
+        assert_eq!(concurrent.get_stats(), serial.get_stats());
+        assert_eq!(concurrent.get_char_usage(), serial.get_char_usage());
+    }
+
+    #[test]
+    fn repair_cuts_an_unterminated_string_at_binary_data() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let junk = (0..=255).find(|code| !maps.contains_code(1, *code)).unwrap() as u8;
+        let data = [0, b'S', b'p', b'e', b'e', b'd', junk, 0x40, junk, 0x11, 0];
+
+        let options = ParseOptions { repair_unterminated: true, ..ParseOptions::default() };
+        let mut fp = blob_from_bytes(&data, options);
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(blob.get_string(1, 8).unwrap(), "Speed");
+        let diagnostics = fp.get_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].location, 1);
+        assert_eq!(diagnostics[0].message, "Unterminated string, truncated to 5 of 8 bytes");
+
+        // Without the repair the junk is decoded, and is not a character
+        let mut fp = blob_from_bytes(&data, ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        let error = blob.get_string(1, 8).unwrap_err();
+        assert!(error.starts_with(&format!("No character for code {:X}", junk)), "{}", error);
+    }
+
+    #[test]
+    fn utf16_bom_string_in_utf8_mode() {
+        let mut data = vec![0, 0xFF, 0xFE];
+        data.extend("H\u{e9}\u{4e2d}".encode_utf16().flat_map(|x| x.to_le_bytes()));
+        data.extend([0, 0, 0xFE, 0xFF]);
+        data.extend("H\u{e9}".encode_utf16().flat_map(|x| x.to_be_bytes()));
+        data.extend([0, 0]);
+        let utf8_at = data.len() as u32;
+        data.extend("H\u{e9}\0".as_bytes());
+
+        let mut fp = blob_with_maps(&data, CharacterMaps::utf8(), ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(blob.get_string(1, 32), Ok("H\u{e9}\u{4e2d}".to_string()));
+        assert_eq!(blob.get_string(11, 32), Ok("H\u{e9}".to_string()));
+        assert_eq!(blob.get_string(utf8_at, 32), Ok("H\u{e9}".to_string()));
+    }
+
+    #[test]
+    fn invalid_utf8_reports_its_position() {
+        let mut data = vec![0];
+        data.extend("Caf\u{e9}".as_bytes());
+        data.extend([0xFF, b'!', 0]);
+        let mut fp = blob_with_maps(&data, CharacterMaps::utf8(), ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(
+            blob.get_string(1, 32),
+            Err("Failed to decode UTF-8 string, invalid byte FF at 5 of [43, 61, 66, C3, A9, FF, 21]".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_region_label_is_reported() {
+        let mut fp = blob_from_bytes(&[1, 2, 3, 4, 5, 6], ParseOptions::default());
+        fp.skip(2, BlobRegions::Header);
+        fp.skip(3, BlobRegions::Other(7));
+        assert!(fp.get_stats().regions.contains(&(2, 5, BlobRegions::Other(7))));
+        assert_eq!(fp.region_bytes(BlobRegions::Other(7)), [3, 4, 5]);
+        assert!(fp.region_bytes(BlobRegions::Other(8)).is_empty());
+
+        fp.set_pos(2);
+        let error = panic::catch_unwind(AssertUnwindSafe(|| fp.skip(1, BlobRegions::Other(8)))).err().unwrap();
+        assert_eq!(error.downcast_ref::<String>().unwrap(), "Region type mismatch Other(7) != Other(8)");
+    }
+
+    #[test]
+    fn units_in_utf8_and_params_in_the_character_map() {
+        // "20°C" in UTF-8 at 1, and with the map's ° (129) at 7
+        let mut data = vec![0];
+        data.extend("20\u{b0}C\0".as_bytes());
+        data.extend([b'2', b'0', 129, b'C', 0]);
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let options = ParseOptions { utf8_regions: HashMap::from([(BlobRegions::Units, true)]), ..ParseOptions::default() };
+        let mut fp = blob_with_maps(&data, maps, options);
+        let units = fp.freeze(BlobRegions::Units);
+        let params = fp.freeze(BlobRegions::Parameters);
+
+        assert_eq!(units.get_string(1, 32), Ok("20\u{b0}C".to_string()));
+        assert_eq!(params.get_string(7, 32), Ok("20\u{b0}C".to_string()));
+        assert!(units.get_string(7, 32).is_err());
+    }
+
+    #[test]
+    fn context_window_is_clipped_to_the_file() {
+        let data: Vec<u8> = (0..20).collect();
+        let fp = blob_from_bytes(&data, ParseOptions::default());
+        assert_eq!(fp.extract_context(10, 4), (6, (6..14).collect()));
+        assert_eq!(fp.extract_context(2, 4), (0, (0..6).collect()));
+        assert_eq!(fp.extract_context(18, 4), (14, (14..20).collect()));
+        assert_eq!(fp.extract_context(30, 4), (20, vec![]));
+    }
+
+    #[test]
+    fn dropping_the_blob_runs_the_stats_callback() {
+        let data = fs::read("languageV3.bin").unwrap();
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let crc = little_endian_4_bytes(&data[4..8]);
+        let mut fp = FileBlob::load(&mut Cursor::new(&data), data.len() as u32, crc, maps, ParseOptions::default()).unwrap();
+        fp.set_pos(32);
+        fp.read_le_2bytes(BlobRegions::Header).unwrap();
+
+        let captured = Rc::new(RefCell::new(None));
+        let capture = captured.clone();
+        fp.set_stats_on_drop(Some(Box::new(move |fp: &FileBlob| *capture.borrow_mut() = Some(fp.get_stats()))));
+        assert!(captured.borrow().is_none());
+        drop(fp);
+
+        let stats = captured.borrow_mut().take().unwrap();
+        assert!(stats.regions.contains(&(32, 34, BlobRegions::Header)));
+    }
+
+    #[test]
+    fn duplicates_count_the_extra_copies() {
+        let mut fp = blob_from_bytes(b"\0Cap\0Cap\0Cap\0Tip\0", ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        for off in [1, 5, 9, 13] {
+            blob.get_string(off, 4).unwrap();
         }
+        assert_eq!(fp.get_stats().duplicates, [("Cap".to_string(), 2)]);
     }
 }
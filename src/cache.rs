@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use crate::conversion::little_endian_4_bytes;
+use crate::flatten::ProductKey;
+use crate::language::Language;
+use crate::tree::{LanguageTree, MenuTree, ParamTree, ProductTree, TreeText};
+
+const CACHE_MAGIC: &[u8; 4] = b"KPLC";
+const CACHE_VERSION: u16 = 1;
+
+///
+/// Serialises a LanguageTree, all values little endian and strings
+/// as a 4 byte length followed by the UTF-8 bytes
+///
+struct CacheWriter {
+    buf: Vec<u8>,
+}
+
+///
+/// Reads back what CacheWriter wrote, None if the data runs out
+///
+struct CacheReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl CacheWriter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, text: &str) {
+        self.u32(text.len() as u32);
+        self.bytes(text.as_bytes());
+    }
+
+    fn result(&mut self, result: &Result<String, String>) {
+        match result {
+            Ok(x) => {
+                self.u8(0);
+                self.string(x);
+            }
+            Err(x) => {
+                self.u8(1);
+                self.string(x);
+            }
+        }
+    }
+
+    fn text(&mut self, text: &TreeText) {
+        self.result(&text.caption);
+        match &text.tooltip {
+            Some(x) => {
+                self.u8(1);
+                self.result(x);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn tree(&mut self, tree: &LanguageTree) {
+        self.u32(tree.products.len() as u32);
+        for product in &tree.products {
+            self.u16(product.key.product_id);
+            self.u16(product.key.derivative_id_low);
+            self.u16(product.key.derivative_id_high);
+            self.u16(product.flags);
+            self.u32(product.modes.len() as u32);
+            for (mode, menus) in &product.modes {
+                self.u8(*mode);
+                self.u32(menus.len() as u32);
+                for (menu, menu_tree) in menus {
+                    self.u8(*menu);
+                    self.text(&menu_tree.text);
+                    self.u32(menu_tree.params.len() as u32);
+                    for (param, param_tree) in &menu_tree.params {
+                        self.u8(*param);
+                        self.text(&param_tree.text);
+                        self.u32(param_tree.mnemonics.len() as u32);
+                        for (value, text) in &param_tree.mnemonics {
+                            self.i32(*value);
+                            self.text(text);
+                        }
+                    }
+                }
+            }
+        }
+        for table in [&tree.enumerations, &tree.keypad_strs] {
+            self.u32(table.len() as u32);
+            for (id, result) in table {
+                self.u16(*id);
+                self.result(result);
+            }
+        }
+        self.u32(tree.units.len() as u32);
+        for (unit, text) in &tree.units {
+            self.u16(*unit);
+            self.text(text);
+        }
+    }
+}
+
+impl CacheReader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn result(&mut self) -> Option<Result<String, String>> {
+        match self.u8()? {
+            0 => Some(Ok(self.string()?)),
+            1 => Some(Err(self.string()?)),
+            _ => None,
+        }
+    }
+
+    fn text(&mut self) -> Option<TreeText> {
+        let caption = self.result()?;
+        let tooltip = match self.u8()? {
+            0 => None,
+            1 => Some(self.result()?),
+            _ => return None,
+        };
+        Some(TreeText { caption, tooltip })
+    }
+
+    fn tree(&mut self) -> Option<LanguageTree> {
+        let mut products = Vec::new();
+        for _i in 0..self.u32()? {
+            let key = ProductKey {
+                product_id: self.u16()?,
+                derivative_id_low: self.u16()?,
+                derivative_id_high: self.u16()?,
+            };
+            let flags = self.u16()?;
+            let mut modes = BTreeMap::new();
+            for _j in 0..self.u32()? {
+                let mode = self.u8()?;
+                let mut menus = BTreeMap::new();
+                for _k in 0..self.u32()? {
+                    let menu = self.u8()?;
+                    let text = self.text()?;
+                    let mut params = BTreeMap::new();
+                    for _l in 0..self.u32()? {
+                        let param = self.u8()?;
+                        let text = self.text()?;
+                        let mut mnemonics = BTreeMap::new();
+                        for _m in 0..self.u32()? {
+                            let value = self.i32()?;
+                            mnemonics.insert(value, self.text()?);
+                        }
+                        params.insert(param, ParamTree { text, mnemonics });
+                    }
+                    menus.insert(menu, MenuTree { text, params });
+                }
+                modes.insert(mode, menus);
+            }
+            products.push(ProductTree { key, flags, modes });
+        }
+        let mut tables = Vec::new();
+        for _i in 0..2 {
+            let mut table = BTreeMap::new();
+            for _j in 0..self.u32()? {
+                let id = self.u16()?;
+                table.insert(id, self.result()?);
+            }
+            tables.push(table);
+        }
+        let keypad_strs = tables.pop()?;
+        let enumerations = tables.pop()?;
+        let mut units = BTreeMap::new();
+        for _i in 0..self.u32()? {
+            let unit = self.u16()?;
+            units.insert(unit, self.text()?);
+        }
+        if self.pos != self.buf.len() {
+            return None;
+        }
+        Some(LanguageTree { products, enumerations, keypad_strs, units })
+    }
+}
+
+///
+/// The CRC recorded in the header of the language file at filepath
+///
+fn read_file_crc(filepath: &str) -> io::Result<u32> {
+    let mut header = [0; 8];
+    File::open(filepath)?.read_exact(&mut header)?;
+    Ok(little_endian_4_bytes(&header[4..8]))
+}
+
+impl Language {
+    ///
+    /// Save the decoded LanguageTree to path, tagged with the CRC of the
+    /// language file so that load_cache can tell when it is stale
+    ///
+    pub fn save_cache(&self, path: &str) -> io::Result<()> {
+        let mut writer = CacheWriter { buf: Vec::new() };
+        writer.bytes(CACHE_MAGIC);
+        writer.u16(CACHE_VERSION);
+        writer.u32(self.get_file_crc());
+        writer.tree(&self.to_tree());
+        fs::write(path, writer.buf)
+    }
+
+    ///
+    /// Load the LanguageTree cached at path for the language file at
+    /// source_path. None if there is no cache, it is from another version,
+    /// or the language file has changed (its CRC differs) since it was saved.
+    ///
+    pub fn load_cache(path: &str, source_path: &str) -> Option<LanguageTree> {
+        let data = fs::read(path).ok()?;
+        let source_crc = read_file_crc(source_path).ok()?;
+
+        let mut reader = CacheReader { buf: &data, pos: 0 };
+        if reader.bytes(4)? != CACHE_MAGIC || reader.u16()? != CACHE_VERSION || reader.u32()? != source_crc {
+            return None;
+        }
+        reader.tree()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+
+    #[test]
+    fn tree_round_trips_through_the_cache() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let path = std::env::temp_dir().join(format!("keypad_sim_{}.cache", std::process::id()));
+        let path = path.to_str().unwrap();
+        lang.save_cache(path).unwrap();
+
+        let tree = Language::load_cache(path, "languageV3.bin");
+        let stale = Language::load_cache(path, "languageV2.bin");
+        fs::remove_file(path).unwrap();
+        assert_eq!(tree, Some(lang.to_tree()));
+        assert_eq!(stale, None);
+    }
+}
@@ -1,7 +1,9 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::rc::Rc;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::str::FromStr;
 use std::vec::Vec;
 use xml::attribute::OwnedAttribute;
 use xml::reader::{EventReader, XmlEvent};
@@ -9,33 +11,91 @@ use xml::reader::{EventReader, XmlEvent};
 #[derive(Clone)]
 pub struct CharacterMaps {
     is_utf8: bool,
-    maps: Rc<_CharacterMaps>,
+    wide: bool, // Schema 4 strings of 3 or 4 byte codes, see as_wide
+    maps: Arc<_CharacterMaps>,
+    bidi_controls: Vec<(u8, BidiControl)>,
+    shift_codes: Option<ShiftCodes>,
+}
+
+///
+/// In-band bytes switching the single byte characters of a string
+/// to another map, and back to the default map
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ShiftCodes {
+    pub shift_out: u8, // Following characters are from map_id
+    pub shift_in: u8, // Following characters are from the default map
+    pub map_id: u16,
+}
+
+///
+/// Unicode directional formatting characters
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BidiControl {
+    Lrm, // Left-to-right mark
+    Rlm, // Right-to-left mark
+    Lre, // Left-to-right embedding
+    Rle, // Right-to-left embedding
+    Pdf, // Pop directional formatting
 }
 
 struct _CharacterMaps {
     maps: Vec<CharacterMap>,
+    encodings: OnceLock<HashMap<String, Vec<u8>>>, // Unicode to packed bytes, built on first use
+    warnings: Vec<String>, // Problems found loading the maps, such as a value defined twice
 }
 
 pub struct CharacterMap {
     id: u16,
     bytes_per: u16,
-    chars: HashMap<u16, Character>,
+    chars: HashMap<u32, Character>,
 }
 
 struct Character {
     unicode: String,
-    count: u32,
+    count: AtomicU32, // Times decoded, see CharacterMaps::with_fresh_counts
 }
 
 impl _CharacterMaps {
     fn empty() -> _CharacterMaps {
         _CharacterMaps {
             maps: Vec::<CharacterMap>::new(),
+            encodings: OnceLock::new(),
+            warnings: Vec::new(),
         }
     }
 
-    fn new(maps: Vec<CharacterMap>) -> _CharacterMaps {
-        _CharacterMaps { maps }
+    fn new(maps: Vec<CharacterMap>, warnings: Vec<String>) -> _CharacterMaps {
+        _CharacterMaps { maps, encodings: OnceLock::new(), warnings }
+    }
+
+    ///
+    /// The reverse of the maps, unicode to the bytes of its code. A single
+    /// byte code is preferred over a 2 byte one, and the lowest code within
+    /// a map, as find_code does.
+    ///
+    fn encodings(&self) -> &HashMap<String, Vec<u8>> {
+        self.encodings.get_or_init(|| {
+            let mut encodings = HashMap::new();
+            for bytes_per in [1, 2] {
+                let mut codes: Vec<(u32, &String)> = Vec::new();
+                for map in self.maps.iter().filter(|x| x.bytes_per == bytes_per) {
+                    let mut chars: Vec<(u32, &String)> = map.chars.iter().map(|(code, ch)| (*code, &ch.unicode)).collect();
+                    chars.sort();
+                    codes.extend(chars);
+                }
+                for (code, unicode) in codes {
+                    let bytes = match bytes_per {
+                        1 if code < 256 => vec![code as u8],
+                        2 if code < 0x2000 => vec![(((code & 0x7F) << 1) | 1) as u8, (0xC0 | (code >> 7)) as u8],
+                        _ => continue,
+                    };
+                    encodings.entry(unicode.clone()).or_insert(bytes);
+                }
+            }
+            encodings
+        })
     }
 }
 
@@ -47,25 +107,280 @@ impl CharacterMaps {
     pub fn utf8() -> CharacterMaps {
         CharacterMaps {
             is_utf8: true,
-            maps: Rc::new(_CharacterMaps::empty()),
+            wide: false,
+            maps: Arc::new(_CharacterMaps::empty()),
+            bidi_controls: Vec::new(),
+            shift_codes: None,
+        }
+    }
+
+    ///
+    /// The same maps, but with strings in UTF-8. The maps are kept for
+    /// any region of a file still in the legacy encoding.
+    ///
+    pub fn as_utf8(&self) -> CharacterMaps {
+        CharacterMaps {
+            is_utf8: true,
+            wide: false,
+            maps: self.maps.clone(),
+            bidi_controls: self.bidi_controls.clone(),
+            shift_codes: self.shift_codes,
+        }
+    }
+
+    ///
+    /// The same maps, but with schema 4 strings of codes of the 3 or 4
+    /// byte map rather than UTF-8
+    ///
+    pub fn as_wide(&self) -> CharacterMaps {
+        CharacterMaps {
+            is_utf8: false,
+            wide: true,
+            maps: self.maps.clone(),
+            bidi_controls: self.bidi_controls.clone(),
+            shift_codes: self.shift_codes,
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        self.wide
+    }
+
+    ///
+    /// Decode the given single byte codes as bidi control characters when
+    /// the character map does not define them, instead of failing
+    ///
+    pub fn with_bidi_controls(&self, controls: &[(u8, BidiControl)]) -> CharacterMaps {
+        CharacterMaps {
+            is_utf8: self.is_utf8,
+            wide: self.wide,
+            maps: self.maps.clone(),
+            bidi_controls: controls.to_vec(),
+            shift_codes: self.shift_codes,
         }
     }
 
+    ///
+    /// Decode single byte codes between shift_out and shift_in bytes
+    /// with another map, for locales mixing two scripts in a string
+    ///
+    pub fn with_shift_codes(&self, shift_codes: ShiftCodes) -> CharacterMaps {
+        CharacterMaps {
+            is_utf8: self.is_utf8,
+            wide: self.wide,
+            maps: self.maps.clone(),
+            bidi_controls: self.bidi_controls.clone(),
+            shift_codes: Some(shift_codes),
+        }
+    }
+
+    pub fn get_shift_codes(&self) -> Option<ShiftCodes> {
+        self.shift_codes
+    }
+
+    ///
+    /// The problems found loading the maps, such as two chars of a map
+    /// with the same value. Empty for a clean CharacterMaps.xml.
+    ///
+    pub fn get_warnings(&self) -> &[String] {
+        &self.maps.warnings
+    }
+
+    fn get_bidi_control(&self, ch: u8) -> Option<BidiControl> {
+        for (code, control) in &self.bidi_controls {
+            if *code == ch {
+                return Some(*control);
+            }
+        }
+        None
+    }
+
+    ///
+    /// The character map map_id
+    ///
+    pub fn get_map(&self, map_id: u16) -> Option<&CharacterMap> {
+        self.maps.maps.iter().find(|x| x.id == map_id)
+    }
+
+    ///
+    /// A copy of the maps with every count zero. Clones share the counts,
+    /// so each language's blob takes a copy to count its own usage.
+    ///
+    pub fn with_fresh_counts(&self) -> CharacterMaps {
+        let maps = self.maps.maps.iter().map(|x| x.with_fresh_counts()).collect();
+        CharacterMaps {
+            maps: Arc::new(_CharacterMaps::new(maps, self.maps.warnings.clone())),
+            ..self.clone()
+        }
+    }
+
+    ///
+    /// The (map id, code) of every character decoded so far, with the
+    /// number of times it was decoded
+    ///
+    pub fn char_usage(&self) -> HashMap<(u16, u32), u32> {
+        let mut usage = HashMap::new();
+        for map in &self.maps.maps {
+            for (code, count) in map.used_codepoints() {
+                usage.insert((map.id, code), count);
+            }
+        }
+        usage
+    }
+
+    ///
+    /// The id of the (first) map with bytes_per bytes per character
+    ///
+    pub fn map_id(&self, bytes_per: u16) -> Option<u16> {
+        self.maps.maps.iter().find(|x| x.bytes_per == bytes_per).map(|x| x.id)
+    }
+
+    ///
+    /// Does the map with bytes_per bytes per character define code
+    ///
+    pub fn contains_code(&self, bytes_per: u16, code: u16) -> bool {
+        match self.maps.maps.iter().find(|x| x.bytes_per == bytes_per) {
+            Some(map) => map.chars.contains_key(&(code as u32)),
+            None => false,
+        }
+    }
+
+    ///
+    /// Decode a packed 2 byte code, None if the map does not define it
+    ///
     pub fn decode_2bytes(&self, ch: u16) -> Option<String> {
         for map in &self.maps.maps {
             if map.bytes_per == 2 {
-                let unicode = map.get_unicode(ch);
-                return Some(unicode);
+                return map.get_unicode(ch as u32);
             }
         }
         panic!("Failed to decode 2 byte code {}", ch);
     }
 
+    ///
+    /// The id and bytes per character of the (first) map of 3 or 4 byte
+    /// codes, such as a CJK map
+    ///
+    pub fn wide_map(&self) -> Option<(u16, u16)> {
+        self.maps.maps.iter().find(|x| x.bytes_per == 3 || x.bytes_per == 4).map(|x| (x.id, x.bytes_per))
+    }
+
+    ///
+    /// Decode a code of the wide map, None if it does not define the code
+    ///
+    pub fn decode_wide(&self, code: u32) -> Option<String> {
+        let map = self.maps.maps.iter().find(|x| x.bytes_per == 3 || x.bytes_per == 4)?;
+        map.chars.get(&code).map(|x| x.get_unicode())
+    }
+
+    ///
+    /// Find the code of unicode in the character map map_id
+    ///
+    pub fn find_code(&self, map_id: u16, unicode: &str) -> Option<u16> {
+        let mut code = None;
+        for map in &self.maps.maps {
+            if map.id == map_id {
+                code = map.find_code(unicode).and_then(|x| u16::try_from(x).ok());
+            }
+        }
+        code
+    }
+
+    ///
+    /// The bytes of the code of the character unicode, a single byte or
+    /// a packed 2 byte code, None if no map has it
+    ///
+    pub fn encode_char(&self, unicode: &str) -> Option<Vec<u8>> {
+        if self.is_utf8 {
+            return Some(unicode.as_bytes().to_vec());
+        }
+        self.maps.encodings().get(unicode).cloned()
+    }
+
+    ///
+    /// Encode text as the bytes of a language file string, preferring
+    /// single byte codes over the packed 2 byte codes
+    ///
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        for ch in text.chars() {
+            match self.encode_char(&ch.to_string()) {
+                Some(code) => bytes.extend(code),
+                None => return Err(format!("No code for '{}' in {}", ch, text)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    ///
+    /// Check every (map id, code) defined by other is defined with the
+    /// same unicode here, else return the missing or changed codes
+    ///
+    pub fn is_superset_of(&self, other: &CharacterMaps) -> Result<(), Vec<(u16, u32)>> {
+        let mut missing = Vec::new();
+        for other_map in &other.maps.maps {
+            let map = self.maps.maps.iter().find(|x| x.id == other_map.id);
+            for (value, ch) in &other_map.chars {
+                let found = match map {
+                    Some(map) => map.chars.get(value),
+                    None => None,
+                };
+                match found {
+                    Some(x) if x.unicode == ch.unicode => {}
+                    _ => missing.push((other_map.id, *value)),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        missing.sort();
+        Err(missing)
+    }
+
+    ///
+    /// List each map, sorted by id, as its code => unicode sorted by code
+    ///
+    pub fn to_table_string(&self) -> String {
+        let mut maps: Vec<&CharacterMap> = self.maps.maps.iter().collect();
+        maps.sort_by_key(|x| x.id);
+
+        let mut text = String::new();
+        for map in maps {
+            text += &format!("Character Map {}, size of chars {}\n", map.id, map.bytes_per);
+            let mut values: Vec<&u32> = map.chars.keys().collect();
+            values.sort();
+            for value in values {
+                text += &format!("{} => {}\n", value, map.chars[value].unicode);
+            }
+        }
+        text
+    }
+
+    ///
+    /// Decode a single byte code with the map map_id, for shifted text
+    ///
+    pub fn decode_shifted_byte(&self, map_id: u16, ch: u8) -> Option<String> {
+        for map in &self.maps.maps {
+            if map.id == map_id {
+                return map.get_unicode(ch as u32);
+            }
+        }
+        panic!("Failed to decode shifted code {}, no character map {}", ch, map_id);
+    }
+
+    ///
+    /// Decode a single byte code, None if the map does not define it
+    ///
     pub fn decode_byte(&self, ch: u8) -> Option<String> {
         for map in &self.maps.maps {
             if map.bytes_per == 1 {
-                let unicode = map.get_unicode(ch as u16);
-                return Some(unicode);
+                if !map.chars.contains_key(&(ch as u32)) {
+                    if let Some(control) = self.get_bidi_control(ch) {
+                        return Some(control.as_char().to_string());
+                    }
+                }
+                return map.get_unicode(ch as u32);
             }
         }
         panic!("Failed to decode 1 byte code {}", ch);
@@ -78,46 +393,69 @@ impl PartialEq for CharacterMaps {
     }
 }
 
+impl BidiControl {
+    pub fn as_char(self) -> char {
+        match self {
+            BidiControl::Lrm => '\u{200E}',
+            BidiControl::Rlm => '\u{200F}',
+            BidiControl::Lre => '\u{202A}',
+            BidiControl::Rle => '\u{202B}',
+            BidiControl::Pdf => '\u{202C}',
+        }
+    }
+}
+
 impl CharacterMap {
-    fn new(attributes: &Vec<OwnedAttribute>) -> CharacterMap {
+    fn new(attributes: &Vec<OwnedAttribute>) -> Result<CharacterMap, String> {
         let mut id = 0;
         let mut bytes_per = 0;
         for attr in attributes {
             match attr.name.local_name.as_str() {
-                "id" => id = attr.value.parse().unwrap(),
-                "bytesPerCharacter" => bytes_per = attr.value.parse().unwrap(),
+                "id" => id = parse_attribute(attr)?,
+                "bytesPerCharacter" => bytes_per = parse_attribute(attr)?,
                 _ => {}
             };
         }
-        CharacterMap {
+        Ok(CharacterMap {
             id: id,
             bytes_per,
-            chars: HashMap::<u16, Character>::new(),
-        }
+            chars: HashMap::<u32, Character>::new(),
+        })
     }
 
-    fn get_unicode(&self, ch: u16) -> String {
-        match self.chars.get(&ch) {
-            Some(ch) => ch,
-            None => {
-                self.display();
-                panic!(
-                    "Failed to find {} in character map {} size {}",
-                    ch, self.id, self.bytes_per
-                )
-            }
-        }
-        .get_unicode()
+    fn with_fresh_counts(&self) -> CharacterMap {
+        let chars = self.chars.iter().map(|(code, ch)| (*code, Character::new(ch.unicode.clone()))).collect();
+        CharacterMap { id: self.id, bytes_per: self.bytes_per, chars }
+    }
+
+    ///
+    /// The codes of this map decoded at least once, with the times each
+    /// was decoded, sorted by code
+    ///
+    pub fn used_codepoints(&self) -> Vec<(u32, u32)> {
+        let mut used: Vec<(u32, u32)> = self.chars.iter()
+            .map(|(code, ch)| (*code, ch.count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        used.sort();
+        used
     }
 
-    fn display(&self) {
-        println!(
-            "Character Map {}, size of chars {}",
-            self.id, self.bytes_per
-        );
+    fn find_code(&self, unicode: &str) -> Option<u32> {
+        let mut code = None;
         for (value, ch) in &self.chars {
-            ch.display(*value);
+            if ch.unicode == unicode && code.is_none_or(|x| *value < x) {
+                code = Some(*value);
+            }
         }
+        code
+    }
+
+    ///
+    /// The unicode of the code ch, None if this map does not define it
+    ///
+    fn get_unicode(&self, ch: u32) -> Option<String> {
+        self.chars.get(&ch).map(|x| x.get_unicode())
     }
 }
 
@@ -125,63 +463,74 @@ impl Character {
     fn new(unicode: String) -> Character {
         Character {
             unicode: unicode,
-            count: 0,
+            count: AtomicU32::new(0),
         }
     }
 
     fn get_unicode(&self) -> String {
-        //        self.count += 1;
+        self.count.fetch_add(1, Ordering::Relaxed);
         self.unicode.clone()
     }
 
-    fn display(&self, value: u16) {
-        println!("{} => {} / count = {}", value, self.unicode, self.count);
-    }
-
-    fn create_from_xml(attributes: &Vec<OwnedAttribute>) -> (u16, Character) {
+    fn create_from_xml(attributes: &Vec<OwnedAttribute>) -> Result<(u32, Character), String> {
         let mut unicode: String = "".to_string();
         let mut value = 0;
         for attr in attributes {
             match attr.name.local_name.as_str() {
                 "name" => unicode = attr.value.clone(),
-                "value" => value = attr.value.parse().unwrap(),
+                "value" => value = parse_attribute(attr)?,
                 _ => {}
             };
         }
-        (value, Character::new(unicode))
+        Ok((value, Character::new(unicode)))
     }
 }
 
+///
+/// The number in attribute attr, an error naming the attribute if it is not one
+///
+fn parse_attribute<T: FromStr>(attr: &OwnedAttribute) -> Result<T, String> {
+    attr.value.parse().map_err(|_| format!("Invalid {} \"{}\"", attr.name.local_name, attr.value))
+}
+
 /// Some XML starts with a BOM that causes issues!
-fn skip_bom(fp: &mut BufReader<File>) {
+fn skip_bom<R: Read + Seek>(fp: &mut BufReader<R>) -> Result<(), String> {
     let mut bom = [0; 4];
-    match fp.read_exact(&mut bom) {
-        Ok(num) => num,
-        Err(_) => {
-            panic!("Failed to read XML BOM");
-        }
-    }
-    if bom[0] == 0xEF {
-        fp.seek_relative(-1).unwrap();
-    } else {
-        fp.seek_relative(-4).unwrap();
+    if let Err(x) = fp.read_exact(&mut bom) {
+        return Err(format!("Failed to read XML BOM: {}", x));
     }
+    let rewind = if bom[0] == 0xEF { -1 } else { -4 };
+    fp.seek_relative(rewind).map_err(|x| format!("Failed to read XML BOM: {}", x))
 }
 
-pub fn read_character_file(filepath: &str) -> CharacterMaps {
+///
+/// Read the character maps of a CharacterMaps.xml file, an error if it
+/// cannot be read, is not well formed XML or has an invalid number
+///
+pub fn read_character_file(filepath: &str) -> Result<CharacterMaps, String> {
     let fp = match File::open(filepath) {
         Ok(fp) => fp,
-        Err(_) => {
-            panic!("Failed to open {}", String::from(filepath));
+        Err(x) => {
+            return Err(format!("Failed to open {}: {}", filepath, x));
         }
     };
-    let mut fp = BufReader::new(fp);
+    read_character_stream(BufReader::new(fp))
+}
+
+///
+/// Read the character maps from the contents of a CharacterMaps.xml file
+///
+pub fn read_character_data(data: &[u8]) -> Result<CharacterMaps, String> {
+    read_character_stream(BufReader::new(Cursor::new(data)))
+}
 
-    skip_bom(&mut fp);
+fn read_character_stream<R: Read + Seek>(mut fp: BufReader<R>) -> Result<CharacterMaps, String> {
+    skip_bom(&mut fp)?;
 
     let parser = EventReader::new(fp);
 
     let mut maps = Vec::new();
+    let mut warnings = Vec::new();
 
     for e in parser {
         match e {
@@ -192,25 +541,165 @@ pub fn read_character_file(filepath: &str) -> CharacterMaps {
             }) => {
                 match name.local_name.as_str() {
                     "characterMap" => {
-                        maps.push(CharacterMap::new(&attributes));
+                        maps.push(CharacterMap::new(&attributes)?);
                     }
                     "char" => {
-                        let (value, char_def) = Character::create_from_xml(&attributes);
+                        let (value, char_def) = Character::create_from_xml(&attributes)?;
+                        if maps.is_empty() {
+                            return Err(format!("char {} is not within a characterMap", value));
+                        }
                         let end = maps.len() - 1;
+                        // A char with no name would silently drop out of decoded strings
+                        if char_def.unicode.is_empty() {
+                            warnings.push(format!("Character map {} char {} has no unicode", maps[end].id, value));
+                        }
                         // println!("{} = {}", value, unicode);
-                        maps[end].chars.insert(value, char_def);
+                        let unicode = char_def.unicode.clone();
+                        if let Some(old) = maps[end].chars.insert(value, char_def) {
+                            let warning = format!(
+                                "Character map {} defines char {} twice, as '{}' and '{}'",
+                                maps[end].id, value, old.unicode, unicode
+                            );
+                            warnings.push(warning);
+                        }
                     }
                     _ => {}
                 }
             }
             Err(e) => {
-                println!("Error: {}", e);
+                return Err(format!("Invalid XML: {}", e));
             }
             _ => {}
         }
     }
-    return CharacterMaps {
+    Ok(CharacterMaps {
         is_utf8: false,
-        maps: Rc::new(_CharacterMaps::new(maps)),
-    };
+        wide: false,
+        maps: Arc::new(_CharacterMaps::new(maps, warnings)),
+        bidi_controls: Vec::new(),
+        shift_codes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::{blob_with_maps, BlobRegions};
+    use crate::options::ParseOptions;
+
+    #[test]
+    fn nameless_char_is_flagged() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?>
+<characterMaps>
+  <characterMap name="Latin" id="1" bytesPerCharacter="1">
+      <char name="A" value="65" />
+      <char value="66" />
+  </characterMap>
+</characterMaps>
+"#;
+        let maps = read_character_data(xml).unwrap();
+        assert_eq!(maps.get_warnings(), ["Character map 1 char 66 has no unicode"]);
+    }
+
+    #[test]
+    fn table_is_sorted_by_map_and_code() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?>
+<characterMaps>
+  <characterMap name="Latin 2" id="2" bytesPerCharacter="1">
+      <char name="Z" value="90" />
+  </characterMap>
+  <characterMap name="Latin" id="1" bytesPerCharacter="1">
+      <char name="b" value="98" />
+      <char name="A" value="65" />
+      <char name="a" value="97" />
+  </characterMap>
+</characterMaps>
+"#;
+        let maps = read_character_data(xml).unwrap();
+        assert_eq!(maps.to_table_string(), "\
+Character Map 1, size of chars 1
+65 => A
+97 => a
+98 => b
+Character Map 2, size of chars 1
+90 => Z
+");
+
+        let table = read_character_file("CharacterMaps.xml").unwrap().to_table_string();
+        assert!(table.lines().any(|x| x == "65 => A"));
+    }
+
+    #[test]
+    fn string_shifting_maps_partway() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?>
+<characterMaps>
+  <characterMap name="Latin" id="1" bytesPerCharacter="1">
+      <char name="A" value="65" />
+      <char name="B" value="66" />
+  </characterMap>
+  <characterMap name="Greek" id="3" bytesPerCharacter="1">
+      <char name="&#945;" value="65" />
+  </characterMap>
+</characterMaps>
+"#;
+        let shift = ShiftCodes { shift_out: 14, shift_in: 15, map_id: 3 };
+        let maps = read_character_data(xml).unwrap().with_shift_codes(shift);
+        let mut fp = blob_with_maps(&[0, 65, 14, 65, 15, 66, 65, 0], maps, ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(blob.get_string(1, 32), Ok("A\u{3b1}BA".to_string()));
+    }
+
+    #[test]
+    fn map_dropping_a_char_is_not_a_superset() {
+        let full = read_character_file("CharacterMaps.xml").unwrap();
+        let xml = String::from_utf8(std::fs::read("CharacterMaps.xml").unwrap()).unwrap();
+        let dropped = xml.replacen(r#"<char name="A" value="65" description="" />"#, "", 1);
+        assert_ne!(dropped, xml);
+        let reduced = read_character_data(dropped.as_bytes()).unwrap();
+
+        assert_eq!(full.is_superset_of(&full), Ok(()));
+        assert_eq!(full.is_superset_of(&reduced), Ok(()));
+        assert_eq!(reduced.is_superset_of(&full), Err(vec![(1, 65)]));
+    }
+
+    #[test]
+    fn direction_marker_in_a_string() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let code = (0x80..=0xff).find(|code| !maps.contains_code(1, *code)).unwrap() as u8;
+        let maps = maps.with_bidi_controls(&[(code, BidiControl::Rlm)]);
+
+        let mut fp = blob_with_maps(&[0, b'A', code, b'B', 0], maps, ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(blob.get_string(1, 32).unwrap(), "A\u{200F}B");
+    }
+
+    #[test]
+    fn decoded_codes_are_counted_per_map() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?>
+<characterMaps>
+  <characterMap name="Latin" id="1" bytesPerCharacter="1">
+      <char name="A" value="65" />
+      <char name="B" value="66" />
+  </characterMap>
+  <characterMap name="CJK" id="4" bytesPerCharacter="3">
+      <char name="&#x4E2D;" value="20013" />
+  </characterMap>
+</characterMaps>
+"#;
+        let maps = read_character_data(xml).unwrap();
+        maps.decode_byte(65);
+        maps.decode_byte(65);
+        maps.decode_byte(66);
+        maps.decode_wide(0x4E2D);
+        assert_eq!(maps.get_map(1).unwrap().used_codepoints(), [(65, 2), (66, 1)]);
+        assert_eq!(maps.get_map(4).unwrap().used_codepoints(), [(0x4E2D, 1)]);
+        assert_eq!(maps.char_usage().len(), 3);
+
+        // A blob counts its own usage, apart from the maps it was given
+        let mut fp = blob_with_maps(&[0, 66, 66, 0], maps.clone(), ParseOptions::default());
+        let blob = fp.freeze(BlobRegions::Text);
+        assert_eq!(blob.get_string(1, 32), Ok("BB".to_string()));
+        assert_eq!(blob.get_char_usage(), HashMap::from([((1, 66), 2)]));
+        assert_eq!(maps.get_map(1).unwrap().used_codepoints(), [(65, 2), (66, 1)]);
+    }
 }
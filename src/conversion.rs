@@ -28,3 +28,17 @@ pub fn little_endian_4_version(bytes: &[u8]) -> String {
 
     format!("V{}.{}.{}.{}", major, minor, patch, build)
 }
+
+///
+/// The IEEE 802.3 CRC-32 of bytes, as used by the firmware
+///
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,42 @@
+use std::any::Any;
+use std::fmt;
+
+use crate::blob::BlobRegions;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+///
+/// A problem found while parsing, located by region and blob offset
+///
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub region: BlobRegions,
+    pub location: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {:?} at offset {}: {}", self.severity, self.region, self.location, self.message)
+    }
+}
+
+///
+/// The message of a caught panic, parse errors are reported by panicking
+/// with a String or &str
+///
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "Unknown error".to_string(),
+        },
+    }
+}
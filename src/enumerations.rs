@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
 
@@ -7,7 +9,7 @@ use crate::blob::{FileBlob, RawBlob, BlobRegions};
 /// to handle string ID values from the drive.
 ///
 pub struct EnumerationsIndex {
-    enumerations: HashMap<u16, EnumerationsIndexEntry>,
+    enumerations: BTreeMap<u16, EnumerationsIndexEntry>,
 }
 
 pub struct EnumerationsIndexEntry {
@@ -16,36 +18,39 @@ pub struct EnumerationsIndexEntry {
     blob: RawBlob,
 }
 
-pub struct EnumerationsIndexIterator {
-    items: Vec<(u16, EnumerationsIndexEntry)>,
+pub struct EnumerationsIndexIterator<'a> {
+    items: btree_map::Iter<'a, u16, EnumerationsIndexEntry>,
 }
 
 impl EnumerationsIndex 
 {
-    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8) -> EnumerationsIndex 
+    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8) -> io::Result<EnumerationsIndex> 
 	{
-        let num_entries = fp.read_le_2bytes(BlobRegions::Enumerations);
+        let num_entries = fp.read_le_2bytes(BlobRegions::Enumerations)?;
+        fp.check_entries(BlobRegions::Enumerations, num_entries as u32)?;
+		let str_len = fp.max_str_len(BlobRegions::Enumerations, if schema < 4 { 16 } else { 256 });
 		if schema < 4 {
-        	let max_str_len = fp.read_le_2bytes(BlobRegions::Enumerations);
-        	let font_family = fp.read_byte(BlobRegions::Enumerations);
-        	let idx_entry_len = fp.read_byte(BlobRegions::Enumerations);
+        	let max_str_len = fp.read_le_2bytes(BlobRegions::Enumerations)?;
+        	let font_family = fp.read_byte(BlobRegions::Enumerations)?;
+        	let idx_entry_len = fp.read_byte(BlobRegions::Enumerations)?;
 
         	if root_font_family != font_family {
             	panic!("Mis-match font_family");
         	}
-        	Self::validate_schema(schema, idx_entry_len, max_str_len);
+        	Self::validate_schema(schema, idx_entry_len, max_str_len, str_len);
+        	fp.add_entry_layout(BlobRegions::Enumerations, idx_entry_len);
 		} else {
-        	let idx_entry_len = fp.read_byte(BlobRegions::Enumerations);
-        	Self::validate_schema(schema, idx_entry_len, 256);
+        	let idx_entry_len = fp.read_byte(BlobRegions::Enumerations)?;
+        	Self::validate_schema(schema, idx_entry_len, str_len, str_len);
+        	fp.add_entry_layout(BlobRegions::Enumerations, idx_entry_len);
 		}
 
-        let mut enumerations = HashMap::new();
+        let mut enumerations = BTreeMap::new();
 
         for _i in 0..num_entries {
             let (enumeration, entry) = match schema {
-                2 => EnumerationsIndexEntry::load_v2(fp),
-                3 => EnumerationsIndexEntry::load_v3(fp, 16),
-                4 => EnumerationsIndexEntry::load_v3(fp, 256),
+                2 => EnumerationsIndexEntry::load_v2(fp, str_len)?,
+                3 | 4 => EnumerationsIndexEntry::load_v3(fp, str_len)?,
                 _ => panic!("Invalid schema"),
             };
             let old = enumerations.insert(enumeration, entry);
@@ -53,12 +58,32 @@ impl EnumerationsIndex
                 panic!("Two entries with same enum!");
             }
         }
+        Ok(EnumerationsIndex { enumerations })
+    }
+
+    pub fn empty() -> EnumerationsIndex {
+        let enumerations = BTreeMap::<u16, EnumerationsIndexEntry>::new();
         EnumerationsIndex { enumerations }
     }
 
-    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16) 
+    ///
+    /// Decode every entry into a lookup table of enumeration to string,
+    /// fails on the first entry that does not decode
+    ///
+    pub fn to_map(&self) -> Result<HashMap<u16, String>, String>
+    {
+        let mut map = HashMap::new();
+        for (enumeration, entry) in &self.enumerations {
+            match entry.to_string() {
+                Ok(x) => map.insert(*enumeration, x),
+                Err(x) => return Err(format!("{} => {}", enumeration, x)),
+            };
+        }
+        Ok(map)
+    }
+
+    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16, req_string_len: u16) 
 	{
-		let mut req_string_len = 16;
         match schema {
             2 => {
                 if idx_entry_len != 6 {
@@ -74,7 +99,6 @@ impl EnumerationsIndex
                 if idx_entry_len != 5 {
                     panic!("V3 EnumerationIndexEntry wrong size 3 != {}", idx_entry_len)
                 }
-				req_string_len = 256;
             }
             _ => panic!("Invalid format"),
         };
@@ -82,28 +106,29 @@ impl EnumerationsIndex
             panic!("Max string len should be {} was {}", req_string_len, max_str_len);
         }
     }
+
+    ///
+    /// Iterate over the enumerations in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &EnumerationsIndexEntry)> {
+        self.enumerations.iter().map(|(key, entry)| (*key, entry))
+    }
 }
 
-impl IntoIterator for &EnumerationsIndex {
+impl<'a> IntoIterator for &'a EnumerationsIndex {
     type Item = (u16, EnumerationsIndexEntry);
-    type IntoIter = EnumerationsIndexIterator;
+    type IntoIter = EnumerationsIndexIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut keys = Vec::new();
-        for key in self.enumerations.keys() {
-            keys.push(*key)
-        }
-        keys.sort();
-        keys.reverse();
-        let mut items = Vec::new();
-        for key in keys {
-            items.push((key, self.enumerations[&key].clone()));
-        }
-        EnumerationsIndexIterator { items }
+        EnumerationsIndexIterator { items: self.enumerations.iter() }
     }
 }
 
 impl EnumerationsIndexEntry {
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
     pub fn get_caption_off(&self) -> u32 {
         self.caption_off
     }
@@ -116,34 +141,41 @@ impl EnumerationsIndexEntry {
         }
     }
 
-    fn load_v2(fp: &mut FileBlob) -> (u16, EnumerationsIndexEntry) 
+    ///
+    /// The caption and, as there is none, no tooltip, as the other entries
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.to_string(), None)
+    }
+
+    fn load_v2(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, EnumerationsIndexEntry)> 
 	{
-        let enumeration = fp.read_le_2bytes(BlobRegions::Enumerations);
-        let offset = fp.read_le_4bytes(BlobRegions::Enumerations);
+        let enumeration = fp.read_le_2bytes(BlobRegions::Enumerations)?;
+        let offset = fp.read_le_4bytes(BlobRegions::Enumerations)?;
         if offset == 0 {
             panic! {"Empty slot"};
         };
         let entry = EnumerationsIndexEntry {
             caption_off: offset,
-			str_len: 16,
-            blob: fp.freeze(),
+			str_len,
+            blob: fp.freeze(BlobRegions::Enumerations),
         };
-        (enumeration, entry)
+        Ok((enumeration, entry))
     }
 
-    fn load_v3(fp: &mut FileBlob, str_len: u16) -> (u16, EnumerationsIndexEntry) 
+    fn load_v3(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, EnumerationsIndexEntry)> 
 	{
-        let enumeration = fp.read_le_2bytes(BlobRegions::Enumerations);
-        let offset = fp.read_le_3bytes(BlobRegions::Enumerations);
+        let enumeration = fp.read_le_2bytes(BlobRegions::Enumerations)?;
+        let offset = fp.read_le_3bytes(BlobRegions::Enumerations)?;
         if offset == 0 {
             panic! {"Empty slot"};
         };
         let entry = EnumerationsIndexEntry {
             caption_off: offset,
 			str_len: str_len,
-            blob: fp.freeze(),
+            blob: fp.freeze(BlobRegions::Enumerations),
         };
-        (enumeration, entry)
+        Ok((enumeration, entry))
     }
 }
 
@@ -165,10 +197,91 @@ impl Clone for EnumerationsIndexEntry {
     }
 }
 
-impl Iterator for EnumerationsIndexIterator {
+impl Iterator for EnumerationsIndexIterator<'_> {
     type Item = (u16, EnumerationsIndexEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.pop()
+        self.items.next().map(|(key, entry)| (*key, entry.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::{ParseLimits, ParseOptions};
+
+    ///
+    /// A V3 enumerations index of the entries, followed by their strings
+    ///
+    fn load_v3(entries: &[(u16, &str)], max_str_len: u16, options: ParseOptions) -> EnumerationsIndex {
+        let mut data = Vec::new();
+        data.extend((entries.len() as u16).to_le_bytes());
+        data.extend(max_str_len.to_le_bytes());
+        data.push(0);
+        data.push(5);
+        let mut text_off = (data.len() + 5 * entries.len()) as u32;
+        for (enumeration, text) in entries {
+            data.extend(enumeration.to_le_bytes());
+            data.extend(&text_off.to_le_bytes()[..3]);
+            text_off += text.len() as u32 + 1;
+        }
+        for (_, text) in entries {
+            data.extend(text.as_bytes());
+            data.push(0);
+        }
+        let mut fp = blob_from_bytes(&data, options);
+        EnumerationsIndex::from(&mut fp, 3, 0).unwrap()
+    }
+
+    #[test]
+    fn low_entry_limit_aborts_a_huge_count() {
+        let limits = ParseLimits { max_entries: 100, ..ParseLimits::default() };
+        let options = ParseOptions { limits, ..ParseOptions::default() };
+        let mut fp = blob_from_bytes(&[0xFF, 0xFF, 16, 0, 0, 5], options);
+        let error = EnumerationsIndex::from(&mut fp, 3, 0).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Enumerations index of 65535 entries exceeds the limit of 100 at offset 2");
+    }
+
+    #[test]
+    fn low_offset_limit_stops_decoding() {
+        let limits = ParseLimits { max_offsets: 2, ..ParseLimits::default() };
+        let options = ParseOptions { limits, ..ParseOptions::default() };
+        let index = load_v3(&[(3, "Off"), (7, "On"), (9, "Auto")], 16, options);
+        let error = index.to_map().err().unwrap();
+        assert!(error.starts_with("9 => ") && error.ends_with("More than 2 distinct string offsets"), "{}", error);
+    }
+
+    #[test]
+    fn to_map_resolves_each_id() {
+        let index = load_v3(&[(3, "Off"), (7, "On"), (9, "Auto")], 16, ParseOptions::default());
+        let map = index.to_map().unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&7], "On");
+        assert_eq!(map[&9], "Auto");
+        assert!(!map.contains_key(&4));
+    }
+
+    #[test]
+    fn custom_max_str_len_caps_decode_and_validation() {
+        let entries = [(1, "Automatic"), (2, "Off")];
+        let index = load_v3(&entries, 16, ParseOptions::default());
+        assert_eq!(index.to_map().unwrap()[&1], "Automatic");
+
+        let mut options = ParseOptions::default();
+        options.max_str_lens.insert(BlobRegions::Enumerations, 8);
+        let index = load_v3(&entries, 8, options);
+        let (_, entry) = index.iter().next().unwrap();
+        assert_eq!(entry.get_str_len(), 8);
+        let map = index.to_map().unwrap();
+        assert_eq!(map[&1], "Automati");
+        assert_eq!(map[&2], "Off");
+    }
+
+    #[test]
+    #[should_panic(expected = "Max string len should be 16 was 8")]
+    fn max_str_len_must_match_without_a_cap() {
+        load_v3(&[(1, "Off")], 8, ParseOptions::default());
     }
 }
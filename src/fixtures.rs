@@ -0,0 +1,46 @@
+use std::io::{self, Cursor};
+
+use crate::characters::{read_character_file, CharacterMaps};
+use crate::language::Language;
+use crate::options::ParseOptions;
+
+pub fn maps() -> CharacterMaps {
+    read_character_file("CharacterMaps.xml").unwrap()
+}
+
+///
+/// The language file parsed after edit has changed its bytes, the
+/// CRC is not checked
+///
+pub fn parse_edited(path: &str, edit: impl FnOnce(&mut Vec<u8>)) -> io::Result<Language> {
+    let mut data = std::fs::read(path).unwrap();
+    edit(&mut data);
+    let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+    Language::create_from_file_with_options(&mut Cursor::new(data), maps(), &options)
+}
+
+///
+/// A V4 file of ten stub products and two units, unit 1 with its
+/// caption as its tooltip and unit 2 with a tooltip of its own
+///
+pub fn v4_units_file() -> Vec<u8> {
+    let mut data = vec![0; 32];
+    data[8..10].copy_from_slice(&4u16.to_le_bytes());
+    data.extend(3u16.to_le_bytes());
+    for offset in [43u32, 0, 155] {
+        data.extend(&offset.to_le_bytes()[..3]);
+    }
+    data.extend([10, 11]);
+    for product_id in 1..=10u16 {
+        data.extend(product_id.to_le_bytes());
+        data.extend([0, 0, 0xFF, 0xFF, 0, 0, 0, 0, 0]);
+    }
+    data.extend(2u16.to_le_bytes());
+    data.push(8);
+    data.extend([1, 0, 174, 0, 0, 174, 0, 0]);
+    data.extend([2, 0, 174, 0, 0, 179, 0, 0]);
+    data.extend(b"Same\0Other\0");
+    let file_len = data.len() as u32;
+    data[0..4].copy_from_slice(&file_len.to_le_bytes());
+    data
+}
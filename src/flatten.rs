@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::blob::BlobRegions;
+use crate::language::Language;
+
+///
+/// Identifies a product by its id and derivative range
+///
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct ProductKey {
+    pub product_id: u16,
+    pub derivative_id_low: u16,
+    pub derivative_id_high: u16,
+}
+
+///
+/// Location of a decoded string within a Language
+///
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum IdPath {
+    Menu { product: ProductKey, mode: u8, menu: u8 },
+    Param { product: ProductKey, mode: u8, menu: u8, param: u8 },
+    Mnemonic { product: ProductKey, mode: u8, menu: u8, param: u8, value: i32 },
+    Enumeration(u16),
+    KeypadStr(u16),
+    Unit(u16),
+}
+
+///
+/// A decoded entry of the Language, flattened out of the tree
+///
+pub struct FlatEntry {
+    pub path: IdPath,
+    pub caption: Result<String, String>,
+    pub tooltip: Option<Result<String, String>>,
+    pub caption_off: u32,
+    pub tooltip_off: u32,
+    pub str_len: u16,
+}
+
+impl IdPath {
+    ///
+    /// The region of the blob holding the entry
+    ///
+    pub fn region(&self) -> BlobRegions {
+        match self {
+            IdPath::Menu { .. } => BlobRegions::Menus,
+            IdPath::Param { .. } => BlobRegions::Parameters,
+            IdPath::Mnemonic { .. } => BlobRegions::Mnemonics,
+            IdPath::Enumeration(_) => BlobRegions::Enumerations,
+            IdPath::KeypadStr(_) => BlobRegions::KeypadStrs,
+            IdPath::Unit(_) => BlobRegions::Units,
+        }
+    }
+}
+
+impl FlatEntry {
+    ///
+    /// The caption and tooltip, or None if either failed to decode
+    ///
+    pub fn decoded(&self) -> Option<(String, Option<String>)> {
+        let caption = self.caption.clone().ok()?;
+        let tooltip = match &self.tooltip {
+            Some(Ok(x)) => Some(x.clone()),
+            Some(Err(_)) => return None,
+            None => None,
+        };
+        Some((caption, tooltip))
+    }
+}
+
+impl fmt::Display for ProductKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.derivative_id_high > self.derivative_id_low {
+            write!(f, "{} Derv {} - {}", self.product_id, self.derivative_id_low, self.derivative_id_high)
+        } else {
+            write!(f, "{} Derv {}", self.product_id, self.derivative_id_low)
+        }
+    }
+}
+
+impl fmt::Display for IdPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdPath::Menu { product, mode, menu } => write!(f, "{} / Mode {} / M.{}", product, mode, menu),
+            IdPath::Param { product, mode, menu, param } => {
+                write!(f, "{} / Mode {} / M.{} / P.{}", product, mode, menu, param)
+            }
+            IdPath::Mnemonic { product, mode, menu, param, value } => {
+                write!(f, "{} / Mode {} / M.{} / P.{} / {}", product, mode, menu, param, value)
+            }
+            IdPath::Enumeration(id) => write!(f, "Enumeration {}", id),
+            IdPath::KeypadStr(id) => write!(f, "Keypad str {}", id),
+            IdPath::Unit(id) => write!(f, "Unit {}", id),
+        }
+    }
+}
+
+impl Language {
+    ///
+    /// Decode every entry of the Language into a flat list, in tree order
+    /// followed by the enumerations, keypad strings and units
+    ///
+    pub fn flatten(&self) -> Vec<FlatEntry> {
+        let mut entries = Vec::new();
+
+        for product in self.get_products().iter() {
+            let (derivative_id_low, derivative_id_high) = product.get_derivative_ids();
+            let product_key = ProductKey {
+                product_id: product.get_product_id(),
+                derivative_id_low,
+                derivative_id_high,
+            };
+            for (mode, mode_entry) in product.get_modes().iter() {
+                for (menu, menu_entry) in mode_entry.get_menus().iter() {
+                    entries.push(FlatEntry {
+                        path: IdPath::Menu { product: product_key, mode, menu },
+                        caption: menu_entry.caption(),
+                        tooltip: menu_entry.tooltip(),
+                        caption_off: menu_entry.get_caption_off(),
+                        tooltip_off: menu_entry.get_tooltip_off(),
+                        str_len: menu_entry.get_str_len(),
+                    });
+                    for (param, param_entry) in menu_entry.get_params().iter() {
+                        entries.push(FlatEntry {
+                            path: IdPath::Param { product: product_key, mode, menu, param },
+                            caption: param_entry.caption(),
+                            tooltip: param_entry.tooltip(),
+                            caption_off: param_entry.get_caption_off(),
+                            tooltip_off: param_entry.get_tooltip_off(),
+                            str_len: param_entry.get_str_len(),
+                        });
+                        for (value, mnemonic_entry) in param_entry.get_mnemonics().iter() {
+                            entries.push(FlatEntry {
+                                path: IdPath::Mnemonic { product: product_key, mode, menu, param, value },
+                                caption: mnemonic_entry.caption(),
+                                tooltip: mnemonic_entry.tooltip(),
+                                caption_off: mnemonic_entry.get_caption_off(),
+                                tooltip_off: mnemonic_entry.get_tooltip_off(),
+                                str_len: mnemonic_entry.get_str_len(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        for (enumeration, entry) in self.get_enumerations().iter() {
+            entries.push(FlatEntry {
+                path: IdPath::Enumeration(enumeration),
+                caption: entry.to_string(),
+                tooltip: None,
+                caption_off: entry.get_caption_off(),
+                tooltip_off: 0,
+                str_len: entry.get_str_len(),
+            });
+        }
+
+        for (num, entry) in self.get_keypad_strs().iter() {
+            entries.push(FlatEntry {
+                path: IdPath::KeypadStr(num),
+                caption: entry.to_string(),
+                tooltip: None,
+                caption_off: entry.get_caption_off(),
+                tooltip_off: 0,
+                str_len: entry.get_str_len(),
+            });
+        }
+
+        for (unit, entry) in self.get_units().iter() {
+            entries.push(FlatEntry {
+                path: IdPath::Unit(unit),
+                caption: entry.caption(),
+                tooltip: entry.tooltip(),
+                caption_off: entry.get_caption_off(),
+                tooltip_off: entry.get_tooltip_off(),
+                str_len: entry.get_str_len(),
+            });
+        }
+        entries
+    }
+
+    ///
+    /// The decoded strings (captions and tooltips) with a character whose
+    /// source code is in lo to hi, e.g. to find CJK text in a Latin locale
+    ///
+    pub fn strings_with_codepoint_range(&self, lo: u16, hi: u16) -> Vec<(IdPath, String)> {
+        let mut found = Vec::new();
+        for entry in self.flatten() {
+            let mut strings = vec![(entry.caption_off, entry.caption)];
+            if let Some(tooltip) = entry.tooltip {
+                strings.push((entry.tooltip_off, tooltip));
+            }
+            for (off, text) in strings {
+                if let Ok(text) = text {
+                    if off != 0 && self.get_codes(off).iter().any(|x| (lo..=hi).contains(x)) {
+                        found.push((entry.path, text));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    ///
+    /// Every caption that decodes, sorted case-insensitively, with its location
+    ///
+    pub fn captions_sorted(&self) -> Vec<(String, IdPath)> {
+        let mut captions = Vec::new();
+        for entry in self.flatten() {
+            if let Ok(caption) = entry.caption {
+                captions.push((caption, entry.path));
+            }
+        }
+        captions.sort_by_cached_key(|(caption, path)| (caption.to_lowercase(), caption.clone(), *path));
+        captions
+    }
+
+    ///
+    /// Every menu, in every mode of every product, holding parameter
+    /// param_num, with the parameter's caption there
+    ///
+    pub fn parameter_occurrences(&self, param_num: u8) -> Vec<(IdPath, String)> {
+        let mut occurrences = Vec::new();
+        for entry in self.flatten() {
+            if let (IdPath::Param { param, .. }, Ok(caption)) = (entry.path, entry.caption) {
+                if param == param_num {
+                    occurrences.push((entry.path, caption));
+                }
+            }
+        }
+        occurrences
+    }
+
+    ///
+    /// The n longest captions and tooltips of each region, longest first,
+    /// by their length in bytes as decoded (up to the max string length)
+    ///
+    pub fn longest_strings(&self, n: usize) -> HashMap<BlobRegions, Vec<(usize, IdPath, String)>> {
+        let mut longest = HashMap::<BlobRegions, Vec<(usize, IdPath, String)>>::new();
+        for entry in self.flatten() {
+            let mut strings = vec![(entry.caption_off, entry.caption)];
+            if let Some(tooltip) = entry.tooltip {
+                strings.push((entry.tooltip_off, tooltip));
+            }
+            for (off, text) in strings {
+                if let Ok(text) = text {
+                    if off == 0 {
+                        continue;
+                    }
+                    let len = self.get_raw_bytes(off, entry.str_len).len();
+                    longest.entry(entry.path.region()).or_default().push((len, entry.path, text));
+                }
+            }
+        }
+        for strings in longest.values_mut() {
+            strings.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+            strings.truncate(n);
+        }
+        longest
+    }
+
+    ///
+    /// Every decoded caption and tooltip once, in the order they are laid
+    /// out in the file, for lining up with a hex dump. Strings that failed
+    /// to decode are left out.
+    ///
+    pub fn strings_by_offset(&self) -> Vec<(u32, String)> {
+        let mut strings = BTreeMap::new();
+        for entry in self.flatten() {
+            if let Ok(caption) = entry.caption {
+                strings.insert(entry.caption_off, caption);
+            }
+            if let Some(Ok(tooltip)) = entry.tooltip {
+                strings.insert(entry.tooltip_off, tooltip);
+            }
+        }
+        strings.remove(&0);
+        strings.into_iter().collect()
+    }
+
+    ///
+    /// Just the captions and tooltips that failed to decode, with the
+    /// offset of the string in the blob and the error
+    ///
+    pub fn decode_failures(&self) -> Vec<(IdPath, u32, String)> {
+        let mut failures = Vec::new();
+        for entry in self.flatten() {
+            if let Err(error) = entry.caption {
+                failures.push((entry.path, entry.caption_off, error));
+            }
+            if let Some(Err(error)) = entry.tooltip {
+                failures.push((entry.path, entry.tooltip_off, error));
+            }
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+    use crate::options::ParseOptions;
+
+    #[test]
+    fn captions_sorted_is_alphabetical() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let captions = lang.captions_sorted();
+        assert_eq!(captions.len(), lang.flatten().iter().filter(|x| x.caption.is_ok()).count());
+        assert!(captions.len() > 1);
+        for pair in captions.windows(2) {
+            assert!(pair[0].0.to_lowercase() <= pair[1].0.to_lowercase(), "{:?} > {:?}", pair[0].0, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn string_with_an_out_of_range_char_is_flagged() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let clean = read_language_file("languageV3.bin", maps.clone()).unwrap();
+        clean.flatten();
+        let usage = clean.get_char_usage();
+        let code = (0x80..0x100u16).step_by(2)
+            .find(|code| maps.contains_code(1, *code) && !usage.contains_key(&(1, *code as u32)))
+            .unwrap();
+        assert!(clean.strings_with_codepoint_range(code, code).is_empty());
+
+        let (unit, entry) = clean.get_units().iter().next().unwrap();
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        data[entry.get_caption_off() as usize] = code as u8;
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut std::io::Cursor::new(data), maps, &options).unwrap();
+
+        let found = lang.strings_with_codepoint_range(code, code);
+        assert!(found.iter().any(|(path, _)| *path == IdPath::Unit(unit)), "{:?}", found);
+    }
+
+    #[test]
+    fn param_12_in_two_menus() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let occurrences = lang.parameter_occurrences(12);
+        let expected: Vec<(IdPath, String)> = lang.flatten().into_iter()
+            .filter(|x| matches!(x.path, IdPath::Param { param: 12, .. }))
+            .map(|x| (x.path, x.caption.unwrap()))
+            .collect();
+        assert_eq!(occurrences, expected);
+
+        let mut menus = Vec::new();
+        for (path, _) in &occurrences {
+            if let IdPath::Param { menu, .. } = path {
+                if !menus.contains(menu) {
+                    menus.push(*menu);
+                }
+            }
+        }
+        assert!(menus.len() >= 2, "{:?}", menus);
+    }
+
+    #[test]
+    fn longest_param_caption_is_first() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let longest = lang.longest_strings(3);
+        let params = &longest[&BlobRegions::Parameters];
+        assert_eq!(params.len(), 3);
+        assert!(params.windows(2).all(|pair| pair[0].0 >= pair[1].0));
+
+        let (path, caption) = lang.flatten().into_iter()
+            .filter(|x| x.path.region() == BlobRegions::Parameters && x.caption_off != 0)
+            .max_by_key(|x| (lang.get_raw_bytes(x.caption_off, x.str_len).len(), std::cmp::Reverse(x.path)))
+            .map(|x| (x.path, x.caption.unwrap()))
+            .unwrap();
+        assert_eq!((params[0].1, &params[0].2), (path, &caption));
+    }
+
+    #[test]
+    fn strings_by_offset_ascend() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let strings = lang.strings_by_offset();
+        assert!(!strings.is_empty());
+        assert!(strings[0].0 > 0);
+        assert!(strings.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let (unit, entry) = lang.get_units().iter().next().unwrap();
+        let off = entry.get_caption_off();
+        let caption = entry.caption().unwrap();
+        assert!(strings.contains(&(off, caption)), "unit {}", unit);
+    }
+
+    #[test]
+    fn two_broken_offsets_give_two_failures() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let clean = read_language_file("languageV3.bin", maps.clone()).unwrap();
+        assert!(clean.decode_failures().is_empty());
+
+        let units = clean.get_stats().regions.iter()
+            .find(|(_, _, region)| *region == BlobRegions::Units)
+            .map(|(start, _, _)| *start)
+            .unwrap();
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        let mut broken = Vec::new();
+        for (unit, entry) in clean.get_units().iter().take(2) {
+            let off = entry.get_caption_off().to_le_bytes();
+            let pos = (units..data.len() - 3)
+                .find(|pos| data[*pos..*pos + 3] == off[..3])
+                .unwrap();
+            data[pos..pos + 3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+            broken.push(IdPath::Unit(unit));
+        }
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut std::io::Cursor::new(data), maps, &options).unwrap();
+
+        let failures = lang.decode_failures();
+        assert_eq!(failures.len(), 2, "{:?}", failures);
+        for (path, off, error) in &failures {
+            assert!(broken.contains(path), "{:?}", path);
+            assert_eq!(*off, 0xFFFFFF);
+            assert!(error.contains("past the end of the file"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unmapped_code_is_a_failure() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let clean = read_language_file("languageV3.bin", maps.clone()).unwrap();
+        let code = (1..0xC0u16)
+            .find(|code| !maps.contains_code(1, *code) && maps.decode_byte(*code as u8).is_none())
+            .unwrap();
+
+        let (unit, entry) = clean.get_units().iter().next().unwrap();
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        data[entry.get_caption_off() as usize] = code as u8;
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut std::io::Cursor::new(data), maps, &options).unwrap();
+
+        let failures = lang.decode_failures();
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        let (path, off, error) = &failures[0];
+        assert_eq!((*path, *off), (IdPath::Unit(unit), entry.get_caption_off()));
+        assert!(error.contains(&format!("No character for code {:X}", code)), "{}", error);
+    }
+}
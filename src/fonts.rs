@@ -1,11 +1,21 @@
-use crate::conversion::{little_endian_2_bytes, little_endian_4_bytes};
+use crate::blob::CRC_COVERS_FROM;
+use crate::characters::CharacterMaps;
+use crate::conversion::{crc32, little_endian_2_bytes, little_endian_4_bytes};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::vec::Vec;
 
+///
+/// A font family and the distinct (width, height) glyph sizes of its sections
+///
+pub type FamilySizes = (u8, Vec<(u8, u8)>);
+
 pub struct FontIndex {
     sections: Vec<FontSection>,
+    warnings: Vec<String>, // Problems found loading the fonts, such as padded glyphs
 }
 
 struct FontSection {
@@ -20,7 +30,7 @@ struct FontSection {
 }
 
 impl FontIndex {
-    pub fn from(fp: &mut File) -> io::Result<FontIndex> {
+    pub fn from<R: Read + Seek>(fp: &mut R) -> io::Result<FontIndex> {
         // read font file header..
         let mut file_header = [0; 16];
         fp.read_exact(&mut file_header)?;
@@ -36,6 +46,37 @@ impl FontIndex {
         println!("Font file schema {}, version {}", schema, font_version);
         println!("Number of fonts is {}", num_fonts);
 
+        // A truncated upload would otherwise fail part way through a section
+        let stream_len = fp.seek(SeekFrom::End(0))?;
+        if stream_len != file_len as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Font file is {} bytes but the header says {}", stream_len, file_len),
+            ));
+        }
+        let mut covered = Vec::new();
+        fp.seek(SeekFrom::Start(CRC_COVERS_FROM as u64))?;
+        fp.read_to_end(&mut covered)?;
+        let crc = crc32(&covered);
+        if crc != file_crc {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Font file CRC mismatch, header has {:08X} but the file is {:08X}", file_crc, crc),
+            ));
+        }
+
+        // A corrupt offset table pointer would have garbage read as offsets
+        let table_end = offset_to_offset_table as u64 + (num_fonts as u64) * 4;
+        if (offset_to_offset_table as u64) < 16 || table_end > stream_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Font offset table at {} for {} fonts does not fit in {} bytes",
+                    offset_to_offset_table, num_fonts, stream_len
+                ),
+            ));
+        }
+
         // Read the offset table..
         let mut offset_table = Vec::<u32>::new();
         fp.seek(SeekFrom::Start(offset_to_offset_table as u64))?;
@@ -46,12 +87,27 @@ impl FontIndex {
         }
 
         let mut sections = Vec::new();
+        let mut warnings = Vec::new();
 
         for i in 0..num_fonts {
             fp.seek(SeekFrom::Start(offset_table[i as usize] as u64))?;
-            sections.push(FontSection::from(fp)?);
+            sections.push(FontSection::from(fp, &mut warnings)?);
         }
-        Result::Ok(FontIndex { sections })
+        Result::Ok(FontIndex { sections, warnings })
+    }
+
+    ///
+    /// Read the fonts from the contents of a font file, e.g. from an archive
+    ///
+    pub fn from_bytes(data: &[u8]) -> io::Result<FontIndex> {
+        FontIndex::from(&mut Cursor::new(data))
+    }
+
+    ///
+    /// The problems found loading the fonts that did not stop the load
+    ///
+    pub fn get_warnings(&self) -> &[String] {
+        &self.warnings
     }
 
     pub fn get_size(&self, char_map: u8, font_family: u8) -> Option<(u8, u8)> {
@@ -63,28 +119,230 @@ impl FontIndex {
         return None;
     }
 
+    ///
+    /// Check the sections of each font family have the same glyph height,
+    /// widths may differ (proportional fonts). Returns the families that
+    /// disagree with their distinct (width, height) sizes.
+    ///
+    pub fn check_family_dimensions(&self) -> Result<(), Vec<FamilySizes>> {
+        let mut families = BTreeMap::<u8, Vec<(u8, u8)>>::new();
+        for section in self.sections.iter() {
+            let sizes = families.entry(section.font_family).or_default();
+            let size = (section.glyph_width, section.glyph_height);
+            if !sizes.contains(&size) {
+                sizes.push(size);
+            }
+        }
+
+        let mut mismatched = Vec::new();
+        for (font_family, mut sizes) in families {
+            if sizes.iter().any(|x| x.1 != sizes[0].1) {
+                sizes.sort();
+                mismatched.push((font_family, sizes));
+            }
+        }
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        Err(mismatched)
+    }
+
     pub fn get_glyph(&self, char_map: u8, font_family: u8, codepoint: u16) -> Option<Vec<u8>> {
+        let section = self.find_section(char_map, font_family, codepoint)?;
+        Some(section.glyph(codepoint)?.to_vec())
+    }
+
+    ///
+    /// The glyph for codepoint drawn as lines of '#' for set and ' ' for
+    /// clear pixels, a line per row
+    ///
+    pub fn render_glyph_ascii(&self, char_map: u8, font_family: u8, codepoint: u16) -> Option<String> {
+        let section = self.find_section(char_map, font_family, codepoint)?;
+        let glyph = section.glyph(codepoint)?;
+        let mut text = String::new();
+        for y in 0..section.glyph_height {
+            for x in 0..section.glyph_width {
+                text.push(if section.is_pixel_set(glyph, x, y) { '#' } else { ' ' });
+            }
+            text.push('\n');
+        }
+        Some(text)
+    }
+
+    ///
+    /// The width in pixels of text in the font for char_map and font_family,
+    /// taking each character of text as a codepoint of char_map. None if
+    /// the font has no glyph for one of them.
+    ///
+    pub fn measure_string(&self, char_map: u8, font_family: u8, text: &str) -> Option<u32> {
+        self.measure_codepoints(char_map, font_family, text.chars().map(|ch| u16::try_from(ch as u32).ok()))
+    }
+
+    fn measure_codepoints(&self, char_map: u8, font_family: u8, codepoints: impl IntoIterator<Item = Option<u16>>) -> Option<u32> {
+        let mut width = 0;
+        for codepoint in codepoints {
+            let codepoint = codepoint?;
+            self.get_glyph(char_map, font_family, codepoint)?;
+            width += self.get_glyph_width(char_map, font_family, codepoint)? as u32;
+        }
+        Some(width)
+    }
+
+    pub fn get_glyph_width(&self, char_map: u8, font_family: u8, codepoint: u16) -> Option<u8> {
+        let section = self.find_section(char_map, font_family, codepoint)?;
+        Some(section.glyph_width)
+    }
+
+    ///
+    /// Write the glyphs of char_map and font_family as a BDF font, each
+    /// glyph is encoded as its codepoint in the character map
+    ///
+    pub fn write_bdf(&self, char_map: u8, font_family: u8, path: &str) -> io::Result<()> {
+        let sections: Vec<&FontSection> = self.sections.iter()
+            .filter(|section| (section.char_map == char_map) && (section.font_family == font_family))
+            .collect();
+        if sections.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("No font for character map {} family {}", char_map, font_family),
+            ));
+        }
+        let width = sections.iter().map(|x| x.glyph_width).max().unwrap_or(0);
+        let height = sections.iter().map(|x| x.glyph_height).max().unwrap_or(0);
+
+        let mut chars = String::new();
+        let mut num_chars = 0;
+        for section in sections {
+            for codepoint in section.min_codepoint..=section.max_codepoint {
+                if let Some(bitmap) = section.bdf_bitmap(codepoint) {
+                    chars += &format!("STARTCHAR C{}\nENCODING {}\n", codepoint, codepoint);
+                    chars += &format!("SWIDTH {} 0\nDWIDTH {} 0\n", 1000 * section.glyph_width as u32 / height as u32, section.glyph_width);
+                    chars += &format!("BBX {} {} 0 0\nBITMAP\n{}ENDCHAR\n", section.glyph_width, section.glyph_height, bitmap);
+                    num_chars += 1;
+                }
+            }
+        }
+
+        let mut fp = File::create(path)?;
+        writeln!(fp, "STARTFONT 2.1")?;
+        writeln!(fp, "FONT keypad-map{}-family{}", char_map, font_family)?;
+        writeln!(fp, "SIZE {} 75 75", height)?;
+        writeln!(fp, "FONTBOUNDINGBOX {} {} 0 0", width, height)?;
+        writeln!(fp, "STARTPROPERTIES 2\nFONT_ASCENT {}\nFONT_DESCENT 0\nENDPROPERTIES", height)?;
+        writeln!(fp, "CHARS {}", num_chars)?;
+        write!(fp, "{}", chars)?;
+        writeln!(fp, "ENDFONT")?;
+        Ok(())
+    }
+
+    ///
+    /// Write the glyph for codepoint to path as a PBM image
+    ///
+    pub fn export_glyph_pbm(&self, char_map: u8, font_family: u8, codepoint: u16, path: &str) -> io::Result<()> {
+        let image = self.find_section(char_map, font_family, codepoint)
+            .and_then(|section| section.pbm_image(codepoint))
+            .ok_or_else(|| Error::new(
+                ErrorKind::InvalidInput,
+                format!("No glyph {} for character map {} family {}", codepoint, char_map, font_family),
+            ))?;
+        File::create(path)?.write_all(&image)
+    }
+
+    ///
+    /// Write every glyph of every section to dir as a PBM image, named
+    /// map{char_map}_family{font_family}_cp{codepoint}.pbm. Returns the
+    /// number of images written.
+    ///
+    pub fn export_all_pbm(&self, dir: &str) -> io::Result<usize> {
+        let mut written = 0;
         for section in self.sections.iter() {
-            if (section.char_map == char_map)
+            for codepoint in section.min_codepoint..=section.max_codepoint {
+                if let Some(image) = section.pbm_image(codepoint) {
+                    let name = format!("map{}_family{}_cp{}.pbm", section.char_map, section.font_family, codepoint);
+                    File::create(Path::new(dir).join(name))?.write_all(&image)?;
+                    written += 1;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    fn find_section(&self, char_map: u8, font_family: u8, codepoint: u16) -> Option<&FontSection> {
+        self.sections.iter().find(|section| {
+            (section.char_map == char_map)
                 && (section.font_family == font_family)
                 && (codepoint >= section.min_codepoint)
                 && (codepoint <= section.max_codepoint)
-            {
-                let idx: usize = ((codepoint - section.min_codepoint) as usize)
-                    * (section.bytes_per_glyph as usize);
-                let mut glyph = Vec::<u8>::new();
-                glyph.extend_from_slice(
-                    &section.blob[idx..(idx + (section.bytes_per_glyph) as usize)],
-                );
-                return Some(glyph);
-            }
-        }
-        None
+        })
     }
 }
 
 impl FontSection {
-    pub fn from(fp: &mut File) -> io::Result<FontSection> {
+    ///
+    /// The bytes of the glyph for codepoint, None if the blob of a
+    /// truncated section is too short for it
+    ///
+    fn glyph(&self, codepoint: u16) -> Option<&[u8]> {
+        let idx = ((codepoint - self.min_codepoint) as usize) * (self.bytes_per_glyph as usize);
+        self.blob.get(idx..(idx + self.bytes_per_glyph as usize))
+    }
+
+    ///
+    /// Is the pixel at x, y of glyph set. The glyph is stored in pages of
+    /// 8 rows, each a byte per column with the top row in the low bit.
+    ///
+    fn is_pixel_set(&self, glyph: &[u8], x: u8, y: u8) -> bool {
+        let idx = (y as usize / 8) * (self.glyph_width as usize) + (x as usize);
+        (glyph[idx] >> (y % 8)) & 1 == 1
+    }
+
+    ///
+    /// The rows of the glyph for codepoint, each packed into bytes with
+    /// the leftmost pixel in the high bit and padded to a whole byte
+    ///
+    fn packed_rows(&self, codepoint: u16) -> Option<Vec<Vec<u8>>> {
+        let glyph = self.glyph(codepoint)?;
+        let row_bytes = (self.glyph_width as usize).div_ceil(8);
+        let mut rows = Vec::new();
+        for y in 0..self.glyph_height {
+            let mut row = vec![0u8; row_bytes];
+            for x in 0..self.glyph_width {
+                if self.is_pixel_set(glyph, x, y) {
+                    row[x as usize / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            rows.push(row);
+        }
+        Some(rows)
+    }
+
+    ///
+    /// The BDF BITMAP rows of the glyph for codepoint as hex, one line
+    /// per row with the leftmost pixel in the high bit
+    ///
+    fn bdf_bitmap(&self, codepoint: u16) -> Option<String> {
+        let mut bitmap = String::new();
+        for row in self.packed_rows(codepoint)? {
+            for byte in row {
+                bitmap += &format!("{:02X}", byte);
+            }
+            bitmap += "\n";
+        }
+        Some(bitmap)
+    }
+
+    ///
+    /// The glyph for codepoint as a binary (P4) PBM image
+    ///
+    fn pbm_image(&self, codepoint: u16) -> Option<Vec<u8>> {
+        let mut image = format!("P4\n{} {}\n", self.glyph_width, self.glyph_height).into_bytes();
+        for row in self.packed_rows(codepoint)? {
+            image.extend(row);
+        }
+        Some(image)
+    }
+
+    pub fn from<R: Read + Seek>(fp: &mut R, warnings: &mut Vec<String>) -> io::Result<FontSection> {
         let mut font_header = [0; 12];
         fp.read_exact(&mut font_header)?;
         let char_map = font_header[0];
@@ -98,6 +356,33 @@ impl FontSection {
             "map ={}, id = {}, {} x {}, {} to {}",
             char_map, font_family, glyph_width, glyph_height, min_codepoint, max_codepoint
         );
+
+        // A zero height would divide by zero drawing or exporting the glyphs
+        if glyph_height == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Font {} has zero height {} x {} glyphs", font_family, glyph_width, glyph_height),
+            ));
+        }
+
+        // A glyph is pages of 8 rows, each a byte per column
+        let glyph_size = (glyph_width as usize) * (glyph_height as usize).div_ceil(8);
+        if (bytes_per_glyph as usize) < glyph_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Font {} bytes per glyph {} too few for {} x {} glyphs",
+                    font_family, bytes_per_glyph, glyph_width, glyph_height
+                ),
+            ));
+        }
+        if (bytes_per_glyph as usize) > glyph_size {
+            warnings.push(format!(
+                "Font {} bytes per glyph {} is padded, {} x {} glyphs need {}",
+                font_family, bytes_per_glyph, glyph_width, glyph_height, glyph_size
+            ));
+        }
+
         let mut blob_size: usize =
             (bytes_per_glyph as usize) * ((max_codepoint - min_codepoint + 1) as usize);
         let mut buf = [0; 512];
@@ -132,6 +417,24 @@ impl FontSection {
     }
 }
 
+///
+/// The width in pixels of text rendered in the font for char_map and
+/// font_family, and whether that is wider than max_width. None if a
+/// character of text has no glyph in the font.
+///
+pub fn string_pixel_width(
+    fonts: &FontIndex,
+    maps: &CharacterMaps,
+    char_map: u8,
+    font_family: u8,
+    text: &str,
+    max_width: u32,
+) -> Option<(u32, bool)> {
+    let codepoints = text.chars().map(|ch| maps.find_code(char_map as u16, &ch.to_string()));
+    let width = fonts.measure_codepoints(char_map, font_family, codepoints)?;
+    Some((width, width > max_width))
+}
+
 pub fn read_font_file(filepath: &str) -> FontIndex {
     let mut fp = match File::open(filepath) {
         Ok(fp) => fp,
@@ -142,10 +445,215 @@ pub fn read_font_file(filepath: &str) -> FontIndex {
 
     let index = match FontIndex::from(&mut fp) {
         Ok(index) => index,
-        Err(_) => {
-            panic!("Failed to process {}", String::from(filepath));
+        Err(x) => {
+            panic!("Failed to process {}: {}", String::from(filepath), x);
         }
     };
     //    fp.close();
     return index;
 }
+
+///
+/// Read the font file if there is one. A missing font file is only a
+/// warning, as the fonts are not needed to decode the language files,
+/// but one that fails to open or parse (a CRC mismatch say) is an error
+///
+pub fn read_optional_font_file(filepath: &str) -> Result<Option<FontIndex>, String> {
+    if !Path::new(filepath).exists() {
+        println!("Warning: no font file {}, continuing without fonts", filepath);
+        return Ok(None);
+    }
+    let mut fp = match File::open(filepath) {
+        Ok(fp) => fp,
+        Err(x) => return Err(format!("Failed to open {}: {}", filepath, x)),
+    };
+    match FontIndex::from(&mut fp) {
+        Ok(index) => Ok(Some(index)),
+        Err(x) => Err(format!("Failed to process {}: {}", filepath, x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+
+    struct TestSection {
+        char_map: u8,
+        font_family: u8,
+        size: (u8, u8),
+        bytes_per_glyph: u8,
+        codepoints: (u16, u16),
+        glyphs: Vec<u8>,
+    }
+
+    impl TestSection {
+        ///
+        /// A 5 x 8 font of codepoints min to max, with glyph i all i
+        ///
+        fn new(char_map: u8, font_family: u8, min: u16, max: u16) -> TestSection {
+            let glyphs = (min..=max).flat_map(|codepoint| [codepoint as u8; 5]).collect();
+            TestSection { char_map, font_family, size: (5, 8), bytes_per_glyph: 5, codepoints: (min, max), glyphs }
+        }
+    }
+
+    ///
+    /// A font file of the sections, with a correct length and CRC
+    ///
+    fn font_file(sections: &[TestSection]) -> Vec<u8> {
+        let mut data = vec![0; 16];
+        data[8..10].copy_from_slice(&1u16.to_le_bytes());
+        data[10..12].copy_from_slice(&1u16.to_le_bytes());
+        data[12..14].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        data[14..16].copy_from_slice(&16u16.to_le_bytes());
+        data.resize(16 + 4 * sections.len(), 0);
+        for (i, section) in sections.iter().enumerate() {
+            let offset = data.len() as u32;
+            data[16 + 4 * i..20 + 4 * i].copy_from_slice(&offset.to_le_bytes());
+            data.extend([section.char_map, 0, 0, 0, section.font_family, section.size.0, section.size.1, section.bytes_per_glyph]);
+            data.extend(section.codepoints.0.to_le_bytes());
+            data.extend(section.codepoints.1.to_le_bytes());
+            data.extend(&section.glyphs);
+        }
+        let file_len = data.len() as u32;
+        data[0..4].copy_from_slice(&file_len.to_le_bytes());
+        set_crc(&mut data);
+        data
+    }
+
+    fn set_crc(data: &mut [u8]) {
+        let crc = crc32(&data[CRC_COVERS_FROM..]);
+        data[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn bogus_offset_table_pointer() {
+        for pointer in [4u16, 1000] {
+            let mut data = font_file(&[TestSection::new(1, 0, 65, 67)]);
+            data[14..16].copy_from_slice(&pointer.to_le_bytes());
+            set_crc(&mut data);
+            let error = FontIndex::from_bytes(&data).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert!(error.to_string().starts_with(&format!("Font offset table at {} for 1 fonts", pointer)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn family_with_two_heights_is_flagged() {
+        let mut tall = TestSection::new(2, 0, 65, 66);
+        tall.size = (5, 16);
+        tall.bytes_per_glyph = 10;
+        tall.glyphs = vec![0; 20];
+        let mut wide = TestSection::new(2, 1, 65, 66);
+        wide.size = (6, 8);
+        wide.bytes_per_glyph = 6;
+        wide.glyphs = vec![0; 12];
+        let sections = [TestSection::new(1, 0, 65, 66), tall, TestSection::new(1, 1, 65, 66), wide];
+        let fonts = FontIndex::from_bytes(&font_file(&sections)).unwrap();
+        assert_eq!(fonts.check_family_dimensions(), Err(vec![(0, vec![(5, 8), (5, 16)])]));
+
+        let fonts = FontIndex::from_bytes(&font_file(&[TestSection::new(1, 0, 65, 66)])).unwrap();
+        assert_eq!(fonts.check_family_dimensions(), Ok(()));
+    }
+
+    #[test]
+    fn missing_font_file_is_only_a_warning() {
+        let path = "no_such_fonts.bft";
+        assert!(!Path::new(path).exists());
+        assert!(read_optional_font_file(path).unwrap().is_none());
+
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = crate::language::read_language_file("languageV3.bin", maps).unwrap();
+        assert!(lang.get_units().iter().next().is_some());
+
+        assert!(read_optional_font_file("fonts.bft").unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupt_font_file_is_an_error() {
+        let mut data = std::fs::read("fonts.bft").unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        let path = std::env::temp_dir().join(format!("keypad_sim_{}.bft", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let path = path.to_str().unwrap();
+        let result = read_optional_font_file(path);
+        std::fs::remove_file(path).unwrap();
+
+        let error = result.err().unwrap();
+        assert!(error.starts_with(&format!("Failed to process {}: Font file CRC mismatch", path)), "{}", error);
+    }
+
+    #[test]
+    fn bdf_has_a_char_per_codepoint() {
+        let fonts = FontIndex::from_bytes(&font_file(&[TestSection::new(1, 0, 65, 67)])).unwrap();
+        let path = std::env::temp_dir().join(format!("keypad_sim_{}.bdf", std::process::id()));
+        let path = path.to_str().unwrap();
+        fonts.write_bdf(1, 0, path).unwrap();
+        let bdf = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(bdf.lines().any(|x| x == "CHARS 3"), "{}", bdf);
+        let encodings: Vec<&str> = bdf.lines().filter(|x| x.starts_with("ENCODING ")).collect();
+        assert_eq!(encodings, ["ENCODING 65", "ENCODING 66", "ENCODING 67"]);
+        assert_eq!(fonts.write_bdf(1, 1, path).err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tiny_font_from_memory() {
+        let fonts = FontIndex::from_bytes(&font_file(&[TestSection::new(1, 0, 65, 66)])).unwrap();
+        assert_eq!(fonts.get_size(1, 0), Some((5, 8)));
+        assert_eq!(fonts.get_glyph(1, 0, 66), Some(vec![66; 5]));
+        assert_eq!(fonts.get_glyph(1, 0, 67), None);
+
+        let from_file = read_font_file("fonts.bft");
+        let from_memory = FontIndex::from_bytes(&std::fs::read("fonts.bft").unwrap()).unwrap();
+        assert_eq!(from_memory.get_glyph(1, 1, 65), from_file.get_glyph(1, 1, 65));
+        assert!(from_memory.get_glyph(1, 1, 65).is_some());
+    }
+
+    #[test]
+    fn too_few_bytes_per_glyph_is_an_error() {
+        let mut section = TestSection::new(1, 0, 65, 66);
+        section.bytes_per_glyph = 4;
+        section.glyphs.truncate(8);
+        let error = FontIndex::from_bytes(&font_file(&[section])).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Font 0 bytes per glyph 4 too few for 5 x 8 glyphs"), "{}", error);
+
+        let mut section = TestSection::new(1, 0, 65, 66);
+        section.bytes_per_glyph = 6;
+        section.glyphs = vec![0; 12];
+        let fonts = FontIndex::from_bytes(&font_file(&[section])).unwrap();
+        assert_eq!(fonts.get_warnings(), ["Font 0 bytes per glyph 6 is padded, 5 x 8 glyphs need 5"]);
+    }
+
+    #[test]
+    fn zero_height_section_is_an_error() {
+        let mut section = TestSection::new(1, 0, 65, 66);
+        section.size = (5, 0);
+        let error = FontIndex::from_bytes(&font_file(&[section])).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Font 0 has zero height 5 x 0 glyphs"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_section_has_no_last_glyph() {
+        let mut section = TestSection::new(1, 0, 65, 67);
+        section.glyphs.truncate(12);
+        let fonts = FontIndex::from_bytes(&font_file(&[section])).unwrap();
+        assert_eq!(fonts.get_glyph(1, 0, 65), Some(vec![65; 5]));
+        assert_eq!(fonts.get_glyph(1, 0, 66), Some(vec![66; 5]));
+        assert_eq!(fonts.get_glyph(1, 0, 67), None);
+        assert_eq!(fonts.render_glyph_ascii(1, 0, 67), None);
+    }
+
+    #[test]
+    fn string_just_over_and_under_the_width() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let fonts = FontIndex::from_bytes(&font_file(&[TestSection::new(1, 0, 65, 67)])).unwrap();
+        assert_eq!(string_pixel_width(&fonts, &maps, 1, 0, "ABC", 15), Some((15, false)));
+        assert_eq!(string_pixel_width(&fonts, &maps, 1, 0, "ABC", 14), Some((15, true)));
+        assert_eq!(string_pixel_width(&fonts, &maps, 1, 0, "ABD", 15), None);
+    }
+}
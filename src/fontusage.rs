@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::language::Language;
+
+///
+/// The codepoints of a character map drawn with a font family
+///
+pub struct FontUsage {
+    pub font_family: u8,
+    pub char_map: u16,
+    pub codepoints: BTreeSet<u32>,
+}
+
+impl Language {
+    ///
+    /// Decode every string and list the (font family, character map)
+    /// pairs used, with the codepoints touched. These are the font
+    /// sections the language needs, UTF-8 languages are not mapped so
+    /// report none.
+    ///
+    pub fn font_usage(&self) -> Vec<FontUsage> {
+        // Decoding records the characters in the blob's stats
+        self.flatten();
+
+        let mut maps = BTreeMap::<u16, BTreeSet<u32>>::new();
+        for (map_id, code) in self.get_char_usage().keys() {
+            maps.entry(*map_id).or_default().insert(*code);
+        }
+
+        let mut usage = Vec::new();
+        for (char_map, codepoints) in maps {
+            usage.push(FontUsage { font_family: self.get_font_family(), char_map, codepoints });
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+
+    #[test]
+    fn fixture_uses_latin_and_a_few_chinese_glyphs() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps.clone()).unwrap();
+        let usage = lang.font_usage();
+        let used: Vec<(u8, u16, usize)> = usage.iter().map(|x| (x.font_family, x.char_map, x.codepoints.len())).collect();
+        assert_eq!(used, [(1, 1, 84), (1, 2, 3)]);
+
+        let mut expected = std::collections::BTreeSet::new();
+        for entry in lang.flatten() {
+            for text in [entry.caption.ok(), entry.tooltip.and_then(|x| x.ok())].into_iter().flatten() {
+                for ch in text.chars() {
+                    if let Some(code) = maps.find_code(1, &ch.to_string()) {
+                        expected.insert(code as u32);
+                    }
+                }
+            }
+        }
+        assert!(expected.is_subset(&usage[0].codepoints));
+        assert!(usage[0].codepoints.contains(&(b'A' as u32)));
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::flatten::FlatEntry;
+use crate::language::Language;
+use crate::tree::TreeText;
+
+///
+/// text as a quoted JSON string
+///
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            ch if (ch as u32) < 0x20 => quoted += &format!("\\u{:04x}", ch as u32),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+///
+/// A decoded string as JSON, null if it failed to decode
+///
+fn json_text(text: Option<&Result<String, String>>) -> String {
+    match text {
+        Some(Ok(x)) => json_string(x),
+        _ => "null".to_string(),
+    }
+}
+
+///
+/// The "caption" and "tooltip" members of a node, with an "error" for
+/// either that failed to decode
+///
+fn json_tree_text(text: &TreeText) -> String {
+    let mut json = format!(
+        "\"caption\":{},\"tooltip\":{}",
+        json_text(Some(&text.caption)),
+        json_text(text.tooltip.as_ref())
+    );
+    if let (Err(error), _) | (_, Some(Err(error))) = (&text.caption, &text.tooltip) {
+        json += &format!(",\"error\":{}", json_string(error));
+    }
+    json
+}
+
+impl FlatEntry {
+    ///
+    /// The entry as a single line JSON object, with an "error" for a
+    /// caption or tooltip that failed to decode
+    ///
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"region\":{},\"id_path\":{},\"caption\":{},\"tooltip\":{},\"caption_off\":{},\"tooltip_off\":{}",
+            json_string(&format!("{:?}", self.path.region())),
+            json_string(&self.path.to_string()),
+            json_text(Some(&self.caption)),
+            json_text(self.tooltip.as_ref()),
+            self.caption_off,
+            self.tooltip_off
+        );
+        if let (Err(error), _) | (_, Some(Err(error))) = (&self.caption, &self.tooltip) {
+            json += &format!(",\"error\":{}", json_string(error));
+        }
+        json.push('}');
+        json
+    }
+}
+
+impl Language {
+    ///
+    /// Write every decoded entry as newline delimited JSON, an object per line
+    ///
+    pub fn write_ndjson(&self, path: &str) -> io::Result<()> {
+        let mut fp = BufWriter::new(File::create(path)?);
+        for entry in self.flatten() {
+            writeln!(fp, "{}", entry.to_json())?;
+        }
+        fp.flush()
+    }
+
+    ///
+    /// Write the Language as a JSON document, the products nest their
+    /// modes, menus, parameters and mnemonics and the enumerations, keypad
+    /// strings and units are arrays alongside them
+    ///
+    pub fn write_json_file(&self, filepath: &str) -> io::Result<()> {
+        let tree = self.to_tree();
+        let mut fp = BufWriter::new(File::create(filepath)?);
+
+        writeln!(fp, "{{\"products\":[")?;
+        for (i, product) in tree.products.iter().enumerate() {
+            let mut modes = Vec::new();
+            for (mode, menus) in &product.modes {
+                let mut menus_json = Vec::new();
+                for (menu, menu_tree) in menus {
+                    let mut params = Vec::new();
+                    for (param, param_tree) in &menu_tree.params {
+                        let mnemonics: Vec<String> = param_tree.mnemonics.iter()
+                            .map(|(value, text)| format!("{{\"value\":{},{}}}", value, json_tree_text(text)))
+                            .collect();
+                        params.push(format!(
+                            "{{\"id\":{},{},\"mnemonics\":[{}]}}",
+                            param, json_tree_text(&param_tree.text), mnemonics.join(",")
+                        ));
+                    }
+                    menus_json.push(format!(
+                        "{{\"id\":{},{},\"params\":[{}]}}",
+                        menu, json_tree_text(&menu_tree.text), params.join(",")
+                    ));
+                }
+                modes.push(format!("{{\"id\":{},\"menus\":[{}]}}", mode, menus_json.join(",")));
+            }
+            writeln!(
+                fp,
+                "{{\"product_id\":{},\"derivative_id_low\":{},\"derivative_id_high\":{},\"flags\":{},\"modes\":[{}]}}{}",
+                product.key.product_id,
+                product.key.derivative_id_low,
+                product.key.derivative_id_high,
+                product.flags,
+                modes.join(","),
+                if i + 1 < tree.products.len() { "," } else { "" }
+            )?;
+        }
+
+        for (name, table) in [("enumerations", &tree.enumerations), ("keypad_strs", &tree.keypad_strs)] {
+            let entries: Vec<String> = table.iter()
+                .map(|(id, caption)| {
+                    format!("{{\"id\":{},{}}}", id, json_tree_text(&TreeText { caption: caption.clone(), tooltip: None }))
+                })
+                .collect();
+            writeln!(fp, "],\"{}\":[{}", name, entries.join(",\n"))?;
+        }
+
+        let units: Vec<String> = tree.units.iter()
+            .map(|(id, text)| format!("{{\"id\":{},{}}}", id, json_tree_text(text)))
+            .collect();
+        writeln!(fp, "],\"units\":[{}", units.join(",\n"))?;
+        writeln!(fp, "]}}")?;
+        fp.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+    use std::fs;
+
+    ///
+    /// The length of the JSON value at the start of text, None if it is
+    /// not valid. Just the objects, strings, numbers and null written here.
+    ///
+    fn json_value_len(text: &[u8]) -> Option<usize> {
+        match text.first()? {
+            b'{' => {
+                let mut i = 1;
+                if text.get(i) == Some(&b'}') {
+                    return Some(2);
+                }
+                loop {
+                    if text.get(i) != Some(&b'"') {
+                        return None;
+                    }
+                    i += json_value_len(&text[i..])?;
+                    if text.get(i) != Some(&b':') {
+                        return None;
+                    }
+                    i += 1;
+                    i += json_value_len(&text[i..])?;
+                    match text.get(i)? {
+                        b',' => i += 1,
+                        b'}' => return Some(i + 1),
+                        _ => return None,
+                    }
+                }
+            }
+            b'"' => {
+                let mut i = 1;
+                loop {
+                    match text.get(i)? {
+                        b'"' => return Some(i + 1),
+                        b'\\' => i += 2,
+                        x if *x < 0x20 => return None,
+                        _ => i += 1,
+                    }
+                }
+            }
+            b'n' => text.starts_with(b"null").then_some(4),
+            b'0'..=b'9' | b'-' => Some(text.iter().position(|x| !(x.is_ascii_digit() || *x == b'-')).unwrap_or(text.len())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn ndjson_is_an_object_per_line() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let path = std::env::temp_dir().join(format!("keypad_sim_{}.ndjson", std::process::id()));
+        let path = path.to_str().unwrap();
+        lang.write_ndjson(path).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert!(text.ends_with('\n'));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), lang.flatten().len());
+        for line in lines {
+            assert!(line.starts_with('{'), "{}", line);
+            assert_eq!(json_value_len(line.as_bytes()), Some(line.len()), "{}", line);
+        }
+    }
+}
@@ -1,7 +1,15 @@
 use std::collections::HashMap;
+use std::io;
+use std::ops::RangeInclusive;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
 
+///
+/// Keypad string ids index the keypad's fixed firmware string table,
+/// by default ids 0 to 63 are accepted
+///
+pub const DEFAULT_KEYPAD_STR_IDS: RangeInclusive<u16> = 0..=63;
+
 pub struct KeypadStrIndex 
 {
     keypad_strs: HashMap<u16, KeypadStrIndexEntry>,
@@ -9,6 +17,7 @@ pub struct KeypadStrIndex
 
 pub struct KeypadStrIndexEntry {
     caption_off: u32,
+    str_len: u16,
     blob: RawBlob,
 }
 
@@ -17,44 +26,56 @@ pub struct KeypadStrIterator {
 }
 
 impl KeypadStrIndex {
-    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8) -> KeypadStrIndex {
+    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8, valid_ids: RangeInclusive<u16>) -> io::Result<KeypadStrIndex> {
 
-        let num_entries = fp.read_le_2bytes(BlobRegions::KeypadStrs);
-        let max_str_len = fp.read_le_2bytes(BlobRegions::KeypadStrs);
-        let font_family = fp.read_byte(BlobRegions::KeypadStrs);
-        let idx_entry_len = fp.read_byte(BlobRegions::KeypadStrs);
+        let num_entries = fp.read_le_2bytes(BlobRegions::KeypadStrs)?;
+        fp.check_entries(BlobRegions::KeypadStrs, num_entries as u32)?;
+        let max_str_len = fp.read_le_2bytes(BlobRegions::KeypadStrs)?;
+        let font_family = fp.read_byte(BlobRegions::KeypadStrs)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::KeypadStrs)?;
 
         if root_font_family != font_family {
             panic!("Mis-match font_family");
         }
         let mut keypad_strs = HashMap::new();
 
-        Self::validate_schema(schema, idx_entry_len, max_str_len);
+        let str_len = fp.max_str_len(BlobRegions::KeypadStrs, 32);
+        Self::validate_schema(schema, idx_entry_len, max_str_len, str_len);
+        fp.add_entry_layout(BlobRegions::KeypadStrs, idx_entry_len);
 
         for _i in 0..num_entries {
             let (string_id, entry) = match schema {
-                2 => KeypadStrIndexEntry::load_v2(fp),
+                2 => KeypadStrIndexEntry::load_v2(fp, str_len)?,
+                3 => KeypadStrIndexEntry::load_v3(fp, str_len)?,
                 _ => panic!("Invalid schema"),
             };
+            if !valid_ids.contains(&string_id) {
+                panic!("Keypad string id {} outside {} to {}", string_id, valid_ids.start(), valid_ids.end());
+            }
             let old = keypad_strs.insert(string_id, entry);
             if old != None {
                 panic!("Two entries with same keypad strings!");
             }
         }
-        KeypadStrIndex { keypad_strs }
+        Ok(KeypadStrIndex { keypad_strs })
     }
 
-    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16) {
+    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16, req_str_len: u16) {
         match schema {
             2 => {
                 if idx_entry_len != 6 {
-                    panic!("V2 KeypadStrIndexEntry wrong size 4 != {}", idx_entry_len)
+                    panic!("V2 KeypadStrIndexEntry wrong size 6 != {}", idx_entry_len)
+                }
+            }
+            3 => {
+                if idx_entry_len != 5 {
+                    panic!("V3 KeypadStrIndexEntry wrong size 5 != {}", idx_entry_len)
                 }
             }
             _ => panic!("Invalid format"),
         };
-        if max_str_len != 32 {
-            panic!("Keypad string len is incorrect");
+        if max_str_len != req_str_len {
+            panic!("Keypad string len is incorrect {} != {}", req_str_len, max_str_len);
         }
     }
 
@@ -62,6 +83,15 @@ impl KeypadStrIndex {
         let keypad_strs = HashMap::<u16, KeypadStrIndexEntry>::new();
         KeypadStrIndex { keypad_strs }
     }
+
+    ///
+    /// Iterate over the keypad strings in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &KeypadStrIndexEntry)> {
+        let mut keys: Vec<u16> = self.keypad_strs.keys().copied().collect();
+        keys.sort();
+        keys.into_iter().map(move |key| (key, &self.keypad_strs[&key]))
+    }
 }
 
 impl IntoIterator for &KeypadStrIndex {
@@ -84,25 +114,55 @@ impl IntoIterator for &KeypadStrIndex {
 }
 
 impl KeypadStrIndexEntry {
-    fn load_v2(fp: &mut FileBlob) -> (u16, KeypadStrIndexEntry) {
-        let string_id = fp.read_le_2bytes(BlobRegions::KeypadStrs);
-        let offset = fp.read_le_4bytes(BlobRegions::KeypadStrs);
+    fn load_v2(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, KeypadStrIndexEntry)> {
+        let string_id = fp.read_le_2bytes(BlobRegions::KeypadStrs)?;
+        let offset = fp.read_le_4bytes(BlobRegions::KeypadStrs)?;
+        if offset == 0 {
+            panic! {"Empty slot"};
+        };
+        let entry = KeypadStrIndexEntry {
+            caption_off: offset,
+            str_len,
+            blob: fp.freeze(BlobRegions::KeypadStrs),
+        };
+        Ok((string_id, entry))
+    }
+
+    fn load_v3(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, KeypadStrIndexEntry)> {
+        let string_id = fp.read_le_2bytes(BlobRegions::KeypadStrs)?;
+        let offset = fp.read_le_3bytes(BlobRegions::KeypadStrs)?;
         if offset == 0 {
             panic! {"Empty slot"};
         };
         let entry = KeypadStrIndexEntry {
             caption_off: offset,
-            blob: fp.freeze(),
+            str_len,
+            blob: fp.freeze(BlobRegions::KeypadStrs),
         };
-        (string_id, entry)
+        Ok((string_id, entry))
+    }
+
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
+    pub fn get_caption_off(&self) -> u32 {
+        self.caption_off
     }
 
     pub fn to_string(&self) -> Result<String, String> {
-        match self.blob.get_string(self.caption_off, 32) {
+        match self.blob.get_string(self.caption_off, self.str_len) {
             Ok(x) => Ok(x),
             Err(x) => Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
         }
     }
+
+    ///
+    /// The caption and, as there is none, no tooltip, as the other entries
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.to_string(), None)
+    }
 }
 
 impl PartialEq for KeypadStrIndexEntry {
@@ -115,6 +175,7 @@ impl Clone for KeypadStrIndexEntry {
     fn clone(&self) -> KeypadStrIndexEntry {
         KeypadStrIndexEntry {
             caption_off: self.caption_off,
+            str_len: self.str_len,
             blob: self.blob.clone(),
         }
     }
@@ -127,3 +188,90 @@ impl Iterator for KeypadStrIterator {
         self.items.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    ///
+    /// A keypad string index of one string, string_id, followed by the string
+    ///
+    fn load_id(schema: u16, max_str_len: u16, string_id: u16, options: ParseOptions) -> io::Result<KeypadStrIndex> {
+        let mut data = vec![1, 0];
+        data.extend(max_str_len.to_le_bytes());
+        data.push(0);
+        let str_off: u32 = if schema == 2 { 6 + 6 } else { 6 + 5 };
+        match schema {
+            2 => {
+                data.push(6);
+                data.extend(string_id.to_le_bytes());
+                data.extend(str_off.to_le_bytes());
+            }
+            _ => {
+                data.push(5);
+                data.extend(string_id.to_le_bytes());
+                data.extend(&str_off.to_le_bytes()[..3]);
+            }
+        }
+        data.extend(b"Keypad\0");
+
+        let mut fp = blob_from_bytes(&data, options);
+        KeypadStrIndex::from(&mut fp, schema, 0, DEFAULT_KEYPAD_STR_IDS)
+    }
+
+    fn load(schema: u16, max_str_len: u16, options: ParseOptions) -> io::Result<KeypadStrIndex> {
+        load_id(schema, max_str_len, 5, options)
+    }
+
+    #[test]
+    fn v2_keypad_strings_are_32_long() {
+        let index = load(2, 32, ParseOptions::default()).unwrap();
+        let (id, entry) = index.iter().next().unwrap();
+        assert_eq!(id, 5);
+        assert_eq!(entry.get_str_len(), 32);
+        assert_eq!(entry.get_caption_off(), 12);
+    }
+
+    #[test]
+    fn v3_keypad_strings_are_32_long() {
+        let index = load(3, 32, ParseOptions::default()).unwrap();
+        let (id, entry) = index.iter().next().unwrap();
+        assert_eq!(id, 5);
+        assert_eq!(entry.get_str_len(), 32);
+        assert_eq!(entry.get_caption_off(), 11);
+    }
+
+    #[test]
+    #[should_panic(expected = "Keypad string len is incorrect 32 != 256")]
+    fn wrong_keypad_string_len_reports_both() {
+        let _ = load(3, 256, ParseOptions::default());
+    }
+
+    #[test]
+    fn keypad_string_len_can_be_overridden() {
+        let mut options = ParseOptions::default();
+        options.max_str_lens.insert(BlobRegions::KeypadStrs, 256);
+        let index = load(3, 256, options).unwrap();
+        assert_eq!(index.iter().next().unwrap().1.get_str_len(), 256);
+    }
+
+    #[test]
+    fn last_keypad_string_id_is_accepted() {
+        let index = load_id(2, 32, 63, ParseOptions::default()).unwrap();
+        assert_eq!(index.iter().next().unwrap().0, 63);
+    }
+
+    #[test]
+    #[should_panic(expected = "Keypad string id 64 outside 0 to 63")]
+    fn out_of_range_keypad_string_id() {
+        let _ = load_id(2, 32, 64, ParseOptions::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid format")]
+    fn v4_has_no_keypad_strings() {
+        let _ = load(4, 32, ParseOptions::default());
+    }
+}
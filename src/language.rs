@@ -1,19 +1,27 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::io::Read;
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
 
 use crate::conversion::{
     little_endian_2_bytes,
     little_endian_4_bytes, little_endian_4_version,
 };
 
-use crate::blob::{FileBlob, BlobRegions};
+use crate::blob::{FileBlob, RawBlob, BlobRegions, BlobStats};
 use crate::characters::CharacterMaps;
-use crate::keypadstrs::KeypadStrIndex;
+use crate::diagnostics::{panic_message, Diagnostic, Severity};
+use crate::flatten::ProductKey;
+use crate::fonts::string_pixel_width;
+use crate::keypadstrs::{KeypadStrIndex, DEFAULT_KEYPAD_STR_IDS};
 //use crate::mnemonics::MnemonicIndex;
-use crate::products::ProductIndex;
+use crate::parameters::ParameterIndexEntry;
+use crate::products::{ProductIndex, ProductIndexEntry};
 use crate::units::UnitsIndex;
 use crate::enumerations::EnumerationsIndex;
+use crate::options::{DumpOptions, ParseOptions};
 
 pub struct Language {
     //    lang_name : [u8; 16],
@@ -21,11 +29,325 @@ pub struct Language {
     enumeration_index: EnumerationsIndex,
     keypad_str_index: KeypadStrIndex,
     units_index: UnitsIndex,
+    diagnostics: Vec<Diagnostic>,
+    font_family: u8,
+    is_utf8: bool, // Schema 4 strings are UTF-8 unless there is a 3 or 4 byte map, before that they use the character maps
+    blob: RawBlob,
 }
 
 impl Language 
 {
-    pub fn create_from_file(fp: &mut File, maps: CharacterMaps) -> io::Result<Language> 
+    pub fn create_from_file<R: Read + Seek>(fp: &mut R, maps: CharacterMaps) -> io::Result<Language> 
+    {
+        Self::create_from_file_with_options(fp, maps, &ParseOptions::default())
+    }
+
+    pub fn create_from_file_with_options<R: Read + Seek>(fp: &mut R, maps: CharacterMaps, options: &ParseOptions) -> io::Result<Language> 
+    {
+        let (mut fp, schema, font_family, offsets) = Self::read_header(fp, maps, options)?;
+        if options.stats_on_drop {
+            fp.set_stats_on_drop(Some(Box::new(|fp: &FileBlob| fp.display_stats())));
+        }
+        if options.timing {
+            fp.enable_timing();
+        }
+
+        fp.set_pos(offsets[0]);
+        let product_index = fp.time(BlobRegions::Products, |fp| ProductIndex::create_from_file(fp, schema, font_family))?;
+
+        let (enumeration_index, keypad_str_index, units_index) = Self::read_tables(&mut fp, schema, font_family, &offsets)?;
+
+        let lang = Language {
+            product_index,
+            enumeration_index,
+            keypad_str_index,
+            units_index,
+            diagnostics: fp.get_diagnostics(),
+            font_family,
+            is_utf8: fp.get_maps().is_utf8(),
+            blob: fp.freeze(BlobRegions::Text),
+        };
+
+        return Result::Ok(lang);
+    }
+
+    ///
+    /// Print the decoded Language as a tree of products, modes, menus,
+    /// parameters and mnemonics, followed by the other indexes
+    ///
+    pub fn dump(&self, options: &DumpOptions) {
+        self.write_dump(&mut io::stdout().lock(), options).expect("Failed to write to stdout");
+    }
+
+    ///
+    /// Write the tree printed by dump to out
+    ///
+    pub fn write_dump<W: Write>(&self, out: &mut W, options: &DumpOptions) -> io::Result<()> {
+        writeln!(out, "Products ....")?;
+
+        for details in self.product_index.iter() {
+            self.write_product(out, details, options)?;
+        }
+
+        writeln!(out, "Legacy Enumerations ....")?;
+
+        for (enumeration, details) in self.enumeration_index.iter() {
+            let at = Self::offsets_note(options, details.get_caption_off(), 0);
+            match details.to_string() {
+                Ok(x) => writeln!(out, "{}{} => {}", enumeration, at, x)?,
+                Err(x) => return Err(Self::decode_error(format!("{}{} => {}", enumeration, at, x))),
+            };
+        }
+
+        writeln!(out, "Keypad strs ....")?;
+
+        for (num, details) in self.keypad_str_index.iter() {
+            let at = Self::offsets_note(options, details.get_caption_off(), 0);
+            match details.to_string() {
+                Ok(x) => writeln!(out, "{}{} => {}", num, at, x)?,
+                Err(x) => return Err(Self::decode_error(format!("{}{} => {}", num, at, x))),
+            };
+        }
+
+        writeln!(out, "Units ....")?;
+
+        for (unit, details) in self.units_index.iter() {
+            let at = Self::offsets_note(options, details.get_caption_off(), details.get_tooltip_off());
+            match details.to_string() {
+                Ok(x) => writeln!(out, "{}{} => {}", unit, at, x)?,
+                Err(x) => return Err(Self::decode_error(format!("{}{} => {}", unit, at, x))),
+            };
+        }
+        Ok(())
+    }
+
+    ///
+    /// Write a product entry and its modes, menus, parameters and mnemonics
+    ///
+    fn write_product<W: Write>(&self, out: &mut W, details: &ProductIndexEntry, options: &DumpOptions) -> io::Result<()> {
+        match details.to_string() {
+            Ok(x) => writeln!(out, "{}", x)?,
+            Err(x) => return Err(Self::decode_error(x)),
+        };
+        let modes = details.get_modes();
+        for (mode, details) in modes.iter() {
+            match details.to_string(mode) {
+                Ok(x) if details.is_any() && modes.get_num_modes() > 1 => writeln!(out, "- {} (common to all modes)", x)?,
+                Ok(x) => writeln!(out, "- {}", x)?,
+                Err(x) => return Err(Self::decode_error(format!("- {}", x))),
+            };
+            for (menu, details) in details.get_menus().iter() {
+                let at = Self::offsets_note(options, details.get_caption_off(), details.get_tooltip_off());
+                let wide = self.width_note(options, || details.caption());
+                match details.to_string() {
+                    Ok(x) => writeln!(out, "- - M.{}{} => {}{}", menu, at, x, wide)?,
+                    Err(x) => return Err(Self::decode_error(format!("- - M.{}{} => {}", menu, at, x))),
+                };
+                for (param, details) in details.get_params().iter() {
+                    let at = Self::offsets_note(options, details.get_caption_off(), details.get_tooltip_off());
+                    let wide = self.width_note(options, || details.caption());
+                    match details.to_string() {
+                        Ok(x) => writeln!(out, "- - - P.{}{} => {}{}", param, at, x, wide)?,
+                        Err(x) => return Err(Self::decode_error(format!("- - - P.{}{} => {}", param, at, x))),
+                    };
+                    for (value, details) in details.get_mnemonics().iter() {
+                        let at = Self::offsets_note(options, details.get_caption_off(), details.get_tooltip_off());
+                        let wide = self.width_note(options, || details.caption());
+                        match details.to_string() {
+                            Ok(x) => writeln!(out, "- - - - {}{} => {}{}", value, at, x, wide)?,
+                            Err(x) => return Err(Self::decode_error(format!("- - - - {}{} => {}", value, at, x))),
+                        };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// A string of the dump that failed to decode, as the error of the write
+    ///
+    fn decode_error(message: String) -> Error {
+        Error::new(ErrorKind::InvalidData, message)
+    }
+
+    ///
+    /// The " @0x.." annotation of a dump line, empty unless offsets are included
+    ///
+    fn offsets_note(options: &DumpOptions, caption_off: u32, tooltip_off: u32) -> String {
+        if !options.include_offsets {
+            String::new()
+        } else if tooltip_off != 0 {
+            format!(" @0x{:X}/0x{:X}", caption_off, tooltip_off)
+        } else {
+            format!(" @0x{:X}", caption_off)
+        }
+    }
+
+    ///
+    /// The " [too wide..]" annotation of a dump line, empty unless caption
+    /// widths are checked and a line of the caption (they are split at ↵)
+    /// is too wide. A caption with a character the font lacks is not flagged.
+    ///
+    fn width_note(&self, options: &DumpOptions, caption: impl FnOnce() -> Result<String, String>) -> String {
+        let check = match &options.caption_width {
+            Some(check) => check,
+            None => return String::new(),
+        };
+        let caption = match caption() {
+            Ok(caption) => caption,
+            Err(_) => return String::new(),
+        };
+        let mut widest = 0;
+        for line in caption.split('↵') {
+            match string_pixel_width(&check.fonts, self.blob.get_maps(), check.char_map, check.font_family, line, check.max_width) {
+                Some((width, _)) => widest = widest.max(width),
+                None => return String::new(),
+            }
+        }
+        if widest > check.max_width {
+            format!(" [too wide, {} > {} pixels]", widest, check.max_width)
+        } else {
+            String::new()
+        }
+    }
+
+    ///
+    /// Parse only the subtree of the first product matching product_id,
+    /// the other products in the index are not decoded.
+    ///
+    pub fn from_product<R: Read + Seek>(fp: &mut R, maps: CharacterMaps, product_id: u16) -> io::Result<ProductIndexEntry>
+    {
+        let (mut fp, schema, font_family, offsets) = Self::read_header(fp, maps, &ParseOptions::default())?;
+
+        fp.set_pos(offsets[0]);
+        match ProductIndex::create_one_from_file(&mut fp, schema, font_family, product_id)? {
+            Some(product) => Ok(product),
+            None => Err(Error::new(ErrorKind::NotFound, format!("Product {} not found", product_id))),
+        }
+    }
+
+    ///
+    /// Read just the global tables, the units, enumerations and keypad strings,
+    /// skipping the product tree
+    ///
+    pub fn load_tables_only<R: Read + Seek>(fp: &mut R, maps: CharacterMaps) -> io::Result<(UnitsIndex, EnumerationsIndex, KeypadStrIndex)>
+    {
+        let (mut fp, schema, font_family, offsets) = Self::read_header(fp, maps, &ParseOptions::default())?;
+
+        let (enumeration_index, keypad_str_index, units_index) = Self::read_tables(&mut fp, schema, font_family, &offsets)?;
+        Ok((units_index, enumeration_index, keypad_str_index))
+    }
+
+    ///
+    /// Parse as far as possible, stopping at the first error. Returns the
+    /// Language built up to that point, with whatever products and tables
+    /// were read, and a diagnostic locating where it stopped. There is no
+    /// Language if the header could not be read.
+    ///
+    pub fn from_partial<R: Read + Seek>(fp: &mut R, maps: CharacterMaps) -> (Option<Language>, Option<Diagnostic>)
+    {
+        // A damaged file is the point, so a bad length or CRC is only a warning
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let header = panic::catch_unwind(AssertUnwindSafe(|| Self::read_header(fp, maps, &options)));
+        let (mut fp, schema, font_family, offsets) = match header {
+            Ok(Ok(header)) => header,
+            Ok(Err(x)) => return (None, Some(Self::stopped_at(BlobRegions::Header, 0, x.to_string()))),
+            Err(x) => return (None, Some(Self::stopped_at(BlobRegions::Header, 0, panic_message(x)))),
+        };
+        if !fp.verify_length() {
+            fp.add_diagnostic(Severity::Warning, BlobRegions::Header, 0, "Length does not match the file");
+        }
+        if !fp.verify_crc() {
+            fp.add_diagnostic(Severity::Warning, BlobRegions::Header, 4, "CRC does not match the file");
+        }
+
+        fp.set_pos(offsets[0]);
+        let (product_index, error) = ProductIndex::create_partial_from_file(&mut fp, schema, font_family);
+
+        let mut tables = (EnumerationsIndex::empty(), KeypadStrIndex::empty(), UnitsIndex::empty());
+        let error = match error {
+            Some(x) => Some(x),
+            None => match fp.catch(|fp| Self::read_tables(fp, schema, font_family, &offsets)) {
+                Ok(x) => {
+                    tables = x;
+                    None
+                }
+                Err(x) => Some(x),
+            },
+        };
+        let stopped = error.map(|x| {
+            let pos = fp.get_pos();
+            Self::stopped_at(fp.region_at(pos.saturating_sub(1)), pos, x)
+        });
+
+        let (enumeration_index, keypad_str_index, units_index) = tables;
+        let lang = Language {
+            product_index,
+            enumeration_index,
+            keypad_str_index,
+            units_index,
+            diagnostics: fp.get_diagnostics(),
+            font_family,
+            is_utf8: fp.get_maps().is_utf8(),
+            blob: fp.freeze(BlobRegions::Text),
+        };
+        (Some(lang), stopped)
+    }
+
+    fn stopped_at(region: BlobRegions, location: u32, message: String) -> Diagnostic
+    {
+        Diagnostic { severity: Severity::Error, region, location, message }
+    }
+
+    ///
+    /// Read the enumerations, keypad strings and units at their offsets
+    ///
+    fn read_tables(fp: &mut FileBlob, schema: u16, font_family: u8, offsets: &[u32]) -> io::Result<(EnumerationsIndex, KeypadStrIndex, UnitsIndex)>
+    {
+        // Stripped down files may have no enumerations
+        let enumeration_index = if offsets[1] > 0 {
+            fp.set_pos(offsets[1]);
+            fp.time(BlobRegions::Enumerations, |fp| EnumerationsIndex::from(fp, schema, font_family))?
+        } else {
+            EnumerationsIndex::empty()
+        };
+
+        let mut keypad_str_range = 0..0;
+        let keypad_str_index = if offsets[2] > 0 {
+            fp.set_pos(offsets[2]);
+            let index = fp.time(BlobRegions::KeypadStrs, |fp| KeypadStrIndex::from(fp, schema, font_family, DEFAULT_KEYPAD_STR_IDS))?;
+            keypad_str_range = offsets[2]..fp.get_pos();
+            index
+        } else if schema == 2 {
+            panic!("Missing Keypad strings in V2 language file");
+        } else {
+            KeypadStrIndex::empty()
+        };
+
+        // The keypad strings and units offsets are independent, a bad build can make
+        // them overlap. Units starting before the keypad strings and running into
+        // them are caught as a region mismatch when read.
+        if keypad_str_range.contains(&offsets[3]) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Units at {} overlap keypad strings {} to {}",
+                    offsets[3], keypad_str_range.start, keypad_str_range.end
+                ),
+            ));
+        }
+        fp.set_pos(offsets[3]);
+        let units_index = fp.time(BlobRegions::Units, |fp| UnitsIndex::from(fp, schema, font_family))?;
+
+        Ok((enumeration_index, keypad_str_index, units_index))
+    }
+
+    ///
+    /// Read the common header and the section offsets, returns the
+    /// loaded blob, schema, font family and offsets
+    ///
+    fn read_header<R: Read + Seek>(fp: &mut R, maps: CharacterMaps, options: &ParseOptions) -> io::Result<(FileBlob, u16, u8, Vec<u32>)>
     {
         let mut common_hdr = [0; 32];
         fp.read_exact(&mut common_hdr)?;
@@ -33,133 +355,77 @@ impl Language
         // Language file header
         let file_len = little_endian_4_bytes(&common_hdr[0..4]);
         let file_crc = little_endian_4_bytes(&common_hdr[4..8]);
-        let schema = little_endian_2_bytes(&common_hdr[8..10]);
+        let header_schema = little_endian_2_bytes(&common_hdr[8..10]);
+        let schema = options.force_schema.unwrap_or(header_schema);
         let locale_id = little_endian_2_bytes(&common_hdr[10..12]);
         let lang_version = little_endian_4_version(&common_hdr[12..16]);
         let lang_name = &common_hdr[16..32];
+
+        // Check the schema before trusting the rest of the header
+        if !(2..=4).contains(&schema) {
+            panic!("Invalid format {}", schema);
+        }
         
         let mut fp = FileBlob::load(
             fp,
             file_len,
             file_crc,
-            if schema > 3 {
-                CharacterMaps::utf8()
+            if schema > 3 && maps.wide_map().is_some() {
+                maps.as_wide()
+            } else if schema > 3 {
+                maps.as_utf8()
             } else {
                 maps
             },
+            options.clone(),
         )?;
-        fp.set_pos(32);
+        fp.skip(32, BlobRegions::Header);
+        if schema != header_schema {
+            fp.add_diagnostic(Severity::Warning, BlobRegions::Header, 8, &format!("Schema {} forced, header has {}", schema, header_schema));
+        }
        
         println!("Language file locale_id {}, length {}, crc {}, schema {}", locale_id, file_len, file_crc, schema);
 
+        Self::check_lang_name(&fp, lang_name);
+
         let font_family = if schema < 4 {
-            let font_family = fp.read_le_2bytes(BlobRegions::Header) as u8;
+            let font_family = fp.read_le_2bytes(BlobRegions::Header)?;
+            if font_family > 255 {
+                fp.add_diagnostic(Severity::Warning, BlobRegions::Header, 32, &format!("Font family {} has non-zero high byte", font_family));
+            }
+            let font_family = font_family as u8;
             println!("Font family {}", font_family);
             font_family
         } else {
             0
         };
 
-        let offset_size = fp.read_le_2bytes(BlobRegions::Header);
+        let offset_size = fp.read_le_2bytes(BlobRegions::Header)?;
+
+        Self::validate_schema(schema, offset_size);
 
         println!(
             "Language file offset_size {}, version {}",
             offset_size, lang_version
         );
 
-        Self::validate_schema(schema, offset_size);
-
         // Language file V2 uses 32 bit offsets, Language file >= V3 uses 24 bit offsets
-        let offsets = Self::parse_offsets(&mut fp, schema, offset_size);
-
-        fp.set_pos(offsets[0]);
-        let product_index = ProductIndex::create_from_file(&mut fp, schema, font_family);
-
-        fp.set_pos(offsets[1]);
-        let enumeration_index = EnumerationsIndex::from(&mut fp, schema, font_family);
-
-        let keypad_str_index = if offsets[2] > 0 {
-            fp.set_pos(offsets[2]);
-            KeypadStrIndex::from(&mut fp, schema, font_family)
-        } else if schema == 2 {
-            panic!("Missing Keypad strings in V2 language file");
-        } else {
-            KeypadStrIndex::empty()
-        };
+        let offsets = Self::parse_offsets(&mut fp, schema, offset_size)?;
 
-        fp.set_pos(offsets[3]);
-        let units_index = UnitsIndex::from(&mut fp, schema, font_family);
-
-        let lang = Language {
-            product_index,
-            enumeration_index,
-            keypad_str_index,
-            units_index,
-        };
-
-        println!("Products ....");
+        Ok((fp, schema, font_family, offsets))
+    }
 
-        for details in &lang.product_index {
-            match details.to_string() {
-                Ok(x) => println!("{}", x),
-                Err(x) => panic!("{}", x),
-            };
-            for (mode, details) in details.get_modes() {
-                match details.to_string(mode) {
-                    Ok(x) => println!("- {}", x),
-                    Err(x) => panic!("- {}", x),
-                };
-                for (menu, details) in details.get_menus() {
-                    match details.to_string() {
-                        Ok(x) => println!("- - M.{} => {}", menu, x),
-                        Err(x) => panic!("- - M.{} => {}", menu, x),
-                    };
-                    for (param, details) in details.get_params() {
-                        match details.to_string() {
-                            Ok(x) => println!("- - - P.{} => {}", param, x),
-                            Err(x) => panic!("- - - P.{} => {}", param, x),
-                        };
-                        for (value, details) in details.get_mnemonics() {
-                            match details.to_string() {
-                                Ok(x) => println!("- - - - {} => {}", value, x),
-                                Err(x) => panic!("- - - - {} => {}", value, x),
-                            };
-                        }
-                    }
-                }
+    ///
+    /// The language name is NUL padded, non-zero padding suggests
+    /// the header is misaligned
+    ///
+    fn check_lang_name(fp: &FileBlob, lang_name: &[u8]) {
+        if let Some(end) = lang_name.iter().position(|x| *x == 0) {
+            if let Some(junk) = lang_name[end..].iter().position(|x| *x != 0) {
+                let location = (16 + end + junk) as u32;
+                fp.add_diagnostic(Severity::Warning, BlobRegions::Header, location, "Non-zero padding after language name");
             }
         }
-
-        println!("Legacy Enumerations ....");
-
-        for (enumeration, details) in &lang.enumeration_index {
-            match details.to_string() {
-                Ok(x) => println!("{} => {}", enumeration, x),
-                Err(x) => panic!("{} => {}", enumeration, x),
-            };
-        }
-
-        println!("Keypad strs ....");
-
-        for (num, details) in &lang.keypad_str_index {
-            match details.to_string() {
-                Ok(x) => println!("{} => {}", num, x),
-                Err(x) => panic!("{} => {}", num, x),
-            };
-        }
-
-        println!("Units ....");
-
-        for (unit, details) in &lang.units_index {
-            match details.to_string() {
-                Ok(x) => println!("{} => {}", unit, x),
-                Err(x) => panic!("{} => {}", unit, x),
-            };
-        }
-
-        fp.display_stats();
-
-        return Result::Ok(lang);
     }
 
     ///
@@ -187,56 +453,631 @@ impl Language
     }
 
 
-    fn parse_offsets(fp : & mut FileBlob, schema : u16, offset_size: u16) -> Vec<u32> {
+    fn parse_offsets(fp : & mut FileBlob, schema : u16, offset_size: u16) -> io::Result<Vec<u32>> {
         // Language file V2 uses 32 bit offsets, Language file >= V3 uses 24 bit offsets
         let mut offsets = Vec::new();
         match schema {
             2 => {
-                offsets.push(fp.read_le_4bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_4bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_4bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_4bytes(BlobRegions::Header));
+                offsets.push(fp.read_le_4bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_4bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_4bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_4bytes(BlobRegions::Header)?);
             }
             3 => {
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
             }
             4 => {
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
                 offsets.push(0);
-                offsets.push(fp.read_le_3bytes(BlobRegions::Header));
+                offsets.push(fp.read_le_3bytes(BlobRegions::Header)?);
             }
             _ => panic!("Invalid format"),
         };
-        return offsets;
+        return Ok(offsets);
+    }
+
+    pub fn get_products(&self) -> &ProductIndex {
+        &self.product_index
+    }
+
+    pub fn get_enumerations(&self) -> &EnumerationsIndex {
+        &self.enumeration_index
+    }
+
+    pub fn get_keypad_strs(&self) -> &KeypadStrIndex {
+        &self.keypad_str_index
+    }
+
+    pub fn get_units(&self) -> &UnitsIndex {
+        &self.units_index
+    }
+
+    pub fn get_font_family(&self) -> u8 {
+        self.font_family
+    }
+
+    ///
+    /// Are the strings UTF-8 rather than character map codes
+    ///
+    pub fn is_utf8(&self) -> bool {
+        self.is_utf8
+    }
+
+    ///
+    /// The index entry length used by each region, for auditing format drift
+    ///
+    pub fn entry_layouts(&self) -> HashMap<BlobRegions, u8> {
+        self.blob.entry_layouts()
+    }
+
+    ///
+    /// The (accounted, unaccounted) bytes of the file, see FileBlob::account_for_bytes
+    ///
+    pub fn account_for_bytes(&self) -> (usize, usize) {
+        self.blob.account_for_bytes()
+    }
+
+    ///
+    /// Check every byte of the file, apart from the trailing padding, was
+    /// parsed, see FileBlob::check_all_bytes_accounted
+    ///
+    pub fn check_all_bytes_accounted(&self) -> Result<(), String> {
+        self.blob.check_all_bytes_accounted()
+    }
+
+    ///
+    /// The duplicated strings, region layout and unused bytes of the file
+    ///
+    pub fn get_stats(&self) -> BlobStats {
+        self.blob.get_stats()
+    }
+
+    ///
+    /// The time taken parsing each region, empty unless ParseOptions::timing
+    /// was set
+    ///
+    pub fn get_timings(&self) -> Vec<(BlobRegions, Duration)> {
+        self.blob.get_timings()
+    }
+
+    ///
+    /// The bytes of region, see FileBlob::region_bytes
+    ///
+    pub fn region_bytes(&self, region: BlobRegions) -> Vec<u8> {
+        self.blob.region_bytes(region)
+    }
+
+    ///
+    /// The bytes around off, see FileBlob::extract_context
+    ///
+    pub fn extract_context(&self, off: u32, radius: u32) -> (u32, Vec<u8>) {
+        self.blob.extract_context(off, radius)
+    }
+
+    ///
+    /// The source codes of the characters of the string at off
+    ///
+    pub fn get_codes(&self, off: u32) -> Vec<u16> {
+        self.blob.get_codes(off)
+    }
+
+    ///
+    /// The CRC recorded in the file header
+    ///
+    pub fn get_file_crc(&self) -> u32 {
+        self.blob.get_file_crc()
+    }
+
+    ///
+    /// The raw bytes of the string at off, see RawBlob::get_raw_bytes
+    ///
+    pub fn get_raw_bytes(&self, off: u32, max_length: u16) -> Vec<u8> {
+        self.blob.get_raw_bytes(off, max_length)
+    }
+
+    ///
+    /// Encode text with the Language's own character maps
+    ///
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        self.blob.encode(text)
     }
 
-    pub fn write_text_file(&self, filepath: &str) {
-        let mut fp = match File::create(filepath) {
-            Ok(fp) => fp,
-            Err(_) => {
-                panic!("Failed to open {}", String::from(filepath));
+    ///
+    /// The (map id, code) of every character decoded so far, with the
+    /// number of times it was decoded
+    ///
+    pub fn get_char_usage(&self) -> HashMap<(u16, u32), u32> {
+        self.blob.get_char_usage()
+    }
+
+    ///
+    /// The codes of character map map_id decoded so far in this Language,
+    /// with the times each was decoded, sorted by code
+    ///
+    pub fn used_codepoints(&self, map_id: u16) -> Vec<(u32, u32)> {
+        match self.blob.get_maps().get_map(map_id) {
+            Some(map) => map.used_codepoints(),
+            None => Vec::new(),
+        }
+    }
+
+    ///
+    /// The problems noted, but not fatal, while parsing
+    ///
+    pub fn get_diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    ///
+    /// Decode the parameter at product / mode / menu / parameter, the first
+    /// product entry (derivative) with the path is used. None if any level
+    /// is missing.
+    ///
+    pub fn resolve(&self, product_id: u16, mode_num: u8, menu_num: u8, param_num: u8) -> Option<Result<String, String>> {
+        let param = self.find_param(product_id, mode_num, menu_num, param_num)?;
+        Some(param.to_string())
+    }
+
+    ///
+    /// Format value of the parameter at product / mode / menu / parameter
+    /// with the caption of its unit, or as the bare value if the schema has
+    /// no unit reference for the parameter. None if any level is missing.
+    ///
+    pub fn format_param_value(&self, product_id: u16, mode_num: u8, menu_num: u8, param_num: u8, value: i32) -> Option<String> {
+        let param = self.find_param(product_id, mode_num, menu_num, param_num)?;
+        let unit = param.get_units_ref()
+            .and_then(|units_ref| self.units_index.get(units_ref))
+            .and_then(|unit| unit.caption().ok());
+        match unit {
+            Some(unit) => Some(format!("{} {}", value, unit)),
+            None => Some(value.to_string()),
+        }
+    }
+
+    fn find_param(&self, product_id: u16, mode_num: u8, menu_num: u8, param_num: u8) -> Option<&ParameterIndexEntry> {
+        for product in self.product_index.iter() {
+            if product.get_product_id() != product_id {
+                continue;
             }
-        };
+            let param = product.get_modes().get(mode_num)
+                .and_then(|mode| mode.get_menus().get(menu_num))
+                .and_then(|menu| menu.get_params().get(param_num));
+            if let Some(param) = param {
+                return Some(param);
+            }
+        }
+        None
+    }
+
+    ///
+    /// The (product, mode) pairs, across all products, that have the menu menu_num
+    ///
+    pub fn modes_containing_menu(&self, menu_num: u8) -> Vec<(ProductKey, u8)> {
+        let mut result = Vec::new();
+        for product in self.product_index.iter() {
+            let (derivative_id_low, derivative_id_high) = product.get_derivative_ids();
+            let product_key = ProductKey {
+                product_id: product.get_product_id(),
+                derivative_id_low,
+                derivative_id_high,
+            };
+            for mode in product.modes_containing_menu(menu_num) {
+                result.push((product_key, mode));
+            }
+        }
+        result
+    }
+
+    ///
+    /// Write the tree printed by dump to the text file at filepath
+    ///
+    pub fn write_text_file(&self, filepath: &str) -> io::Result<()> {
+        self.write_text_file_with_options(filepath, &DumpOptions::default())
+    }
+
+    pub fn write_text_file_with_options(&self, filepath: &str, options: &DumpOptions) -> io::Result<()> {
+        let mut fp = BufWriter::new(File::create(filepath)?);
+        self.write_dump(&mut fp, options)?;
+        fp.flush()
+    }
+
+    ///
+    /// Write only product_id, an entry per derivative range with its modes,
+    /// menus, parameters and mnemonics. An error if there is no such product.
+    ///
+    pub fn write_product_text(&self, product_id: u16, filepath: &str) -> io::Result<()> {
+        if self.product_index.get(product_id).is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No product {}", product_id)));
+        }
+        let options = DumpOptions::default();
+        let mut fp = BufWriter::new(File::create(filepath)?);
+        for details in self.product_index.iter().filter(|details| details.get_product_id() == product_id) {
+            self.write_product(&mut fp, details, &options)?;
+        }
+        fp.flush()
     }
 }
 
-pub fn read_language_file(filepath: &str, maps: CharacterMaps) -> Language {
+pub fn read_language_file(filepath: &str, maps: CharacterMaps) -> io::Result<Language> {
+    read_language_file_with_options(filepath, maps, &ParseOptions::default())
+}
+
+pub fn read_language_file_with_options(filepath: &str, maps: CharacterMaps, options: &ParseOptions) -> io::Result<Language> {
     let mut fp = match File::open(filepath) {
         Ok(fp) => fp,
-        Err(_) => {
-            panic!("Failed to open {}", String::from(filepath));
+        Err(x) => {
+            return Err(Error::new(x.kind(), format!("Failed to open {}: {}", filepath, x)));
         }
     };
 
-    let language = match Language::create_from_file(&mut fp, maps) {
-        Ok(index) => index,
-        Err(_) => {
-            panic!("Failed to process {}", String::from(filepath));
+    match Language::create_from_file_with_options(&mut fp, maps, options) {
+        Ok(language) => Ok(language),
+        Err(x) => Err(Error::new(x.kind(), format!("Failed to process {}: {}", filepath, x))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{maps, parse_edited, v4_units_file};
+
+    #[test]
+    fn from_product_leaves_the_other_products_untouched() {
+        let full = read_language_file("languageV3.bin", maps()).unwrap();
+        let products: Vec<&ProductIndexEntry> = full.get_products().iter().collect();
+        assert!(products.len() > 1);
+        let count = |product_id| products.iter().filter(|product| product.get_product_id() == product_id).count();
+        let index = products.iter().position(|product| count(product.get_product_id()) == 1).unwrap();
+        let product_id = products[index].get_product_id();
+
+        let mut file = File::open("languageV3.bin").unwrap();
+        let (mut fp, schema, font_family, offsets) = Language::read_header(&mut file, maps(), &ParseOptions::default()).unwrap();
+        fp.set_pos(offsets[0]);
+        let product = ProductIndex::create_one_from_file(&mut fp, schema, font_family, product_id).unwrap().unwrap();
+        assert_eq!(product.get_modes_off(), products[index].get_modes_off());
+
+        let touched = |off: u32| {
+            fp.get_stats().regions.iter()
+                .any(|(start, end, region)| *region == BlobRegions::Modes && (*start..*end).contains(&(off as usize)))
+        };
+        assert!(touched(product.get_modes_off()));
+        for other in products.iter().filter(|other| other.get_product_id() != product_id) {
+            if other.get_modes_off() != product.get_modes_off() {
+                assert!(!touched(other.get_modes_off()), "product {}", other.get_product_id());
+            }
         }
-    };
-    return language;
+    }
+
+    #[test]
+    fn borrowing_iterators_match_the_cloning_order() {
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        let products = lang.get_products();
+        let cloned: Vec<(u16, u16, u16)> = products.into_iter()
+            .map(|x| (x.get_product_id(), x.get_derivative_ids().0, x.get_derivative_ids().1))
+            .collect();
+        let borrowed: Vec<(u16, u16, u16)> = products.iter()
+            .map(|x| (x.get_product_id(), x.get_derivative_ids().0, x.get_derivative_ids().1))
+            .collect();
+        assert_eq!(borrowed, cloned);
+
+        let mut params = 0;
+        for product in products.iter() {
+            let modes = product.get_modes();
+            assert!(modes.iter().map(|(mode, _)| mode).eq(modes.into_iter().map(|(mode, _)| mode)));
+            for (mode, mode_entry) in modes.iter() {
+                assert!(std::ptr::eq(mode_entry, modes.get(mode).unwrap()));
+                let menus = mode_entry.get_menus();
+                assert!(menus.iter().map(|(menu, _)| menu).eq(menus.into_iter().map(|(menu, _)| menu)));
+                for (menu, menu_entry) in menus.iter() {
+                    assert!(std::ptr::eq(menu_entry, menus.get(menu).unwrap()));
+                    let index = menu_entry.get_params();
+                    assert!(index.iter().map(|(param, _)| param).eq(index.into_iter().map(|(param, _)| param)));
+                    for (param, param_entry) in index.iter() {
+                        assert!(std::ptr::eq(param_entry, index.get(param).unwrap()));
+                        params += 1;
+                    }
+                }
+            }
+        }
+        assert!(params > 0);
+
+        let units = lang.get_units();
+        assert!(units.iter().map(|(unit, _)| unit).eq(units.into_iter().map(|(unit, _)| unit)));
+        for (unit, entry) in units.iter() {
+            assert!(std::ptr::eq(entry, units.get(unit).unwrap()));
+        }
+        let enumerations = lang.get_enumerations();
+        assert!(enumerations.iter().map(|(id, _)| id).eq(enumerations.into_iter().map(|(id, _)| id)));
+    }
+
+    #[test]
+    fn resolve_finds_a_valid_path_only() {
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        // The first path that reaches a parameter, earlier products have none
+        let (product, mode, mode_entry, menu, menu_entry, param, param_entry) = lang.get_products().iter()
+            .flat_map(|product| product.get_modes().iter().map(move |(mode, mode_entry)| (product, mode, mode_entry)))
+            .flat_map(|(product, mode, mode_entry)| mode_entry.get_menus().iter()
+                .map(move |(menu, menu_entry)| (product, mode, mode_entry, menu, menu_entry)))
+            .find_map(|(product, mode, mode_entry, menu, menu_entry)| menu_entry.get_params().iter().next()
+                .map(|(param, param_entry)| (product, mode, mode_entry, menu, menu_entry, param, param_entry)))
+            .unwrap();
+        let product_id = product.get_product_id();
+
+        let expected = param_entry.to_string().unwrap();
+        assert_eq!(lang.resolve(product_id, mode, menu, param), Some(Ok(expected)));
+
+        let unused_id = (0..=u16::MAX)
+            .find(|id| lang.get_products().iter().all(|x| x.get_product_id() != *id))
+            .unwrap();
+        assert_eq!(lang.resolve(unused_id, mode, menu, param), None);
+        assert!(product.get_modes().get(255).is_none());
+        assert_eq!(lang.resolve(product_id, 255, menu, param), None);
+        assert!(mode_entry.get_menus().get(255).is_none());
+        assert_eq!(lang.resolve(product_id, mode, 255, param), None);
+        assert!(menu_entry.get_params().get(255).is_none());
+        assert_eq!(lang.resolve(product_id, mode, menu, 255), None);
+    }
+
+    #[test]
+    fn param_value_without_a_unit_is_bare() {
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        let (product_id, mode, menu, param, entry) = lang.get_products().iter()
+            .flat_map(|product| product.get_modes().iter().map(move |(mode, mode_entry)| (product, mode, mode_entry)))
+            .flat_map(|(product, mode, mode_entry)| mode_entry.get_menus().iter()
+                .map(move |(menu, menu_entry)| (product, mode, menu, menu_entry)))
+            .find_map(|(product, mode, menu, menu_entry)| menu_entry.get_params().iter().next()
+                .map(|(param, entry)| (product.get_product_id(), mode, menu, param, entry)))
+            .unwrap();
+        assert_eq!(entry.get_units_ref(), None);
+
+        assert_eq!(lang.format_param_value(product_id, mode, menu, param, 42), Some("42".to_string()));
+        assert_eq!(lang.format_param_value(product_id, mode, menu, param, -7), Some("-7".to_string()));
+        assert_eq!(lang.format_param_value(product_id, mode, menu, 255, 42), None);
+    }
+
+    #[test]
+    fn dump_has_offsets_only_when_asked() {
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        let dump = |options: &DumpOptions| {
+            let mut out = Vec::new();
+            lang.write_dump(&mut out, options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let plain = dump(&DumpOptions::default());
+        let annotated = dump(&DumpOptions { include_offsets: true, ..DumpOptions::default() });
+        assert!(!plain.contains(" @0x"));
+        assert_eq!(plain.lines().count(), annotated.lines().count());
+
+        let (unit, entry) = lang.get_units().iter().next().unwrap();
+        let line = format!("{} @0x{:X} => ", unit, entry.get_caption_off());
+        assert!(annotated.lines().any(|x| x.starts_with(&line)), "{}", line);
+        let (menu, entry) = lang.get_products().iter().next().unwrap()
+            .get_modes().iter().next().unwrap().1
+            .get_menus().iter().next().unwrap();
+        assert_eq!(entry.get_tooltip_off(), 0);
+        let line = format!("- - M.{} @0x{:X} => ", menu, entry.get_caption_off());
+        assert!(annotated.contains(&line), "{}", line);
+    }
+
+    #[test]
+    fn junk_in_the_header_padding_is_warned() {
+        let clean = read_language_file("languageV3.bin", maps()).unwrap();
+        assert!(clean.get_diagnostics().is_empty());
+
+        let lang = parse_edited("languageV3.bin", |data| {
+            data[30] = b'Z';
+            data[33] = 1;
+        }).unwrap();
+        let warnings: Vec<(u32, &str)> = lang.get_diagnostics().iter()
+            .filter(|x| x.severity == Severity::Warning && x.region == BlobRegions::Header)
+            .map(|x| (x.location, x.message.as_str()))
+            .collect();
+        assert_eq!(warnings, [
+            (30, "Non-zero padding after language name"),
+            (32, "Font family 257 has non-zero high byte"),
+        ]);
+    }
+
+    #[test]
+    fn zero_enumerations_offset_gives_an_empty_index() {
+        let clean = read_language_file("languageV3.bin", maps()).unwrap();
+        assert!(clean.get_enumerations().iter().next().is_some());
+
+        // The V3 offsets are 3 bytes each from 36, the enumerations second
+        let lang = parse_edited("languageV3.bin", |data| data[39..42].fill(0)).unwrap();
+        assert_eq!(lang.get_enumerations().iter().count(), 0);
+        assert_eq!(lang.get_units().iter().count(), clean.get_units().iter().count());
+    }
+
+    #[test]
+    fn forced_schema_parses_a_corrupted_schema_byte() {
+        let clean = read_language_file("languageV3.bin", maps()).unwrap();
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        data[8] = 9;
+        let parse = |force_schema| {
+            let options = ParseOptions { skip_crc_check: true, force_schema, ..ParseOptions::default() };
+            Language::create_from_file_with_options(&mut std::io::Cursor::new(data.clone()), maps(), &options)
+        };
+        let error = std::panic::catch_unwind(|| parse(None)).err().unwrap();
+        assert_eq!(error.downcast_ref::<String>().map(String::as_str), Some("Invalid format 9"));
+
+        let lang = parse(Some(3)).unwrap();
+        assert_eq!(lang.get_products().iter().count(), clean.get_products().iter().count());
+        assert_eq!(lang.flatten().len(), clean.flatten().len());
+        let warnings: Vec<(u32, &str)> = lang.get_diagnostics().iter()
+            .map(|x| (x.location, x.message.as_str()))
+            .collect();
+        assert_eq!(warnings, [(8, "Schema 3 forced, header has 9")]);
+    }
+
+    #[test]
+    fn fixture_entry_lengths_per_section() {
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        let expected = HashMap::from([
+            (BlobRegions::Products, 11),
+            (BlobRegions::Modes, 3),
+            (BlobRegions::Menus, 3),
+            (BlobRegions::Parameters, 5),
+            (BlobRegions::Enumerations, 5),
+            (BlobRegions::Units, 5),
+        ]);
+        assert_eq!(lang.entry_layouts(), expected);
+    }
+
+    #[test]
+    fn injected_bytes_are_unaccounted() {
+        let clean = read_language_file("languageV3.bin", maps()).unwrap();
+        clean.flatten();
+        let (accounted, unaccounted) = clean.account_for_bytes();
+        assert_eq!(unaccounted, 0);
+        assert_eq!(clean.check_all_bytes_accounted(), Ok(()));
+
+        let lang = parse_edited("languageV3.bin", |data| {
+            data.extend([0xAA; 4]);
+            let file_len = data.len() as u32;
+            data[0..4].copy_from_slice(&file_len.to_le_bytes());
+        }).unwrap();
+        lang.flatten();
+        assert_eq!(lang.account_for_bytes(), (accounted, 4));
+        assert_eq!(lang.check_all_bytes_accounted(), Err(format!("4 bytes unaccounted for, {} accounted", accounted)));
+    }
+
+    #[test]
+    fn tables_only_match_the_full_parse() {
+        let full = read_language_file("languageV3.bin", maps()).unwrap();
+        let mut file = File::open("languageV3.bin").unwrap();
+        let (units, enumerations, keypad_strs) = Language::load_tables_only(&mut file, maps()).unwrap();
+
+        let decoded = |units: &UnitsIndex| -> Vec<(u16, Result<String, String>)> {
+            units.iter().map(|(unit, entry)| (unit, entry.to_string())).collect()
+        };
+        assert!(!decoded(&units).is_empty());
+        assert_eq!(decoded(&units), decoded(full.get_units()));
+        assert_eq!(enumerations.to_map(), full.get_enumerations().to_map());
+        let ids = |index: &KeypadStrIndex| -> Vec<(u16, Result<String, String>)> {
+            index.iter().map(|(id, entry)| (id, entry.to_string())).collect()
+        };
+        assert_eq!(ids(&keypad_strs), ids(full.get_keypad_strs()));
+    }
+
+    #[test]
+    fn region_bytes_are_the_fixture_slices() {
+        let data = std::fs::read("languageV3.bin").unwrap();
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+
+        // 32 byte common header, font family, offset size and four 3 byte offsets
+        assert_eq!(lang.region_bytes(BlobRegions::Header), data[..48]);
+
+        let units_off = little_endian_4_bytes(&[data[45], data[46], data[47], 0]) as usize;
+        let units = lang.region_bytes(BlobRegions::Units);
+        assert_eq!(units, data[units_off..units_off + units.len()]);
+
+        let mut concatenated = Vec::new();
+        for (start, end, region) in lang.get_stats().regions {
+            if region == BlobRegions::Menus {
+                concatenated.extend(&data[start..end]);
+            }
+        }
+        assert!(!concatenated.is_empty());
+        assert_eq!(lang.region_bytes(BlobRegions::Menus), concatenated);
+    }
+
+    #[test]
+    fn v4_is_utf8_and_v3_is_not() {
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let v4 = Language::create_from_file_with_options(&mut std::io::Cursor::new(v4_units_file()), maps(), &options).unwrap();
+        assert!(v4.is_utf8());
+
+        let v3 = read_language_file("languageV3.bin", maps()).unwrap();
+        assert!(!v3.is_utf8());
+    }
+
+    #[test]
+    fn truncated_file_gives_a_partial_tree() {
+        let full = read_language_file("languageV3.bin", maps()).unwrap();
+        let enumerations = full.get_stats().regions.iter()
+            .find(|(_, _, region)| *region == BlobRegions::Enumerations)
+            .map(|(start, _, _)| *start)
+            .unwrap();
+        // Cut the file part way through the enumerations index
+        let cut = enumerations + 10;
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        data.truncate(cut);
+        let (lang, stopped) = Language::from_partial(&mut std::io::Cursor::new(data), maps());
+        let lang = lang.unwrap();
+        let stopped = stopped.unwrap();
+        assert_eq!(
+            lang.get_products().iter().count(),
+            full.get_products().iter().count()
+        );
+        assert_eq!(stopped.severity, Severity::Error);
+        assert_eq!(stopped.region, BlobRegions::Enumerations);
+        assert!((enumerations..cut).contains(&(stopped.location as usize)), "{} not in {}..{}", stopped.location, enumerations, cut);
+        assert!(stopped.message.contains("Unexpected EOF"));
+    }
+
+    #[test]
+    fn timing_records_the_parsed_regions() {
+        let options = ParseOptions { timing: true, ..ParseOptions::default() };
+        let lang = read_language_file_with_options("languageV3.bin", maps(), &options).unwrap();
+        let timings: HashMap<BlobRegions, Duration> = lang.get_timings().into_iter().collect();
+        for region in [BlobRegions::Products, BlobRegions::Modes, BlobRegions::Menus, BlobRegions::Units] {
+            assert!(timings.get(&region).is_some_and(|elapsed| !elapsed.is_zero()), "{:?}", region);
+        }
+
+        let lang = read_language_file("languageV3.bin", maps()).unwrap();
+        assert!(lang.get_timings().is_empty());
+    }
+
+    #[test]
+    fn v2_overlapping_sections_are_caught() {
+        let clean = read_language_file("languageV2.bin", maps()).unwrap();
+        let keypad = clean.get_stats().regions.iter()
+            .find(|(_, _, region)| *region == BlobRegions::KeypadStrs)
+            .map(|(start, end, _)| (*start as u32, *end as u32))
+            .unwrap();
+
+        // Units pointing part way into the keypad strings
+        let units = keypad.0 + 2;
+        let error = parse_edited("languageV2.bin", |data| data[48..52].copy_from_slice(&units.to_le_bytes()))
+            .err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), format!("Units at {} overlap keypad strings {} to {}", units, keypad.0, keypad.1));
+    }
+
+    #[test]
+    fn v4_strings_of_a_3_byte_map() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?>
+<characterMaps>
+  <characterMap name="Latin" id="1" bytesPerCharacter="1">
+      <char name="A" value="65" />
+  </characterMap>
+  <characterMap name="CJK" id="4" bytesPerCharacter="3">
+      <char name="&#x4E2D;" value="20013" />
+      <char name="&#x6587;" value="25991" />
+  </characterMap>
+</characterMaps>
+"#;
+        let maps = crate::characters::read_character_data(xml).unwrap();
+        let mut data = v4_units_file();
+        data.truncate(174);
+        data.extend([0x2D, 0x4E, 0, 0x87, 0x65, 0, 0, 0, 0]);
+        data.extend([0x87, 0x65, 0, 0, 0, 0]);
+        let file_len = data.len() as u32;
+        data[0..4].copy_from_slice(&file_len.to_le_bytes());
+        let entry = data.windows(8).position(|x| x == [2, 0, 174, 0, 0, 179, 0, 0]).unwrap();
+        data[entry + 5] = 183;
+
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut std::io::Cursor::new(data), maps, &options).unwrap();
+        assert!(!lang.is_utf8());
+        assert_eq!(lang.get_units().get(1).unwrap().caption(), Ok("\u{4e2d}\u{6587}".to_string()));
+        assert_eq!(lang.get_units().get(2).unwrap().to_string(), Ok("\u{4e2d}\u{6587} / \u{6587}".to_string()));
+        let used: Vec<u32> = lang.used_codepoints(4).iter().map(|(code, _)| *code).collect();
+        assert_eq!(used, [0x4E2D, 0x6587]);
+    }
 }
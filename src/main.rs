@@ -1,23 +1,59 @@
 extern crate xml;
 
+pub mod archive;
+pub mod audit;
 pub mod blob;
+pub mod cache;
 pub mod characters;
 pub mod conversion;
+pub mod diagnostics;
 pub mod fonts;
 pub mod keypadstrs;
 pub mod language;
 pub mod menus;
 pub mod enumerations;
+pub mod flatten;
+pub mod fontusage;
+pub mod json;
 pub mod modes;
+pub mod normalize;
+pub mod options;
 pub mod parameters;
 pub mod products;
+pub mod reencode;
+pub mod tree;
 pub mod units;
 pub mod mnemonics;
+#[cfg(test)]
+mod fixtures;
 
 use std::fs;
 fn main() {
-    let _font_index = fonts::read_font_file("fonts.bft");
-    let character_maps = characters::read_character_file("CharacterMaps.xml");
+    let _font_index = match fonts::read_optional_font_file("fonts.bft") {
+        Ok(Some(index)) => {
+            for warning in index.get_warnings() {
+                println!("Warning: {}", warning);
+            }
+            Some(index)
+        }
+        Ok(None) => None,
+        Err(x) => {
+            println!("Error: {}, continuing without fonts", x);
+            None
+        }
+    };
+    let character_maps = match characters::read_character_file("CharacterMaps.xml") {
+        Ok(maps) => {
+            for warning in maps.get_warnings() {
+                println!("Warning: {}", warning);
+            }
+            maps
+        }
+        Err(x) => {
+            println!("Error: {}", x);
+            return;
+        }
+    };
 
     let paths = fs::read_dir("./").unwrap();
 
@@ -25,8 +61,17 @@ fn main() {
         let os_filename = path.unwrap().file_name();
         let filename = os_filename.into_string().unwrap();
         if filename.ends_with(".bin") {
-            let lang_v2 = language::read_language_file(&filename, character_maps.clone());
-            lang_v2.write_text_file(&(filename + ".txt"));
+            let lang_v2 = match language::read_language_file(&filename, character_maps.clone()) {
+                Ok(lang) => lang,
+                Err(x) => {
+                    println!("Error: {}", x);
+                    continue;
+                }
+            };
+            let text_filename = filename + ".txt";
+            if let Err(x) = lang_v2.write_text_file(&text_filename) {
+                println!("Warning: failed to write {}: {}", text_filename, x);
+            }
         }
         //        println!("Name {}", filename);
     }
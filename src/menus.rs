@@ -1,4 +1,6 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
 use std::rc::Rc;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
@@ -6,7 +8,7 @@ use crate::parameters::ParameterIndex;
 
 pub struct MenuIndex 
 {
-    menus: HashMap<u8, MenuIndexEntry>,
+    menus: BTreeMap<u8, MenuIndexEntry>,
 }
 
 pub struct MenuIndexEntry 
@@ -19,14 +21,14 @@ pub struct MenuIndexEntry
     blob: RawBlob,
 }
 
-pub struct MenuIndexIterator
+pub struct MenuIndexIterator<'a>
 {
-    items: Vec<(u8, MenuIndexEntry)>,
+    items: btree_map::Iter<'a, u8, MenuIndexEntry>,
 }
 
 impl MenuIndex {
 
-    pub fn new(menus : HashMap<u8, MenuIndexEntry>) -> MenuIndex
+    pub fn new(menus : BTreeMap<u8, MenuIndexEntry>) -> MenuIndex
     {
         let mut hits = HashSet::<u8>::new();
 
@@ -46,25 +48,29 @@ impl MenuIndex {
     ///
     /// V2 format does not have a MenuIndex, So create an pseudo one
     ///
-    pub fn from_v2(fp: &mut FileBlob, root_font_family: u8) -> MenuIndex {
+    pub fn from_v2(fp: &mut FileBlob, root_font_family: u8) -> io::Result<MenuIndex> {
         // V2 there are no menu Indexes!
         // Read ParameterIndex
 
-        let num_entries = fp.read_le_2bytes(BlobRegions::Parameters);
-        let max_str_len = fp.read_le_2bytes(BlobRegions::Parameters);
-        let font_family = fp.read_byte(BlobRegions::Parameters);
-        let idx_entry_len = fp.read_byte(BlobRegions::Parameters);
+        let num_entries = fp.read_le_2bytes(BlobRegions::Parameters)?;
+        fp.check_entries(BlobRegions::Parameters, num_entries as u32)?;
+        let max_str_len = fp.read_le_2bytes(BlobRegions::Parameters)?;
+        let font_family = fp.read_byte(BlobRegions::Parameters)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Parameters)?;
 
         if root_font_family != font_family {
             panic!("Mis-match font_family");
         }
 
-        ParameterIndex::validate_schema(2, idx_entry_len, num_entries, max_str_len);
+        let str_len = fp.max_str_len(BlobRegions::Parameters, 32);
+        ParameterIndex::validate_schema(2, idx_entry_len, num_entries, max_str_len, str_len);
+        fp.add_entry_layout(BlobRegions::Parameters, idx_entry_len);
 
         // Create menus anyway...
-        let tmp_menus = ParameterIndex::read_v2_entries(fp, num_entries);
+        let tmp_menus = ParameterIndex::read_v2_entries(fp, num_entries, str_len)?;
+        let str_len = fp.max_str_len(BlobRegions::Menus, 32);
 
-        let mut menus = HashMap::<u8, MenuIndexEntry>::new();
+        let mut menus = BTreeMap::<u8, MenuIndexEntry>::new();
 
         for entry in tmp_menus {
             let menu_num = entry.0;
@@ -81,76 +87,88 @@ impl MenuIndex {
                         menu_num,
                         caption_off,
                         tooltip_off,
-						32,
+						str_len,
                         param_index,
                         fp
                     ),
             );
         }
 
-        MenuIndex::new(menus)
+        Ok(MenuIndex::new(menus))
     }
 
     ///
     /// Create a MenuIndex from v3 schema
     ///
-    pub fn from_v3(fp: &mut FileBlob, font_family: u8) -> MenuIndex {
-        let num_menus = fp.read_byte(BlobRegions::Menus);
-        let idx_entry_len = fp.read_byte(BlobRegions::Menus);
+    pub fn from_v3(fp: &mut FileBlob, font_family: u8) -> io::Result<MenuIndex> {
+        let index_start = fp.get_pos();
+        let num_menus = fp.read_byte(BlobRegions::Menus)?;
+        fp.check_entries(BlobRegions::Menus, num_menus as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Menus)?;
 
-        let mut menus = HashMap::new();
+        let mut menus = BTreeMap::new();
 
         Self::validate_schema(3, idx_entry_len);
+        fp.add_entry_layout(BlobRegions::Menus, idx_entry_len);
 
-        let tmp_info = Self::read_v3_entries(fp, num_menus);
+        let tmp_info = Self::read_v3_entries(fp, num_menus)?;
+        let index_range = index_start..fp.get_pos();
+        let str_len = fp.max_str_len(BlobRegions::Menus, 32);
 
         for (menu_num, offset) in tmp_info {
+            fp.check_offset_outside(BlobRegions::Parameters, &index_range, offset)?;
             fp.set_pos(offset);
-            let (param_index, caption_off, tooltip_off) = ParameterIndex::from_v3(fp, font_family);
+            let (param_index, caption_off, tooltip_off) = fp.time(BlobRegions::Parameters, |fp| ParameterIndex::from_v3(fp, font_family))?;
             let menu_entry = MenuIndexEntry::new(
                 menu_num,
                 caption_off,
                 tooltip_off,
-				32,
+				str_len,
                 param_index,
                 fp
             );
             menus.insert(menu_num, menu_entry);
         }
-        MenuIndex::new(menus)
+        Ok(MenuIndex::new(menus))
     }
 
     ///
     /// Create a MenuIndex from v4 schema
     ///
-    pub fn from_v4(fp: &mut FileBlob) -> MenuIndex 
+    pub fn from_v4(fp: &mut FileBlob) -> io::Result<MenuIndex> 
 	{
-        let num_menus = fp.read_byte(BlobRegions::Menus);
-        let idx_entry_len = fp.read_byte(BlobRegions::Menus);
+        let index_start = fp.get_pos();
+        let num_menus = fp.read_byte(BlobRegions::Menus)?;
+        fp.check_entries(BlobRegions::Menus, num_menus as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Menus)?;
 
 
-        let mut menus = HashMap::new();
+        let mut menus = BTreeMap::new();
 
         Self::validate_schema(4, idx_entry_len);
+        fp.add_entry_layout(BlobRegions::Menus, idx_entry_len);
 
-        let tmp_info = Self::read_v4_entries(fp, num_menus);
+        let tmp_info = Self::read_v4_entries(fp, num_menus)?;
+        let index_range = index_start..fp.get_pos();
+        let str_len = fp.max_str_len(BlobRegions::Menus, 256);
 
         for (menu_num, caption_off, tooltip_off, offset) in tmp_info {
 //			println!("{} => {}", menu_num, offset);
 
+            fp.check_offset_outside(BlobRegions::Parameters, &index_range, offset)?;
             fp.set_pos(offset);
-            let param_index = ParameterIndex::from_v4(fp);
+            let param_index = fp.time(BlobRegions::Parameters, ParameterIndex::from_v4)?;
             let menu_entry = MenuIndexEntry::new(
                 menu_num,
                 caption_off,
                 tooltip_off,
-				256,
+				str_len,
                 param_index,
                 fp,
             );
             menus.insert(menu_num, menu_entry);
         }
-        MenuIndex::new(menus)
+        Ok(MenuIndex::new(menus))
     }
 
 
@@ -178,28 +196,28 @@ impl MenuIndex {
     ///
     /// Read and return a temp list of V3 menu entries
     ///
-    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u8, u32)> {
+    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8) -> io::Result<Vec<(u8, u32)>> {
         let mut tmp_info = Vec::new();
 
         for i in 0..num_entries {
-            let offset = fp.read_le_3bytes(BlobRegions::Menus);
+            let offset = fp.read_le_3bytes(BlobRegions::Menus)?;
             if offset > 0 {
                 tmp_info.push((i, offset));
             }
         }
-        tmp_info
+        Ok(tmp_info)
     }
 
     ///
     /// Read and return a temp list of V4 menu entries
     ///
-    fn read_v4_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u8, u32, u32, u32)> {
+    fn read_v4_entries(fp: &mut FileBlob, num_entries: u8) -> io::Result<Vec<(u8, u32, u32, u32)>> {
         let mut tmp_info = Vec::new();
 
         for i in 0..num_entries {
-            let caption_off = fp.read_le_3bytes(BlobRegions::Menus);
-            let tooltip_off = fp.read_le_3bytes(BlobRegions::Menus);
-            let offset = fp.read_le_3bytes(BlobRegions::Menus);
+            let caption_off = fp.read_le_3bytes(BlobRegions::Menus)?;
+            let tooltip_off = fp.read_le_3bytes(BlobRegions::Menus)?;
+            let offset = fp.read_le_3bytes(BlobRegions::Menus)?;
             if offset > 0 {
                 tmp_info.push((i, caption_off, tooltip_off, offset));
             } 
@@ -207,31 +225,39 @@ impl MenuIndex {
 //				panic!("Menu has no caption");
 //			}
         }
-        tmp_info
+        Ok(tmp_info)
     }
 
 
     pub fn get_num_menus(&self) -> usize {
         self.menus.len()
     }
+
+    ///
+    /// The entry of menu menu_num, without iterating the index
+    ///
+    pub fn get(&self, menu_num: u8) -> Option<&MenuIndexEntry> {
+        self.menus.get(&menu_num)
+    }
+
+    pub fn contains(&self, menu_num: u8) -> bool {
+        self.menus.contains_key(&menu_num)
+    }
+
+    ///
+    /// Iterate over the menus in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &MenuIndexEntry)> {
+        self.menus.iter().map(|(key, entry)| (*key, entry))
+    }
 }
 
-impl IntoIterator for &MenuIndex {
+impl<'a> IntoIterator for &'a MenuIndex {
     type Item = (u8, MenuIndexEntry);
-    type IntoIter = MenuIndexIterator;
+    type IntoIter = MenuIndexIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut keys = Vec::new();
-        for key in self.menus.keys() {
-            keys.push(*key)
-        }
-        keys.sort();
-        keys.reverse();
-        let mut items = Vec::new();
-        for key in keys {
-            items.push((key, self.menus[&key].clone()));
-        }
-        MenuIndexIterator { items }
+        MenuIndexIterator { items: self.menus.iter() }
     }
 }
 
@@ -246,29 +272,85 @@ impl MenuIndexEntry {
             tooltip_off,
 			str_len,
             param_index: Rc::<ParameterIndex>::new(param_index),
-            blob: fp.freeze(),
+            blob: fp.freeze(BlobRegions::Menus),
         }
     }
  
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
+    pub fn get_caption_off(&self) -> u32 {
+        self.caption_off
+    }
+
+    pub fn get_tooltip_off(&self) -> u32 {
+        self.tooltip_off
+    }
+
     pub fn to_string(&self) -> Result<String, String> 
 	{
-        let str1 = match self.blob.get_string(self.caption_off, self.str_len) {
-            Ok(x) => x,
-            Err(x) => return Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
-        };
-        if self.tooltip_off != 0 {
-            let str2 = match self.blob.get_string(self.tooltip_off, self.str_len) {
-                Ok(x) => x,
-                Err(x) => return Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
-            };
-            return Result::Ok(format!("{} / {}", str1, str2));
+        let str1 = self.caption()?;
+        if self.blob.lazy_tooltips() {
+            return Result::Ok(str1);
+        }
+        if let Some(tooltip) = self.tooltip() {
+            return Result::Ok(format!("{} / {}", str1, tooltip?));
         };
         return Result::Ok(str1);
     }
 
+    pub fn caption(&self) -> Result<String, String> {
+        match self.blob.get_string(self.caption_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
+        }
+    }
+
+    ///
+    /// The tooltip, or None if the entry has no tooltip
+    ///
+    pub fn tooltip(&self) -> Option<Result<String, String>> {
+        if self.tooltip_off == 0 {
+            return None;
+        }
+        Some(match self.blob.get_tooltip(self.tooltip_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
+        })
+    }
+
+    ///
+    /// The caption and tooltip kept apart, unlike to_string
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.caption(), self.tooltip())
+    }
+
     pub fn get_params(&self) -> &ParameterIndex {
         &self.param_index
     }
+
+    ///
+    /// The parameters of the menu as rows of (param number, caption, tooltip),
+    /// a string that fails to decode is replaced by its error
+    ///
+    pub fn to_table(&self) -> Vec<(u8, String, Option<String>)>
+    {
+        let mut rows = Vec::new();
+        for (param, details) in self.get_params() {
+            let caption = match details.caption() {
+                Ok(x) => x,
+                Err(x) => format!("[-- {} --]", x),
+            };
+            let tooltip = details.tooltip().map(|tooltip| match tooltip {
+                Ok(x) => x,
+                Err(x) => format!("[-- {} --]", x),
+            });
+            rows.push((param, caption, tooltip));
+        }
+        rows
+    }
 }
 
 impl PartialEq for MenuIndexEntry {
@@ -292,10 +374,30 @@ impl Clone for MenuIndexEntry
     }
 }
 
-impl Iterator for MenuIndexIterator {
+impl Iterator for MenuIndexIterator<'_> {
     type Item = (u8, MenuIndexEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.pop()
+        self.items.next().map(|(key, entry)| (*key, entry.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    #[test]
+    fn menu_table_with_a_tooltipless_param() {
+        let text = b"\0Menu\0Speed\0Motor speed\0Ramp\0Ramp rate\0Mode\0";
+        let mut fp = blob_from_bytes(text, ParseOptions::default());
+        let params = ParameterIndex::from_offsets(&mut fp, &[(1, 6, 12), (2, 24, 29), (3, 39, 0)]);
+        let menu = MenuIndexEntry::new(5, 1, 0, 32, params, &mut fp);
+        assert_eq!(menu.to_table(), [
+            (1, "Speed".to_string(), Some("Motor speed".to_string())),
+            (2, "Ramp".to_string(), Some("Ramp rate".to_string())),
+            (3, "Mode".to_string(), None),
+        ]);
     }
 }
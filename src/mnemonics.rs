@@ -1,6 +1,14 @@
 use std::collections::HashMap;
+use std::io;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
+use crate::diagnostics::Severity;
+
+///
+/// Largest plausible number of mnemonics for one parameter, a bigger
+/// count is taken as a misread index header
+///
+pub const DEFAULT_MAX_MNEMONICS: u16 = 1024;
 
 pub struct MnemonicIndex 
 {
@@ -12,6 +20,7 @@ pub struct MnemonicIndexEntry
     value : i32,
     caption_off: u32,
     tooltip_off: u32,
+    str_len: u16,
     blob: RawBlob,
 }
 
@@ -45,32 +54,55 @@ impl MnemonicIndex
     ///
     /// Read and create a V4 MnemonicIndex.
     ///
-    pub fn from(fp: &mut FileBlob) -> MnemonicIndex 
+    pub fn from(fp: &mut FileBlob) -> io::Result<MnemonicIndex> 
     {
-        let num_entries = fp.read_le_2bytes(BlobRegions::Mnemonics);
-        let idx_entry_len = fp.read_byte(BlobRegions::Mnemonics);
+        let num_entries = fp.read_le_2bytes(BlobRegions::Mnemonics)?;
+        fp.check_entries(BlobRegions::Mnemonics, num_entries as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Mnemonics)?;
 
 //		println!("Number of entries {} size {}", num_entries, idx_entry_len);
 
         let mut values = HashMap::new();
 
         Self::validate_schema(4, idx_entry_len, num_entries);
+        fp.add_entry_layout(BlobRegions::Mnemonics, idx_entry_len);
+
+        // Check the count before trusting it to read the entries
+        let max_mnemonics = fp.max_mnemonics(DEFAULT_MAX_MNEMONICS);
+        if num_entries > max_mnemonics {
+            panic!("Implausible number of mnemonics {} > {}", num_entries, max_mnemonics);
+        }
+        if (num_entries as u32) * (idx_entry_len as u32) > fp.get_remaining() {
+            panic!("Mnemonic index of {} entries runs past the end of the file", num_entries);
+        }
 
 		if idx_entry_len != 0 {
 
+            let index_start = fp.get_pos();
+            let str_len = fp.max_str_len(BlobRegions::Mnemonics, 256);
+            let mut skipped = 0;
             for _i in 0..num_entries {
-                let (value, entry) = MnemonicIndexEntry::load(fp);
+                let (value, entry) = MnemonicIndexEntry::load(fp, str_len)?;
 //				println!("{} => {}", value, &entry.to_string().unwrap());
 
+                // Reserved slots have no caption
+                if entry.caption_off == 0 && fp.skip_empty_mnemonics() {
+                    skipped += 1;
+                    continue;
+                }
+
                 let old = values.insert(value, entry);
                 if old != None {
                     panic!("Two entries with same mnemonic! item={}", value);
                 }
             }
+            if skipped > 0 {
+                fp.add_diagnostic(Severity::Info, BlobRegions::Mnemonics, index_start, &format!("Skipped {} mnemonics with no caption", skipped));
+            }
 
-            MnemonicIndex::new(values)
+            Ok(MnemonicIndex::new(values))
         } else {
-            MnemonicIndex::new(values)
+            Ok(MnemonicIndex::new(values))
         }
     }
 
@@ -93,6 +125,15 @@ impl MnemonicIndex
     pub fn get_num_values(&self) -> usize {
         self.values.len()
     }
+
+    ///
+    /// Iterate over the mnemonics in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &MnemonicIndexEntry)> {
+        let mut keys: Vec<i32> = self.values.keys().copied().collect();
+        keys.sort();
+        keys.into_iter().map(move |key| (key, &self.values[&key]))
+    }
 }
 
 impl Clone for MnemonicIndex
@@ -129,11 +170,12 @@ impl IntoIterator for &MnemonicIndex
 
 impl MnemonicIndexEntry 
 {
-    fn load(fp: &mut FileBlob) -> (i32, MnemonicIndexEntry) 
+    fn load(fp: &mut FileBlob, str_len: u16) -> io::Result<(i32, MnemonicIndexEntry)> 
     {
-        let value = fp.read_le_4bytes(BlobRegions::Mnemonics);
-        let caption_off = fp.read_le_3bytes(BlobRegions::Mnemonics);
-        let tooltip_off = fp.read_le_3bytes(BlobRegions::Mnemonics);
+        let location = fp.get_pos();
+        let value = fp.read_le_4bytes(BlobRegions::Mnemonics)?;
+        let caption_off = fp.read_le_3bytes(BlobRegions::Mnemonics)?;
+        let tooltip_off = fp.read_le_3bytes(BlobRegions::Mnemonics)?;
 
         let value : i32 = if value > 0x7FFFFFF {
             -((0xFFFFFFFF - (value - 1)) as i32)
@@ -144,32 +186,68 @@ impl MnemonicIndexEntry
 //		println!("{} => {} {} {}", param, caption_off, tooltip_off, mnemonic_off);
 
         if caption_off == 0 {
-            println!("Empty parameter?");
+            fp.add_diagnostic(Severity::Info, BlobRegions::Mnemonics, location, &format!("Empty parameter? value {}", value));
         };
         let entry = MnemonicIndexEntry {
             value,
             caption_off: caption_off,
             tooltip_off: tooltip_off,
-            blob: fp.freeze(),
+            str_len,
+            blob: fp.freeze(BlobRegions::Mnemonics),
         };
-        (value, entry)
+        Ok((value, entry))
     }
 
 
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
+    pub fn get_caption_off(&self) -> u32 {
+        self.caption_off
+    }
+
+    pub fn get_tooltip_off(&self) -> u32 {
+        self.tooltip_off
+    }
+
     pub fn to_string(&self) -> Result<String, String> {
-        let str1 = match self.blob.get_string(self.caption_off, 256) {
-            Ok(x) => x,
-            Err(x) => return Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
-        };
-        if self.tooltip_off != 0 {
-            let str2 = match self.blob.get_string(self.tooltip_off, 256) {
-                Ok(x) => x,
-                Err(x) => return Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
-            };
-            return Result::Ok(format!("{} / {}", str1, str2));
+        let str1 = self.caption()?;
+        if self.blob.lazy_tooltips() {
+            return Result::Ok(str1);
+        }
+        if let Some(tooltip) = self.tooltip() {
+            return Result::Ok(format!("{} / {}", str1, tooltip?));
         };
         return Result::Ok(str1);
     }
+
+    pub fn caption(&self) -> Result<String, String> {
+        match self.blob.get_string(self.caption_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
+        }
+    }
+
+    ///
+    /// The tooltip, or None if the entry has no tooltip
+    ///
+    pub fn tooltip(&self) -> Option<Result<String, String>> {
+        if self.tooltip_off == 0 {
+            return None;
+        }
+        Some(match self.blob.get_tooltip(self.tooltip_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
+        })
+    }
+
+    ///
+    /// The caption and tooltip kept apart, unlike to_string
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.caption(), self.tooltip())
+    }
 }
 
 impl PartialEq for MnemonicIndexEntry {
@@ -184,6 +262,7 @@ impl Clone for MnemonicIndexEntry {
             value : self.value,
             caption_off: self.caption_off,
             tooltip_off: self.tooltip_off,
+            str_len: self.str_len,
             blob: self.blob.clone(),
         }
     }
@@ -197,3 +276,61 @@ impl Iterator for MnemonicIndexIterator
         self.values.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    ///
+    /// A V4 mnemonic header claiming 0xFFFF entries of 8 bytes, with
+    /// no entries after it
+    ///
+    const HUGE_HEADER: [u8; 3] = [0xFF, 0xFF, 8];
+
+    ///
+    /// A V4 mnemonic index of value 1 captioned "One" and value 2 with
+    /// no caption
+    ///
+    fn load_with_empty(options: ParseOptions) -> (MnemonicIndex, Vec<String>) {
+        let mut data = vec![2, 0, 8];
+        data.extend([1, 0, 0, 0, 23, 0, 0, 0, 0, 0]);
+        data.extend([2, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend(b"One\0");
+        let mut fp = blob_from_bytes(&data, options);
+        let index = MnemonicIndex::from(&mut fp).unwrap();
+        let messages = fp.get_diagnostics().into_iter().map(|x| x.message).collect();
+        (index, messages)
+    }
+
+    #[test]
+    fn zero_caption_is_kept_or_skipped() {
+        let (index, messages) = load_with_empty(ParseOptions::default());
+        let values: Vec<i32> = index.iter().map(|(value, _)| value).collect();
+        assert_eq!(values, [1, 2]);
+        assert_eq!(messages, ["Empty parameter? value 2"]);
+
+        let options = ParseOptions { skip_empty_mnemonics: true, ..ParseOptions::default() };
+        let (index, messages) = load_with_empty(options);
+        let values: Vec<i32> = index.iter().map(|(value, _)| value).collect();
+        assert_eq!(values, [1]);
+        assert_eq!(index.iter().next().unwrap().1.caption(), Ok("One".to_string()));
+        assert_eq!(messages, ["Empty parameter? value 2", "Skipped 1 mnemonics with no caption"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Implausible number of mnemonics 65535 > 1024")]
+    fn huge_count_is_implausible() {
+        let mut fp = blob_from_bytes(&HUGE_HEADER, ParseOptions::default());
+        let _ = MnemonicIndex::from(&mut fp);
+    }
+
+    #[test]
+    #[should_panic(expected = "Mnemonic index of 65535 entries runs past the end of the file")]
+    fn huge_count_runs_past_end() {
+        let options = ParseOptions { max_mnemonics: Some(0xFFFF), ..ParseOptions::default() };
+        let mut fp = blob_from_bytes(&HUGE_HEADER, options);
+        let _ = MnemonicIndex::from(&mut fp);
+    }
+}
@@ -1,12 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
 use std::rc::Rc;
 
 use crate::blob::{FileBlob, BlobRegions};
 use crate::menus::MenuIndex;
 
+///
+/// Most modes a product has on current drives
+///
+pub const DEFAULT_MAX_MODES: u8 = 4;
+
 pub struct ModeIndex
 {
-    modes: HashMap<u8, ModeIndexEntry>,
+    modes: BTreeMap<u8, ModeIndexEntry>,
 }
 
 pub struct ModeIndexEntry 
@@ -15,15 +22,19 @@ pub struct ModeIndexEntry
     menu_index: Rc<MenuIndex>,
 }
 
-pub struct ModeIndexIterator 
+pub struct ModeIndexIterator<'a> 
 {
-    items: Vec<(u8, ModeIndexEntry)>,
+    items: btree_map::Iter<'a, u8, ModeIndexEntry>,
 }
 
 
 impl ModeIndex 
 {
-    pub fn new(modes: HashMap<u8, ModeIndexEntry>) -> ModeIndex 
+    pub fn empty() -> ModeIndex {
+        ModeIndex::new(BTreeMap::new())
+    }
+
+    pub fn new(modes: BTreeMap<u8, ModeIndexEntry>) -> ModeIndex 
     {
         let mut hits = HashSet::new();
 
@@ -40,32 +51,37 @@ impl ModeIndex
         ModeIndex { modes }
     }
 
-    pub fn create_from_file(fp: &mut FileBlob, schema: u16, font_family: u8) -> ModeIndex 
+    pub fn create_from_file(fp: &mut FileBlob, schema: u16, font_family: u8) -> io::Result<ModeIndex> 
     {
-        let num_modes = fp.read_byte(BlobRegions::Modes);
-        let idx_entry_len = fp.read_byte(BlobRegions::Modes);
+        let index_start = fp.get_pos();
+        let num_modes = fp.read_byte(BlobRegions::Modes)?;
+        fp.check_entries(BlobRegions::Modes, num_modes as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Modes)?;
 
-        Self::validate_schema(schema, idx_entry_len, num_modes);
+        Self::validate_schema(schema, idx_entry_len, num_modes, fp.max_modes(DEFAULT_MAX_MODES));
+        fp.add_entry_layout(BlobRegions::Modes, idx_entry_len);
 
         let tmp_info = match schema {
-            2 => Self::read_v2_entries(fp, num_modes),
-            3 => Self::read_v3_entries(fp, num_modes),
-            4 => Self::read_v3_entries(fp, num_modes),
+            2 => Self::read_v2_entries(fp, num_modes)?,
+            3 => Self::read_v3_entries(fp, num_modes)?,
+            4 => Self::read_v3_entries(fp, num_modes)?,
             _ => panic!("Invalid format"),
         };
+        let index_range = index_start..fp.get_pos();
 
-        let mut modes = HashMap::new();
+        let mut modes = BTreeMap::new();
         
         for (mode_num, offset) in tmp_info {
             if offset != 0 {
+                fp.check_offset_outside(BlobRegions::Menus, &index_range, offset)?;
                 fp.set_pos(offset);
 
-                let menu_index = match schema {
+                let menu_index = fp.time(BlobRegions::Menus, |fp| match schema {
                     2 => MenuIndex::from_v2(fp, font_family),
                     3 => MenuIndex::from_v3(fp, font_family),
                     4 => MenuIndex::from_v4(fp),
                     _ => panic!("Invalid format")
-                };
+                })?;
                 modes.insert(
                     mode_num,
                     ModeIndexEntry::new(mode_num, menu_index)
@@ -74,7 +90,7 @@ impl ModeIndex
                 panic!("Unexpected empty mode");
             }
         }
-        ModeIndex::new(modes)
+        Ok(ModeIndex::new(modes))
     }
 
     pub fn get_num_modes(&self) -> usize
@@ -82,7 +98,20 @@ impl ModeIndex
         self.modes.len()
     }
 
-    fn validate_schema(schema: u16, idx_entry_len: u8, num_modes: u8) 
+    ///
+    /// The entry of mode mode_num, without iterating the index
+    ///
+    pub fn get(&self, mode_num: u8) -> Option<&ModeIndexEntry>
+    {
+        self.modes.get(&mode_num)
+    }
+
+    pub fn contains(&self, mode_num: u8) -> bool
+    {
+        self.modes.contains_key(&mode_num)
+    }
+
+    fn validate_schema(schema: u16, idx_entry_len: u8, num_modes: u8, max_modes: u8) 
     {
         match schema {
             2 => {
@@ -105,37 +134,42 @@ impl ModeIndex
         if num_modes < 1 {
             panic!("Too few modes");
         }
-        if num_modes > 4 {
-            panic!("Too many modes");
+        if num_modes > max_modes {
+            panic!("Too many modes {} > {}", num_modes, max_modes);
         }
     }
 
-    fn read_v2_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u8, u32)> {
+    fn read_v2_entries(fp: &mut FileBlob, num_entries: u8) -> io::Result<Vec<(u8, u32)>> {
         let mut tmp_info = Vec::new();
+        let mut any_mode = false;
 
         for i in 0..num_entries {
-            let mode_num = fp.read_byte(BlobRegions::Modes);
+            let mode_num = fp.read_byte(BlobRegions::Modes)?;
             if num_entries > 1 {
-                if mode_num != i + 1 {
-                    panic!("Out of seq mode numbers {} != {}", mode_num, i);
+                // An "Any" mode 0 can precede the numbered modes
+                let expected = if any_mode { i } else { i + 1 };
+                if i == 0 && mode_num == 0 {
+                    any_mode = true;
+                } else if mode_num != expected {
+                    panic!("Out of seq mode numbers {} != {}", mode_num, expected);
                 }
             } else if mode_num != 0 && mode_num != 1 {
                 panic!("Invalid mode_num {}", mode_num);
             }
-            let offset = fp.read_le_4bytes(BlobRegions::Modes);
+            let offset = fp.read_le_4bytes(BlobRegions::Modes)?;
             if offset == 0 {
                 panic!("Offset is zero")
             };
             tmp_info.push((mode_num, offset))
         }
-        tmp_info
+        Ok(tmp_info)
     }
 
-    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u8, u32)> {
+    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8) -> io::Result<Vec<(u8, u32)>> {
         let mut tmp_info = Vec::new();
 
         for i in 0..num_entries {
-            let offset = fp.read_le_3bytes(BlobRegions::Modes);
+            let offset = fp.read_le_3bytes(BlobRegions::Modes)?;
             let mode_num = if num_entries == 1 {
                 if offset == 0 {
                     panic!("Offset is zero")
@@ -148,27 +182,24 @@ impl ModeIndex
                 tmp_info.push((mode_num, offset));
             }
         }
-        tmp_info
+        Ok(tmp_info)
+    }
+
+    ///
+    /// Iterate over the modes in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &ModeIndexEntry)> {
+        self.modes.iter().map(|(key, entry)| (*key, entry))
     }
 }
 
-impl IntoIterator for &ModeIndex 
+impl<'a> IntoIterator for &'a ModeIndex 
 {
     type Item = (u8, ModeIndexEntry);
-    type IntoIter = ModeIndexIterator;
+    type IntoIter = ModeIndexIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut keys = Vec::new();
-        for key in self.modes.keys() {
-            keys.push(*key)
-        }
-        keys.sort();
-        keys.reverse();
-        let mut items = Vec::new();
-        for key in keys {
-            items.push((key, self.modes[&key].clone()));
-        }
-        ModeIndexIterator { items }
+        ModeIndexIterator { items: self.modes.iter() }
     }
 }
 
@@ -184,16 +215,18 @@ impl ModeIndexEntry
     }
 
     pub fn to_string(&self, mode: u8) -> Result<String, String> {
+        // Newer platforms may have modes beyond the known names
+        let name = match mode {
+            0 => "Any".to_string(),
+            1 => "Open Loop".to_string(),
+            2 => "RFC-A".to_string(),
+            3 => "RFC-S".to_string(),
+            4 => "Regen".to_string(),
+            _ => format!("Mode {}", mode),
+        };
         Result::Ok(format!(
             "Mode '{}' num of menus = {}",
-            match mode {
-                0 => "Any",
-                1 => "Open Loop",
-                2 => "RFC-A",
-                3 => "RFC-S",
-                4 => "Regen",
-                _ => panic!("Unknown mode"),
-            },
+            name,
             self.menu_index.get_num_menus()
         ))
     }
@@ -201,6 +234,13 @@ impl ModeIndexEntry
     pub fn get_menus(&self) -> &MenuIndex {
         &self.menu_index
     }
+
+    ///
+    /// Mode 0 is "Any", i.e. the menus apply in all modes
+    ///
+    pub fn is_any(&self) -> bool {
+        self.mode_num == 0
+    }
 }
 
 impl Clone for ModeIndexEntry {
@@ -212,10 +252,85 @@ impl Clone for ModeIndexEntry {
     }
 }
 
-impl Iterator for ModeIndexIterator {
+impl Iterator for ModeIndexIterator<'_> {
     type Item = (u8, ModeIndexEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.pop()
+        self.items.next().map(|(key, entry)| (*key, entry.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    ///
+    /// The mode numbers of a V2 mode index of the given mode numbers
+    ///
+    fn v2_mode_nums(mode_nums: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for mode_num in mode_nums {
+            data.push(*mode_num);
+            data.extend(100u32.to_le_bytes());
+        }
+        let mut fp = blob_from_bytes(&data, ParseOptions::default());
+        let entries = ModeIndex::read_v2_entries(&mut fp, mode_nums.len() as u8).unwrap();
+        entries.into_iter().map(|(mode_num, _)| mode_num).collect()
+    }
+
+    #[test]
+    fn single_mode_is_any_or_one() {
+        assert_eq!(v2_mode_nums(&[0]), [0]);
+        assert_eq!(v2_mode_nums(&[1]), [1]);
+    }
+
+    #[test]
+    fn numbered_modes() {
+        assert_eq!(v2_mode_nums(&[1, 2, 3]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn any_mode_before_numbered_modes() {
+        assert_eq!(v2_mode_nums(&[0, 1, 2]), [0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of seq mode numbers 3 != 2")]
+    fn gap_in_numbered_modes() {
+        v2_mode_nums(&[0, 1, 3]);
+    }
+
+    ///
+    /// A V3 mode index of five modes, all with the empty menu index after it
+    ///
+    fn five_modes(options: ParseOptions) -> io::Result<ModeIndex> {
+        let mut data = vec![5, 3];
+        for _ in 0..5 {
+            data.extend([17, 0, 0]);
+        }
+        data.extend([0, 3]);
+        let mut fp = blob_from_bytes(&data, options);
+        ModeIndex::create_from_file(&mut fp, 3, 0)
+    }
+
+    #[test]
+    fn five_modes_with_a_raised_limit() {
+        let index = five_modes(ParseOptions { max_modes: Some(5), ..ParseOptions::default() }).unwrap();
+        let mode_nums: Vec<u8> = index.iter().map(|(mode_num, _)| mode_num).collect();
+        assert_eq!(mode_nums, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many modes 5 > 4")]
+    fn five_modes_over_the_default_limit() {
+        let _ = five_modes(ParseOptions::default());
+    }
+
+    #[test]
+    fn any_mode_is_mode_zero() {
+        assert!(ModeIndexEntry::new(0, MenuIndex::new(BTreeMap::new())).is_any());
+        assert!(!ModeIndexEntry::new(1, MenuIndex::new(BTreeMap::new())).is_any());
     }
 }
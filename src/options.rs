@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::blob::BlobRegions;
+use crate::fonts::FontIndex;
+use crate::normalize::Normalization;
+
+///
+/// Options controlling how a Language file is parsed
+///
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    pub timing: bool, // Record the time taken parsing each region
+    pub stats_on_drop: bool, // Print the blob stats once the file is parsed
+    pub max_str_lens: HashMap<BlobRegions, u16>, // Overrides the schema's max string length
+    pub utf8_regions: HashMap<BlobRegions, bool>, // Overrides whether the strings of a region are UTF-8
+    pub repair_unterminated: bool, // Cut a string with no NUL at the first non-character byte
+    pub normalization: Option<Normalization>, // Normalize the decoded strings
+    pub max_mnemonics: Option<u16>, // Overrides DEFAULT_MAX_MNEMONICS
+    pub max_modes: Option<u8>, // Overrides DEFAULT_MAX_MODES
+    pub skip_empty_mnemonics: bool, // Drop, rather than keep, mnemonics with no caption
+    pub force_schema: Option<u16>, // Parse as this schema, ignoring a damaged header
+    pub lazy_tooltips: bool, // to_string gives just the caption, tooltip() decodes on demand
+    pub lenient_entry_len: bool, // Accept longer product index entries, skipping the extra bytes
+    pub skip_crc_check: bool, // Load a file whose length or CRC does not match the header, to inspect a damaged file
+    pub limits: ParseLimits,
+}
+
+///
+/// Bounds on the work done parsing, so that a corrupt (or fuzzed) file
+/// fails quickly instead of allocating for huge counts. The defaults
+/// are well above any real file.
+///
+#[derive(Clone)]
+pub struct ParseLimits {
+    pub max_entries: u32, // Most entries in any one index table
+    pub max_total_entries: u32, // Most entries over all the index tables
+    pub max_offsets: u32, // Most distinct string offsets decoded
+}
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits {
+            max_entries: 65536,
+            max_total_entries: 4_000_000,
+            max_offsets: 4_000_000,
+        }
+    }
+}
+
+///
+/// Options controlling the text dump of a Language
+///
+#[derive(Clone, Default)]
+pub struct DumpOptions {
+    pub include_offsets: bool, // Annotate each line with its caption (and tooltip) offsets
+    pub caption_width: Option<CaptionWidthCheck>, // Flag menu, parameter and mnemonic captions too wide to display
+}
+
+///
+/// The font captions are displayed in, and the most pixels a line of
+/// a caption can take
+///
+#[derive(Clone)]
+pub struct CaptionWidthCheck {
+    pub fonts: Rc<FontIndex>,
+    pub char_map: u8,
+    pub font_family: u8,
+    pub max_width: u32,
+}
@@ -1,11 +1,14 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
+use crate::diagnostics::Severity;
 use crate::mnemonics::MnemonicIndex;
 use std::rc::Rc;
 
 pub struct ParameterIndex {
-    params: HashMap<u8, ParameterIndexEntry>,
+    params: BTreeMap<u8, ParameterIndexEntry>,
 }
 
 pub struct ParameterIndexEntry {
@@ -13,17 +16,18 @@ pub struct ParameterIndexEntry {
     caption_off: u32,
     tooltip_off: u32,
 	str_len: u16,
+    units_ref: Option<u16>, // None as the V2, V3 and V4 entries have no unit id
     mnemonic: Rc<MnemonicIndex>,
     blob: RawBlob,
 }
 
-pub struct ParameterIndexIterator {
-    items: Vec<(u8, ParameterIndexEntry)>,
+pub struct ParameterIndexIterator<'a> {
+    items: btree_map::Iter<'a, u8, ParameterIndexEntry>,
 }
 
 impl ParameterIndex {
 
-    pub fn new(params: HashMap<u8, ParameterIndexEntry>) -> ParameterIndex
+    pub fn new(params: BTreeMap<u8, ParameterIndexEntry>) -> ParameterIndex
     {
         let mut hits = HashSet::<u8>::new();
 
@@ -45,15 +49,15 @@ impl ParameterIndex {
     /// So read all parameters, create parameter indexes (as if we were V3 format)
     /// And return a parameter index per menu
     ///
-    pub fn read_v2_entries(fp: &mut FileBlob, num_entries: u16) -> HashMap<u8, ParameterIndex> 
+    pub fn read_v2_entries(fp: &mut FileBlob, num_entries: u16, str_len: u16) -> io::Result<HashMap<u8, ParameterIndex>> 
     {
         let mut tmp_menus = HashMap::<u8, ParameterIndex>::new();
 
         for _i in 0..num_entries {
-            let (menu, param, entry) = ParameterIndexEntry::load_v2(fp);
+            let (menu, param, entry) = ParameterIndexEntry::load_v2(fp, str_len)?;
             match tmp_menus.get_mut(&menu) {
                 None => {
-                    let params = HashMap::<u8, ParameterIndexEntry>::new();
+                    let params = BTreeMap::<u8, ParameterIndexEntry>::new();
                     let mut new = ParameterIndex { params };
                     new.params.insert(param, entry);
                     tmp_menus.insert(menu, new);
@@ -63,7 +67,7 @@ impl ParameterIndex {
                 }
             };
         }
-        tmp_menus
+        Ok(tmp_menus)
     }
 
     ///
@@ -71,57 +75,66 @@ impl ParameterIndex {
     /// check and remove parameter 255 which is a placeholder
     /// for menu caption Id
     ///
-    pub fn from_v3(fp: &mut FileBlob, root_font_family: u8) -> (ParameterIndex, u32, u32) {
-        let num_entries = fp.read_le_2bytes(BlobRegions::Parameters);
-        let max_str_len = fp.read_le_2bytes(BlobRegions::Parameters);
-        let font_family = fp.read_byte(BlobRegions::Parameters);
-        let idx_entry_len = fp.read_byte(BlobRegions::Parameters);
+    pub fn from_v3(fp: &mut FileBlob, root_font_family: u8) -> io::Result<(ParameterIndex, u32, u32)> {
+        let num_entries = fp.read_le_2bytes(BlobRegions::Parameters)?;
+        fp.check_entries(BlobRegions::Parameters, num_entries as u32)?;
+        let max_str_len = fp.read_le_2bytes(BlobRegions::Parameters)?;
+        let font_family = fp.read_byte(BlobRegions::Parameters)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Parameters)?;
 
         if root_font_family != font_family {
             panic!("Mis-match font_family");
         }
-        let mut params = HashMap::new();
+        let mut params = BTreeMap::new();
 
-        Self::validate_schema(3, idx_entry_len, num_entries, max_str_len);
+        let str_len = fp.max_str_len(BlobRegions::Parameters, 32);
+        Self::validate_schema(3, idx_entry_len, num_entries, max_str_len, str_len);
+        fp.add_entry_layout(BlobRegions::Parameters, idx_entry_len);
 
         if idx_entry_len != 0 {
 
             for _i in 0..num_entries {
-                let (param, entry) = ParameterIndexEntry::load_v3(fp);
+                let (param, entry) = ParameterIndexEntry::load_v3(fp, str_len)?;
                 params.insert(param, entry);
             }
 
             let (caption_off, tooltip_off) = Self::check_param255(&mut params);
             let param_index = ParameterIndex { params };
-            (param_index, caption_off, tooltip_off)
+            Ok((param_index, caption_off, tooltip_off))
         } else {
-            (ParameterIndex::new(params), 0, 0)
+            Ok((ParameterIndex::new(params), 0, 0))
         }
     }
 
     ///
     /// Read and create a V4 ParameterIndex.
     ///
-    pub fn from_v4(fp: &mut FileBlob) -> ParameterIndex 
+    pub fn from_v4(fp: &mut FileBlob) -> io::Result<ParameterIndex> 
 	{
-        let num_params = fp.read_byte(BlobRegions::Parameters);
-        let idx_entry_len = fp.read_byte(BlobRegions::Parameters);
+        let index_start = fp.get_pos();
+        let num_params = fp.read_byte(BlobRegions::Parameters)?;
+        fp.check_entries(BlobRegions::Parameters, num_params as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Parameters)?;
 
 //		println!("Number of entries {} size {}", num_entries, idx_entry_len);
 
-        let mut params = HashMap::new();
+        let mut params = BTreeMap::new();
         
-        Self::validate_schema(4, idx_entry_len, num_params as u16, 256);
+        let str_len = fp.max_str_len(BlobRegions::Parameters, 256);
+        Self::validate_schema(4, idx_entry_len, num_params as u16, str_len, str_len);
+        fp.add_entry_layout(BlobRegions::Parameters, idx_entry_len);
 
         if idx_entry_len != 0 {
 
-            let tmp_info = Self::read_v4_entries(fp, num_params);
+            let tmp_info = Self::read_v4_entries(fp, num_params)?;
+            let index_range = index_start..fp.get_pos();
 
             for (param, caption_off, tooltip_off, mnemonic_off) in tmp_info {
 
                 let mnemonic = if mnemonic_off > 0 {
+                    fp.check_offset_outside(BlobRegions::Mnemonics, &index_range, mnemonic_off)?;
                     fp.set_pos(mnemonic_off);
-                    MnemonicIndex::from(fp)
+                    fp.time(BlobRegions::Mnemonics, MnemonicIndex::from)?
                 } else {
                     MnemonicIndex::empty()
                 };
@@ -129,13 +142,13 @@ impl ParameterIndex {
 //				println!("{}", param);
 
                 params.insert(param, ParameterIndexEntry::new(
-                    param, caption_off, tooltip_off, 256,
+                    param, caption_off, tooltip_off, str_len,
                     mnemonic, fp));
             }
 
-            ParameterIndex::new(params)
+            Ok(ParameterIndex::new(params))
         } else {
-            ParameterIndex::new(params)
+            Ok(ParameterIndex::new(params))
         }
     }
 
@@ -143,7 +156,7 @@ impl ParameterIndex {
     ///
     /// Parameter 255 is a fake parameter used to hold menu caption & tooltip
     ///
-    fn check_param255(params: &mut HashMap<u8, ParameterIndexEntry>) -> (u32, u32) 
+    fn check_param255(params: &mut BTreeMap<u8, ParameterIndexEntry>) -> (u32, u32) 
 	{
         let fake_param = match params.remove(&255) {
             Some(param) => param,
@@ -156,22 +169,12 @@ impl ParameterIndex {
         ParameterIndex::check_param255(&mut self.params)
     }
 
-    pub fn validate_schema(schema: u16, idx_entry_len: u8, num_entries: u16, max_str_len: u16) 
+    pub fn validate_schema(schema: u16, idx_entry_len: u8, num_entries: u16, max_str_len: u16, req_str_len: u16) 
 	{
-		let mut req_str_len = 32;
-		let req_idx_entry_len;
-
-        match schema {
-            2 => {
-				req_idx_entry_len = 6;
-            },
-            3 => {
-				req_idx_entry_len = 5;
-            },
-            4 => {
-				req_idx_entry_len = 10;
-				req_str_len = 256;
-            }
+		let req_idx_entry_len = match schema {
+            2 => 6,
+            3 => 5,
+            4 => 10,
             _ => panic!("Invalid format"),
         };
                 
@@ -193,42 +196,50 @@ impl ParameterIndex {
     pub fn get_num_params(&self) -> usize {
         self.params.len()
     }
+
+    ///
+    /// The entry of parameter param_num, without iterating the index
+    ///
+    pub fn get(&self, param_num: u8) -> Option<&ParameterIndexEntry> {
+        self.params.get(&param_num)
+    }
+
+    pub fn contains(&self, param_num: u8) -> bool {
+        self.params.contains_key(&param_num)
+    }
     
-    fn read_v4_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u8, u32, u32, u32)> 
+    fn read_v4_entries(fp: &mut FileBlob, num_entries: u8) -> io::Result<Vec<(u8, u32, u32, u32)>> 
 	{
         let mut tmp_info = Vec::new();
 
         for _i in 0..num_entries {
-            let param = fp.read_byte(BlobRegions::Parameters);
-            let caption_off = fp.read_le_3bytes(BlobRegions::Menus);
-            let tooltip_off = fp.read_le_3bytes(BlobRegions::Menus);
-            let mnemonic_off = fp.read_le_3bytes(BlobRegions::Menus);
+            let param = fp.read_byte(BlobRegions::Parameters)?;
+            let caption_off = fp.read_le_3bytes(BlobRegions::Menus)?;
+            let tooltip_off = fp.read_le_3bytes(BlobRegions::Menus)?;
+            let mnemonic_off = fp.read_le_3bytes(BlobRegions::Menus)?;
             if caption_off > 0 {
                 tmp_info.push((param, caption_off, tooltip_off, mnemonic_off));
             } else {
 				panic!("Caption offset is zero");
 			}
         }
-        tmp_info
+        Ok(tmp_info)
+    }
+
+    ///
+    /// Iterate over the parameters in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &ParameterIndexEntry)> {
+        self.params.iter().map(|(key, entry)| (*key, entry))
     }
 }
 
-impl IntoIterator for &ParameterIndex {
+impl<'a> IntoIterator for &'a ParameterIndex {
     type Item = (u8, ParameterIndexEntry);
-    type IntoIter = ParameterIndexIterator;
+    type IntoIter = ParameterIndexIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut keys = Vec::new();
-        for key in self.params.keys() {
-            keys.push(*key)
-        }
-        keys.sort();
-        keys.reverse();
-        let mut items = Vec::new();
-        for key in keys {
-            items.push((key, self.params[&key].clone()));
-        }
-        ParameterIndexIterator { items }
+        ParameterIndexIterator { items: self.params.iter() }
     }
 }
 
@@ -242,59 +253,136 @@ impl ParameterIndexEntry {
             caption_off: caption_off,
             tooltip_off: tooltip_off,
 			str_len : str_len,
+            units_ref: None,
             mnemonic : Rc::new(mnemonic),
-            blob: fp.freeze()
+            blob: fp.freeze(BlobRegions::Parameters)
         }
     }
 
-    fn load_v3(fp: &mut FileBlob) -> (u8, ParameterIndexEntry) 
+    fn load_v3(fp: &mut FileBlob, str_len: u16) -> io::Result<(u8, ParameterIndexEntry)> 
 	{
-        let param = fp.read_le_2bytes(BlobRegions::Parameters);
+        let location = fp.get_pos();
+        let param = fp.read_le_2bytes(BlobRegions::Parameters)?;
         if param > 255  {
             panic!("Out of range param {}", param);
         };
-        let offset = fp.read_le_3bytes(BlobRegions::Parameters);
+        let offset = fp.read_le_3bytes(BlobRegions::Parameters)?;
         if offset == 0 {
-            println!("Empty slot");
+            fp.add_diagnostic(Severity::Info, BlobRegions::Parameters, location, &format!("Empty slot for param {}", param));
         };
         let param_entry = ParameterIndexEntry::new(
-            param as u8, offset, 0, 32,
+            param as u8, offset, 0, str_len,
             MnemonicIndex::empty(), fp
         );
-        (param as u8, param_entry)
+        Ok((param as u8, param_entry))
     }
 
-    fn load_v2(fp: &mut FileBlob) -> (u8, u8, ParameterIndexEntry) {
-        let param = fp.read_byte(BlobRegions::Parameters);
-        let menu = fp.read_byte(BlobRegions::Parameters);
-        let offset = fp.read_le_4bytes(BlobRegions::Parameters);
+    fn load_v2(fp: &mut FileBlob, str_len: u16) -> io::Result<(u8, u8, ParameterIndexEntry)> {
+        let param = fp.read_byte(BlobRegions::Parameters)?;
+        let menu = fp.read_byte(BlobRegions::Parameters)?;
+        let offset = fp.read_le_4bytes(BlobRegions::Parameters)?;
         let param_entry = ParameterIndexEntry::new(
-            param, offset, 0, 32,
+            param, offset, 0, str_len,
             MnemonicIndex::empty(),
             fp
         );
-        (menu, param, param_entry)
+        Ok((menu, param, param_entry))
+    }
+
+    pub fn param_num(&self) -> u8 {
+        self.param_num
+    }
+
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
+    ///
+    /// The id of the unit the value is displayed in, if the schema has one
+    ///
+    pub fn get_units_ref(&self) -> Option<u16> {
+        self.units_ref
+    }
+
+    pub fn get_caption_off(&self) -> u32 {
+        self.caption_off
+    }
+
+    pub fn get_tooltip_off(&self) -> u32 {
+        self.tooltip_off
     }
 
     pub fn to_string(&self) -> Result<String, String> {
-        let str1 = match self.blob.get_string(self.caption_off, self.str_len) {
-            Ok(x) => x,
-            Err(x) => return Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
-        };
-        if self.tooltip_off != 0 {
-            let str2 = match self.blob.get_string(self.tooltip_off, self.str_len) {
-                Ok(x) => x,
-                Err(x) => return Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
-            };
-            return Result::Ok(format!("{} / {}", str1, str2));
+        let str1 = self.caption()?;
+        if self.blob.lazy_tooltips() {
+            return Result::Ok(str1);
+        }
+        if let Some(tooltip) = self.tooltip() {
+            return Result::Ok(format!("{} / {}", str1, tooltip?));
         };
         return Result::Ok(str1);
     }
 
+    pub fn caption(&self) -> Result<String, String> {
+        match self.blob.get_string(self.caption_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
+        }
+    }
+
+    ///
+    /// The tooltip, or None if the entry has no tooltip
+    ///
+    pub fn tooltip(&self) -> Option<Result<String, String>> {
+        if self.tooltip_off == 0 {
+            return None;
+        }
+        Some(match self.blob.get_tooltip(self.tooltip_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
+        })
+    }
+
+    ///
+    /// The caption and tooltip kept apart, unlike to_string
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.caption(), self.tooltip())
+    }
+
     pub fn get_mnemonics(&self) -> &MnemonicIndex
     {
         &self.mnemonic
     }
+
+    ///
+    /// The lowest and highest mnemonic values, None if there are no mnemonics
+    ///
+    pub fn mnemonic_value_range(&self) -> Option<(i32, i32)>
+    {
+        let mut values = self.mnemonic.iter().map(|(value, _)| value);
+        let first = values.next()?;
+        Some((first, values.last().unwrap_or(first)))
+    }
+
+    ///
+    /// The runs of values missing from the mnemonics, the device expects
+    /// the values to be contiguous
+    ///
+    pub fn mnemonic_value_gaps(&self) -> Vec<(i32, i32)>
+    {
+        let mut gaps = Vec::new();
+        let mut last: Option<i32> = None;
+        for (value, _) in self.mnemonic.iter() {
+            if let Some(last) = last {
+                if value > last + 1 {
+                    gaps.push((last + 1, value - 1));
+                }
+            }
+            last = Some(value);
+        }
+        gaps
+    }
 }
 
 impl PartialEq for ParameterIndexEntry {
@@ -310,16 +398,100 @@ impl Clone for ParameterIndexEntry {
             caption_off: self.caption_off,
             tooltip_off: self.tooltip_off,
 			str_len : self.str_len,
+            units_ref: self.units_ref,
             mnemonic: self.mnemonic.clone(),
             blob: self.blob.clone(),
         }
     }
 }
 
-impl Iterator for ParameterIndexIterator {
+impl Iterator for ParameterIndexIterator<'_> {
     type Item = (u8, ParameterIndexEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.pop()
+        self.items.next().map(|(key, entry)| (*key, entry.clone()))
+    }
+}
+
+#[cfg(test)]
+impl ParameterIndex {
+    ///
+    /// An index of (param, caption offset, tooltip offset) entries with no
+    /// mnemonics, for tests of the indexes above it
+    ///
+    pub fn from_offsets(fp: &mut FileBlob, entries: &[(u8, u32, u32)]) -> ParameterIndex {
+        let mut params = BTreeMap::new();
+        for (param, caption_off, tooltip_off) in entries {
+            params.insert(*param, ParameterIndexEntry::new(*param, *caption_off, *tooltip_off, 32, MnemonicIndex::empty(), fp));
+        }
+        ParameterIndex::new(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    ///
+    /// A V3 parameter index whose parameters have the given caption offsets
+    ///
+    fn load_v3(offsets: &[u32]) -> (FileBlob, ParameterIndex) {
+        let mut data = Vec::new();
+        data.extend((offsets.len() as u16).to_le_bytes());
+        data.extend(32u16.to_le_bytes());
+        data.push(0);
+        data.push(5);
+        for (param, offset) in offsets.iter().enumerate() {
+            data.extend((param as u16).to_le_bytes());
+            data.extend(&offset.to_le_bytes()[..3]);
+        }
+        let mut fp = blob_from_bytes(&data, ParseOptions::default());
+        let (index, _, _) = ParameterIndex::from_v3(&mut fp, 0).unwrap();
+        (fp, index)
+    }
+
+    ///
+    /// A parameter with a V4 mnemonic index of the values
+    ///
+    fn with_mnemonics(values: &[i32]) -> ParameterIndexEntry {
+        let mut data = Vec::new();
+        data.extend((values.len() as u16).to_le_bytes());
+        data.push(if values.is_empty() { 0 } else { 8 });
+        for value in values {
+            data.extend(value.to_le_bytes());
+            data.extend([1, 0, 0, 0, 0, 0]);
+        }
+        let mut fp = blob_from_bytes(&data, ParseOptions::default());
+        let mnemonic = MnemonicIndex::from(&mut fp).unwrap();
+        ParameterIndexEntry::new(1, 1, 0, 32, mnemonic, &mut fp)
+    }
+
+    #[test]
+    fn gapped_mnemonics_are_flagged() {
+        let param = with_mnemonics(&[5, 1, 2, 9]);
+        assert_eq!(param.mnemonic_value_range(), Some((1, 9)));
+        assert_eq!(param.mnemonic_value_gaps(), [(3, 4), (6, 8)]);
+
+        let param = with_mnemonics(&[-1, 0, 1]);
+        assert_eq!(param.mnemonic_value_range(), Some((-1, 1)));
+        assert!(param.mnemonic_value_gaps().is_empty());
+
+        let param = with_mnemonics(&[]);
+        assert_eq!(param.mnemonic_value_range(), None);
+        assert!(param.mnemonic_value_gaps().is_empty());
+    }
+
+    #[test]
+    fn empty_slot_is_one_info_diagnostic() {
+        let (fp, index) = load_v3(&[16, 0, 16]);
+        assert_eq!(index.get_num_params(), 3);
+        let diagnostics = fp.get_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Info);
+        assert_eq!(diagnostics[0].region, BlobRegions::Parameters);
+        assert_eq!(diagnostics[0].location, 11);
+        assert_eq!(diagnostics[0].message, "Empty slot for param 1");
     }
 }
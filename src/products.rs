@@ -1,16 +1,24 @@
 use std::collections::HashMap;
+use std::io;
 use std::rc::Rc;
 use std::cmp::Ordering;
 
 use crate::blob::{FileBlob, BlobRegions};
+use crate::diagnostics::Severity;
 use crate::modes::ModeIndex;
 
+///
+/// A product index entry as read, before its modes are: product id,
+/// derivative id low and high, flags and the offset to its modes
+///
+type EntryInfo = (u16, u16, u16, u16, u32);
+
 ///
 /// ProductIndex is a dictionary of Products
 ///
 pub struct ProductIndex
 {
-    products: Vec<ProductIndexEntry>,
+    products: Vec<ProductIndexEntry>, // Sorted, by product id then derivative range
 }
 
 ///
@@ -22,9 +30,16 @@ pub struct ProductIndexEntry
     derivative_id_low: u16,
     derivative_id_high: u16,
     flags: u16,
+    modes_off: u32,
     mode_index: Rc<ModeIndex>,
 }
 
+///
+/// The flags of a product, bit0 = hidden, bit1 = beta, bit2 = oem
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ProductFlags(pub u16);
+
 pub struct ProductIndexIterator 
 {
     items: Vec<ProductIndexEntry>
@@ -58,65 +73,208 @@ impl ProductIndex
             }
         }
  
+        Self::sorted(products)
+    }
+
+    fn sorted(mut products: Vec<ProductIndexEntry>) -> ProductIndex
+    {
+        products.sort();
         ProductIndex { products }
     }
 
     ///
     /// Create a ProductIndex from the FileBlob
     ///
-    pub fn create_from_file(fp: &mut FileBlob, schema: u16, font_family: u8) -> ProductIndex
+    pub fn create_from_file(fp: &mut FileBlob, schema: u16, font_family: u8) -> io::Result<ProductIndex>
     {
-        // Product index header
-        let num_products = fp.read_byte(BlobRegions::Products);
-        let idx_entry_len = fp.read_byte(BlobRegions::Products);
-
-        Self::validate_schema(schema, idx_entry_len, num_products);
-
-        let tmp_info = match schema {
-            2 => Self::read_v2_entries(fp, num_products),
-            3 => Self::read_v3_entries(fp, num_products),
-            4 => Self::read_v3_entries(fp, num_products),
-            _ => panic!("Invalid format"),
-        };
+        let tmp_info = Self::read_entries(fp, schema)?;
 
         let mut products = Vec::new();
 
         for info in tmp_info {
             let (product_id, derivative_id_low, derivative_id_high, flags, offset) = info;
             
-            fp.set_pos(offset);
-            let mode_index = ModeIndex::create_from_file(fp, schema, font_family);
+            let mode_index = Self::read_modes(fp, offset, schema, font_family)?;
             products.push(
-                ProductIndexEntry::new(product_id, derivative_id_low, derivative_id_high, flags, mode_index),
+                ProductIndexEntry::new(product_id, derivative_id_low, derivative_id_high, flags, offset, mode_index),
             );
         }
 
-        ProductIndex::new(products)
+        Ok(ProductIndex::new(products))
     }
 
     ///
-    /// Valid the Product_Index
-    fn validate_schema(schema: u16, idx_entry_len: u8, num_of_products: u8) 
+    /// As create_from_file, but stops at the first product that fails to
+    /// parse and returns the products before it along with the error.
+    /// Duplicate products are kept rather than failing the parse.
+    ///
+    pub fn create_partial_from_file(fp: &mut FileBlob, schema: u16, font_family: u8) -> (ProductIndex, Option<String>)
     {
-        match schema {
-            2 => {
-                if idx_entry_len != 8 {
-                    panic!("ProductIndexEntry wrong size 8 != {}", idx_entry_len)
-                }
+        let tmp_info = match fp.catch(|fp| Self::read_entries(fp, schema)) {
+            Ok(tmp_info) => tmp_info,
+            Err(x) => return (ProductIndex { products: Vec::new() }, Some(x)),
+        };
+
+        let mut products = Vec::new();
+
+        for (product_id, derivative_id_low, derivative_id_high, flags, offset) in tmp_info {
+            match fp.catch(|fp| Self::read_modes(fp, offset, schema, font_family)) {
+                Ok(mode_index) => products.push(
+                    ProductIndexEntry::new(product_id, derivative_id_low, derivative_id_high, flags, offset, mode_index),
+                ),
+                Err(x) => return (Self::sorted(products), Some(x)),
             }
-            3 => {
-                if idx_entry_len != 11 {
-                    panic!("ProductIndexEntry wrong size 11 != {}", idx_entry_len)
-                }
+        }
+
+        (Self::sorted(products), None)
+    }
+
+    ///
+    /// Create just the ProductIndexEntry of the first product matching
+    /// product_id, the mode trees of the other products are skipped
+    ///
+    pub fn create_one_from_file(fp: &mut FileBlob, schema: u16, font_family: u8, product_id: u16) -> io::Result<Option<ProductIndexEntry>>
+    {
+        let tmp_info = Self::read_entries(fp, schema)?;
+
+        for (entry_product_id, derivative_id_low, derivative_id_high, flags, offset) in tmp_info {
+            if entry_product_id == product_id {
+                let mode_index = Self::read_modes(fp, offset, schema, font_family)?;
+                return Ok(Some(ProductIndexEntry::new(product_id, derivative_id_low, derivative_id_high, flags, offset, mode_index)));
+            }
+        }
+        Ok(None)
+    }
+
+    ///
+    /// Read the ModeIndex at offset, a stub product (in pre-release files)
+    /// has a zero offset and no modes
+    ///
+    fn read_modes(fp: &mut FileBlob, offset: u32, schema: u16, font_family: u8) -> io::Result<ModeIndex>
+    {
+        if offset == 0 {
+            return Ok(ModeIndex::empty());
+        }
+        fp.set_pos(offset);
+        fp.time(BlobRegions::Modes, |fp| ModeIndex::create_from_file(fp, schema, font_family))
+    }
+
+    ///
+    /// Read the Product index header and its entries
+    ///
+    fn read_entries(fp: &mut FileBlob, schema: u16) -> io::Result<Vec<EntryInfo>>
+    {
+        // Product index header
+        let index_start = fp.get_pos();
+        let num_products = fp.read_byte(BlobRegions::Products)?;
+        fp.check_entries(BlobRegions::Products, num_products as u32)?;
+        let idx_entry_len = fp.read_byte(BlobRegions::Products)?;
+
+        let extra_len = Self::validate_schema(schema, idx_entry_len, num_products, fp.lenient_entry_len());
+        fp.add_entry_layout(BlobRegions::Products, idx_entry_len);
+
+        let tmp_info = match schema {
+            2 => Self::read_v2_entries(fp, num_products, extra_len)?,
+            3 => Self::read_v3_entries(fp, num_products, extra_len)?,
+            4 => Self::read_v3_entries(fp, num_products, extra_len)?,
+            _ => panic!("Invalid format"),
+        };
+
+        let index_range = index_start..fp.get_pos();
+        for (product_id, _, _, _, offset) in &tmp_info {
+            if *offset == 0 {
+                fp.add_diagnostic(Severity::Info, BlobRegions::Products, index_start, &format!("Product {} has no modes", product_id));
+            }
+            fp.check_offset_outside(BlobRegions::Modes, &index_range, *offset)?;
+        }
+        Ok(tmp_info)
+    }
+
+    ///
+    /// Derivative ranges of product_id that no entry covers, the
+    /// ALL DERIVATIVES entry (0 - 65535) covers everything
+    ///
+    pub fn coverage_gaps(&self, product_id: u16) -> Vec<(u16, u16)>
+    {
+        let mut gaps = Vec::new();
+        let mut next: u32 = 0; // Lowest derivative not yet covered
+
+        for (low, high) in self.derivative_ranges(product_id) {
+            if (low as u32) > next {
+                gaps.push((next as u16, low - 1));
+            }
+            next = next.max(high as u32 + 1);
+        }
+        if next <= 65535 {
+            gaps.push((next as u16, 65535));
+        }
+        gaps
+    }
+
+    ///
+    /// Derivative ranges of product_id covered by more than one entry,
+    /// ignoring the ALL DERIVATIVES entry
+    ///
+    pub fn coverage_overlaps(&self, product_id: u16) -> Vec<(u16, u16)>
+    {
+        let mut overlaps = Vec::new();
+        let mut covered: Option<u16> = None; // Highest derivative covered so far
+
+        for (low, high) in self.derivative_ranges(product_id) {
+            if low == 0 && high == 65535 {
+                continue;
             }
-            4 => {
-                if idx_entry_len != 11 {
-                    panic!("ProductIndexEntry wrong size 11 != {}", idx_entry_len)
+            if let Some(covered_high) = covered {
+                if low <= covered_high {
+                    overlaps.push((low, high.min(covered_high)));
                 }
             }
- 
+            covered = Some(covered.map_or(high, |x| x.max(high)));
+        }
+        overlaps
+    }
+
+    fn derivative_ranges(&self, product_id: u16) -> Vec<(u16, u16)>
+    {
+        let mut ranges = Vec::new();
+        for entry in &self.products {
+            if entry.product_id == product_id {
+                ranges.push((entry.derivative_id_low, entry.derivative_id_high));
+            }
+        }
+        ranges.sort();
+        ranges
+    }
+
+    ///
+    /// Every entry as (product id, derivative low, derivative high, num of modes),
+    /// sorted by derivative range. ALL DERIVATIVES entries are placed last.
+    ///
+    pub fn sorted_by_derivative(&self) -> Vec<(u16, u16, u16, usize)>
+    {
+        let mut rows = Vec::new();
+        for entry in &self.products {
+            rows.push((entry.product_id, entry.derivative_id_low, entry.derivative_id_high, entry.mode_index.get_num_modes()));
+        }
+        rows.sort_by_key(|(product_id, low, high, _)| (*low == 0 && *high == 65535, *low, *high, *product_id));
+        rows
+    }
+
+    ///
+    /// Valid the Product_Index, returns the number of extra bytes per
+    /// entry (only non zero when lenient)
+    fn validate_schema(schema: u16, idx_entry_len: u8, num_of_products: u8, lenient: bool) -> u8
+    {
+        let req_idx_entry_len = match schema {
+            2 => 8,
+            3 => 11,
+            4 => 11,
             _ => panic!("Invalid format"),
         };
+        // Leniently, a newer file may append fields to each entry
+        if idx_entry_len != req_idx_entry_len && !(lenient && idx_entry_len > req_idx_entry_len) {
+            panic!("ProductIndexEntry wrong size {} != {}", req_idx_entry_len, idx_entry_len)
+        }
 
         if num_of_products < 10 {
             panic!("Seems none many products!");
@@ -124,24 +282,26 @@ impl ProductIndex
         if num_of_products > 40 {
             panic!("Seems a lot of products!");
         }
+        idx_entry_len - req_idx_entry_len
     }
 
     ///
     /// Parse V2 Product Index Entries intinally into a list of tuples
     ///
-    fn read_v2_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u16, u16, u16, u16, u32)> 
+    fn read_v2_entries(fp: &mut FileBlob, num_entries: u8, extra_len: u8) -> io::Result<Vec<EntryInfo>> 
     {
         // Language file V2 uses 32 bit offsets
         let mut tmp_info = Vec::new();
 
         for _i in 0..num_entries {
-            let flags = fp.read_byte(BlobRegions::Products) as u16;
+            let flags = fp.read_byte(BlobRegions::Products)? as u16;
             if flags > 15 {
                 panic!("Invalid flags in product index")
             }
-            let derivative_id = fp.read_byte(BlobRegions::Products) as u16;
-            let product_id = fp.read_le_2bytes(BlobRegions::Products);
-            let offset_to_modes = fp.read_le_4bytes(BlobRegions::Products);
+            let derivative_id = fp.read_byte(BlobRegions::Products)? as u16;
+            let product_id = fp.read_le_2bytes(BlobRegions::Products)?;
+            let offset_to_modes = fp.read_le_4bytes(BlobRegions::Products)?;
+            fp.skip(extra_len as u32, BlobRegions::Products);
 
             tmp_info.push((
                 product_id,
@@ -151,23 +311,24 @@ impl ProductIndex
                 offset_to_modes,
             ))
         }
-        tmp_info
+        Ok(tmp_info)
     }
 
     ///
     /// Parse V3 Product Index Entries intinally into a list of tuples
     ///
-    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8) -> Vec<(u16, u16, u16, u16, u32)> 
+    fn read_v3_entries(fp: &mut FileBlob, num_entries: u8, extra_len: u8) -> io::Result<Vec<EntryInfo>> 
     {
         // Language file >= V3 uses 24 bit offsets
         let mut tmp_info = Vec::new();
 
         for _i in 0..num_entries {
-            let product_id = fp.read_le_2bytes(BlobRegions::Products);
-            let derivative_id_low = fp.read_le_2bytes(BlobRegions::Products);
-            let derivative_id_high = fp.read_le_2bytes(BlobRegions::Products);
-            let flags = fp.read_le_2bytes(BlobRegions::Products);
-            let offset_to_modes = fp.read_le_3bytes(BlobRegions::Products);
+            let product_id = fp.read_le_2bytes(BlobRegions::Products)?;
+            let derivative_id_low = fp.read_le_2bytes(BlobRegions::Products)?;
+            let derivative_id_high = fp.read_le_2bytes(BlobRegions::Products)?;
+            let flags = fp.read_le_2bytes(BlobRegions::Products)?;
+            let offset_to_modes = fp.read_le_3bytes(BlobRegions::Products)?;
+            fp.skip(extra_len as u32, BlobRegions::Products);
 
             tmp_info.push((
                 product_id,
@@ -177,7 +338,23 @@ impl ProductIndex
                 offset_to_modes,
             ))
         }
-        tmp_info
+        Ok(tmp_info)
+    }
+
+    ///
+    /// The first entry, in order, for product_id. A product has an entry
+    /// per derivative range so there may be more with the same id.
+    ///
+    pub fn get(&self, product_id: u16) -> Option<&ProductIndexEntry> {
+        let first = self.products.partition_point(|entry| entry.product_id < product_id);
+        self.products.get(first).filter(|entry| entry.product_id == product_id)
+    }
+
+    ///
+    /// Iterate over the products in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = &ProductIndexEntry> {
+        self.products.iter()
     }
 }
 
@@ -192,7 +369,6 @@ impl IntoIterator for &ProductIndex
         for item in &self.products {
             items.push(item.clone())
         }
-        items.sort();
         items.reverse();
         ProductIndexIterator { items }
     }
@@ -200,7 +376,7 @@ impl IntoIterator for &ProductIndex
 
 impl ProductIndexEntry 
 {
-    fn new(product_id : u16, derivative_id_low: u16, derivative_id_high: u16, flags: u16, mode_index: ModeIndex,
+    fn new(product_id : u16, derivative_id_low: u16, derivative_id_high: u16, flags: u16, modes_off: u32, mode_index: ModeIndex,
     ) -> ProductIndexEntry {
             //            if derivative_id_high > derivative_id_low {
             //                println!("Product = {} : {} - {}", product_id, derivative_id_low, derivative_id_high);
@@ -214,6 +390,7 @@ impl ProductIndexEntry
             derivative_id_low,
             derivative_id_high,
             flags,
+            modes_off,
             mode_index: Rc::<ModeIndex>::new(mode_index),
         }
     }
@@ -235,9 +412,60 @@ impl ProductIndexEntry
         ));
     }
 
+    ///
+    /// The offset of the ModeIndex, zero for a product with no modes
+    ///
+    pub fn get_modes_off(&self) -> u32 {
+        self.modes_off
+    }
+
     pub fn get_modes(&self) -> &ModeIndex {
         &self.mode_index
     }
+
+    pub fn get_flags(&self) -> ProductFlags {
+        ProductFlags(self.flags)
+    }
+
+    pub fn get_product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    pub fn get_derivative_ids(&self) -> (u16, u16) {
+        (self.derivative_id_low, self.derivative_id_high)
+    }
+
+    ///
+    /// The modes, in order, that have the menu menu_num
+    ///
+    pub fn modes_containing_menu(&self, menu_num: u8) -> Vec<u8> {
+        let mut modes = Vec::new();
+        for (mode, entry) in self.mode_index.iter() {
+            if entry.get_menus().iter().any(|(menu, _)| menu == menu_num) {
+                modes.push(mode);
+            }
+        }
+        modes
+    }
+}
+
+impl ProductFlags
+{
+    pub fn raw(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        (self.0 & 0x01) != 0
+    }
+
+    pub fn is_beta(&self) -> bool {
+        (self.0 & 0x02) != 0
+    }
+
+    pub fn is_oem(&self) -> bool {
+        (self.0 & 0x04) != 0
+    }
 }
 
 impl Ord for ProductIndexEntry
@@ -282,6 +510,7 @@ impl Clone for ProductIndexEntry
             derivative_id_low: self.derivative_id_low,
             derivative_id_high: self.derivative_id_high,
             flags: self.flags,
+            modes_off: self.modes_off,
             mode_index: self.mode_index.clone(),
         }
     }
@@ -296,3 +525,152 @@ impl Iterator for ProductIndexIterator
         self.items.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::menus::{MenuIndex, MenuIndexEntry};
+    use crate::modes::ModeIndexEntry;
+    use crate::options::ParseOptions;
+    use crate::parameters::ParameterIndex;
+    use std::collections::BTreeMap;
+
+    fn entry(product_id: u16, low: u16, high: u16) -> ProductIndexEntry {
+        ProductIndexEntry::new(product_id, low, high, 0, 0, ModeIndex::empty())
+    }
+
+    ///
+    /// A V3 product index at 1 of ten stub products, product 1 with its
+    /// modes at offset, each entry padded to entry_len
+    ///
+    fn v3_index(offset: u32, entry_len: u8) -> Vec<u8> {
+        let mut data = vec![0, 10, entry_len];
+        for product_id in 1..=10u16 {
+            data.extend(product_id.to_le_bytes());
+            data.extend([0, 0, 0xFF, 0xFF, 0, 0]);
+            let offset = if product_id == 1 { offset } else { 0 };
+            data.extend(&offset.to_le_bytes()[..3]);
+            data.resize(data.len() + entry_len as usize - 11, 0xEE);
+        }
+        data
+    }
+
+    #[test]
+    fn stub_products_are_outside_the_index() {
+        let mut fp = blob_from_bytes(&v3_index(0, 11), ParseOptions::default());
+        fp.set_pos(1);
+        let index = ProductIndex::create_from_file(&mut fp, 3, 1).unwrap();
+        assert_eq!(index.iter().count(), 10);
+    }
+
+    #[test]
+    fn zero_mode_offset_gives_no_modes() {
+        let mut fp = blob_from_bytes(&v3_index(0, 11), ParseOptions::default());
+        fp.set_pos(1);
+        let index = ProductIndex::create_from_file(&mut fp, 3, 1).unwrap();
+        let product = index.get(1).unwrap();
+        assert_eq!(product.get_modes_off(), 0);
+        assert_eq!(product.get_modes().iter().count(), 0);
+        let messages: Vec<String> = fp.get_diagnostics().into_iter().map(|x| x.message).collect();
+        assert!(messages.contains(&"Product 1 has no modes".to_string()), "{:?}", messages);
+    }
+
+    #[test]
+    fn oversized_entries_parse_when_lenient() {
+        let options = ParseOptions { lenient_entry_len: true, ..ParseOptions::default() };
+        let mut fp = blob_from_bytes(&v3_index(0, 14), options);
+        fp.set_pos(1);
+        let index = ProductIndex::create_from_file(&mut fp, 3, 1).unwrap();
+        let ids: Vec<u16> = index.iter().map(|x| x.get_product_id()).collect();
+        assert_eq!(ids, (1..=10).collect::<Vec<u16>>());
+        assert_eq!(index.get(10).unwrap().get_derivative_ids(), (0, 0xFFFF));
+    }
+
+    #[test]
+    #[should_panic(expected = "ProductIndexEntry wrong size 11 != 14")]
+    fn oversized_entries_are_rejected_by_default() {
+        let mut fp = blob_from_bytes(&v3_index(0, 14), ParseOptions::default());
+        fp.set_pos(1);
+        let _ = ProductIndex::create_from_file(&mut fp, 3, 1);
+    }
+
+    #[test]
+    fn mode_offset_into_the_product_index() {
+        let mut fp = blob_from_bytes(&v3_index(5, 11), ParseOptions::default());
+        fp.set_pos(1);
+        let error = ProductIndex::create_from_file(&mut fp, 3, 1).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Modes offset 5 points back into its index at 1 to 113");
+    }
+
+    #[test]
+    fn get_finds_the_first_derivative() {
+        let index = ProductIndex::new(vec![entry(8, 0, 65535), entry(7, 20, 65535), entry(7, 0, 9), entry(5, 0, 65535)]);
+        assert_eq!(index.get(7).unwrap().get_derivative_ids(), (0, 9));
+        assert_eq!(index.get(8).unwrap().get_derivative_ids(), (0, 65535));
+        assert!(index.get(6).is_none());
+        assert!(index.get(9).is_none());
+        let ids: Vec<u16> = index.iter().map(|x| x.get_product_id()).collect();
+        assert_eq!(ids, [5, 7, 7, 8]);
+    }
+
+    #[test]
+    fn gap_in_derivatives_is_reported() {
+        let index = ProductIndex::new(vec![entry(7, 0, 9), entry(7, 20, 65535), entry(8, 0, 65535)]);
+        assert_eq!(index.coverage_gaps(7), [(10, 19)]);
+        assert!(index.coverage_overlaps(7).is_empty());
+        assert!(index.coverage_gaps(8).is_empty());
+    }
+
+    #[test]
+    fn overlapping_derivatives_are_reported() {
+        let index = ProductIndex::new(vec![entry(7, 0, 9), entry(7, 5, 65535), entry(7, 0, 65535)]);
+        assert!(index.coverage_gaps(7).is_empty());
+        assert_eq!(index.coverage_overlaps(7), [(5, 9)]);
+    }
+
+    #[test]
+    fn sorted_by_derivative_puts_all_derivatives_last() {
+        let index = ProductIndex::new(vec![
+            entry(8, 0, 65535), entry(7, 20, 29), entry(9, 0, 9), entry(7, 0, 9), entry(7, 0, 65535),
+        ]);
+        assert_eq!(index.sorted_by_derivative(), [
+            (7, 0, 9, 0), (9, 0, 9, 0), (7, 20, 29, 0), (7, 0, 65535, 0), (8, 0, 65535, 0),
+        ]);
+    }
+
+    #[test]
+    fn menu_in_two_of_three_modes() {
+        let mut fp = blob_from_bytes(&[0], ParseOptions::default());
+        let mut modes = BTreeMap::new();
+        for (mode, menu_nums) in [(1, vec![0, 5]), (2, vec![0]), (3, vec![5, 7])] {
+            let mut menus = BTreeMap::new();
+            for menu in menu_nums {
+                menus.insert(menu, MenuIndexEntry::new(menu, 0, 0, 32, ParameterIndex::new(BTreeMap::new()), &mut fp));
+            }
+            modes.insert(mode, ModeIndexEntry::new(mode, MenuIndex::new(menus)));
+        }
+        let product = ProductIndexEntry::new(7, 0, 65535, 0, 0, ModeIndex::new(modes));
+        assert_eq!(product.modes_containing_menu(5), [1, 3]);
+        assert_eq!(product.modes_containing_menu(0), [1, 2]);
+        assert!(product.modes_containing_menu(9).is_empty());
+    }
+
+    #[test]
+    fn flag_accessors() {
+        let flags = |raw| {
+            let flags = ProductFlags(raw);
+            (flags.is_hidden(), flags.is_beta(), flags.is_oem())
+        };
+        assert_eq!(flags(0x00), (false, false, false));
+        assert_eq!(flags(0x01), (true, false, false));
+        assert_eq!(flags(0x02), (false, true, false));
+        assert_eq!(flags(0x04), (false, false, true));
+        assert_eq!(flags(0xfff8), (false, false, false));
+        assert_eq!(ProductFlags(0x0105).raw(), 0x0105);
+
+        let product = ProductIndexEntry::new(7, 0, 65535, 0x0003, 0, ModeIndex::empty());
+        assert!(product.get_flags().is_hidden() && product.get_flags().is_beta());
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::characters::CharacterMaps;
+use crate::flatten::IdPath;
+use crate::language::Language;
+
+///
+/// The strings of a Language encoded for another character map
+///
+pub struct ReencodedLanguage {
+    pub is_utf8: bool,
+    pub entries: Vec<ReencodedEntry>,
+}
+
+pub struct ReencodedEntry {
+    pub path: IdPath,
+    pub caption: Option<Vec<u8>>, // None if the entry has no caption in the file
+    pub tooltip: Option<Vec<u8>>,
+}
+
+impl ReencodedLanguage {
+    ///
+    /// Lay the strings out as NUL terminated text, identical strings are
+    /// stored once. Returns the text and the (caption, tooltip) offsets
+    /// of each entry relative to base, an absent string stays offset 0
+    ///
+    pub fn string_pool(&self, base: u32) -> (Vec<u8>, HashMap<IdPath, (u32, u32)>) {
+        let mut text = Vec::new();
+        let mut pooled = HashMap::<Vec<u8>, u32>::new();
+        let mut offsets = HashMap::new();
+
+        let mut add = |bytes: &Option<Vec<u8>>| -> u32 {
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                None => return 0,
+            };
+            if let Some(off) = pooled.get(bytes) {
+                return *off;
+            }
+            let off = base + text.len() as u32;
+            text.extend_from_slice(bytes);
+            text.push(0);
+            pooled.insert(bytes.clone(), off);
+            off
+        };
+
+        for entry in &self.entries {
+            let caption_off = add(&entry.caption);
+            let tooltip_off = add(&entry.tooltip);
+            offsets.insert(entry.path, (caption_off, tooltip_off));
+        }
+        (text, offsets)
+    }
+}
+
+impl Language {
+    ///
+    /// Decode every string and encode it with the target character maps,
+    /// e.g. CharacterMaps::utf8() when migrating a legacy map to schema 4
+    ///
+    pub fn reencode(&self, target: &CharacterMaps) -> Result<ReencodedLanguage, String> {
+        let mut entries = Vec::new();
+        for entry in self.flatten() {
+            let caption = match &entry.caption {
+                _ if entry.caption_off == 0 => None,
+                Ok(x) => Some(target.encode(Self::strip_placeholder(x))?),
+                Err(x) => return Err(format!("{} => {}", entry.path, x)),
+            };
+            let tooltip = match &entry.tooltip {
+                _ if entry.tooltip_off == 0 => None,
+                Some(Ok(x)) => Some(target.encode(Self::strip_placeholder(x))?),
+                Some(Err(x)) => return Err(format!("{} => {}", entry.path, x)),
+                None => None,
+            };
+            entries.push(ReencodedEntry { path: entry.path, caption, tooltip });
+        }
+        Ok(ReencodedLanguage { is_utf8: target.is_utf8(), entries })
+    }
+
+    ///
+    /// Encode every decoded string back with the Language's own character
+    /// maps and return those that differ from the bytes in the file, as
+    /// (location, original, re-encoded). A string that fails to encode is
+    /// returned with empty re-encoded bytes.
+    ///
+    pub fn verify_roundtrip(&self) -> Vec<(IdPath, Vec<u8>, Vec<u8>)> {
+        let mut failures = Vec::new();
+        for entry in self.flatten() {
+            let mut strings = vec![(entry.caption_off, entry.caption)];
+            if let Some(tooltip) = entry.tooltip {
+                strings.push((entry.tooltip_off, tooltip));
+            }
+            for (off, text) in strings {
+                let text = match text {
+                    Ok(x) if off != 0 => x,
+                    _ => continue,
+                };
+                let original = self.get_raw_bytes(off, entry.str_len);
+                let reencoded = self.encode(Self::strip_placeholder(&text)).unwrap_or_default();
+                if reencoded != original {
+                    failures.push((entry.path, original, reencoded));
+                }
+            }
+        }
+        failures
+    }
+
+    ///
+    /// Decoding shows an empty string as a placeholder, it is written
+    /// back as an empty string. A missing string (offset 0) is never
+    /// encoded.
+    ///
+    fn strip_placeholder(text: &str) -> &str {
+        match text {
+            "[-- empty string --]" => "",
+            _ => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characters::read_character_file;
+    use crate::flatten::IdPath;
+    use crate::language::read_language_file;
+    use crate::options::ParseOptions;
+
+    ///
+    /// The NUL terminated UTF-8 string at off in text pooled from base
+    ///
+    fn pooled_string(text: &[u8], base: u32, off: u32) -> String {
+        let bytes = &text[(off - base) as usize..];
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap();
+        String::from_utf8(bytes[..end].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reencoded_utf8_decodes_identically() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let reencoded = lang.reencode(&CharacterMaps::utf8()).unwrap();
+        assert!(reencoded.is_utf8);
+
+        let (text, offsets) = reencoded.string_pool(16);
+        let mut absent = 0;
+        for entry in lang.flatten() {
+            let (caption_off, tooltip_off) = offsets[&entry.path];
+            for (file_off, pooled_off, decoded) in [
+                (entry.caption_off, caption_off, Some(entry.caption)),
+                (entry.tooltip_off, tooltip_off, entry.tooltip),
+            ] {
+                if file_off == 0 {
+                    assert_eq!(pooled_off, 0, "{}", entry.path);
+                    absent += 1;
+                } else {
+                    let decoded = decoded.unwrap().unwrap();
+                    assert_eq!(pooled_string(&text, 16, pooled_off), Language::strip_placeholder(&decoded));
+                }
+            }
+        }
+        assert!(absent > 0);
+    }
+
+    #[test]
+    fn ambiguous_encoding_fails_the_roundtrip() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let clean = read_language_file("languageV3.bin", maps.clone()).unwrap();
+        assert_eq!(clean.verify_roundtrip(), []);
+
+        // 'A' is 65 in map 1 and 529 in map 2, packed as 23 C4
+        let (unit, entry) = clean.get_units().iter().next().unwrap();
+        let off = entry.get_caption_off() as usize;
+        let mut data = std::fs::read("languageV3.bin").unwrap();
+        data[off..off + 2].copy_from_slice(&[0x23, 0xC4]);
+        let options = ParseOptions { skip_crc_check: true, ..ParseOptions::default() };
+        let lang = Language::create_from_file_with_options(&mut std::io::Cursor::new(data), maps, &options).unwrap();
+
+        let failures = lang.verify_roundtrip();
+        let (_, original, reencoded) = failures.iter().find(|(path, _, _)| *path == IdPath::Unit(unit)).unwrap();
+        assert_eq!(original[..2], [0x23, 0xC4]);
+        assert_eq!(reencoded[0], b'A');
+        assert_eq!(original[2..], reencoded[1..]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use crate::flatten::ProductKey;
+use crate::language::Language;
+
+///
+/// The decoded contents of a Language as plain owned data, without any
+/// reference back to the blob
+///
+#[derive(Clone, PartialEq, Debug)]
+pub struct LanguageTree {
+    pub products: Vec<ProductTree>,
+    pub enumerations: BTreeMap<u16, Result<String, String>>,
+    pub keypad_strs: BTreeMap<u16, Result<String, String>>,
+    pub units: BTreeMap<u16, TreeText>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ProductTree {
+    pub key: ProductKey,
+    pub flags: u16,
+    pub modes: BTreeMap<u8, BTreeMap<u8, MenuTree>>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MenuTree {
+    pub text: TreeText,
+    pub params: BTreeMap<u8, ParamTree>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParamTree {
+    pub text: TreeText,
+    pub mnemonics: BTreeMap<i32, TreeText>,
+}
+
+///
+/// A decoded caption and, if the entry has one, tooltip
+///
+#[derive(Clone, PartialEq, Debug)]
+pub struct TreeText {
+    pub caption: Result<String, String>,
+    pub tooltip: Option<Result<String, String>>,
+}
+
+impl Language {
+    ///
+    /// Decode everything once into a LanguageTree, products are in file
+    /// order and everything else is sorted by its id
+    ///
+    pub fn to_tree(&self) -> LanguageTree {
+        let mut products = Vec::new();
+        for product in self.get_products().iter() {
+            let (derivative_id_low, derivative_id_high) = product.get_derivative_ids();
+            let key = ProductKey {
+                product_id: product.get_product_id(),
+                derivative_id_low,
+                derivative_id_high,
+            };
+            let mut modes = BTreeMap::new();
+            for (mode, mode_entry) in product.get_modes().iter() {
+                let mut menus = BTreeMap::new();
+                for (menu, menu_entry) in mode_entry.get_menus().iter() {
+                    let mut params = BTreeMap::new();
+                    for (param, param_entry) in menu_entry.get_params().iter() {
+                        let mut mnemonics = BTreeMap::new();
+                        for (value, mnemonic_entry) in param_entry.get_mnemonics().iter() {
+                            mnemonics.insert(value, TreeText {
+                                caption: mnemonic_entry.caption(),
+                                tooltip: mnemonic_entry.tooltip(),
+                            });
+                        }
+                        params.insert(param, ParamTree {
+                            text: TreeText { caption: param_entry.caption(), tooltip: param_entry.tooltip() },
+                            mnemonics,
+                        });
+                    }
+                    menus.insert(menu, MenuTree {
+                        text: TreeText { caption: menu_entry.caption(), tooltip: menu_entry.tooltip() },
+                        params,
+                    });
+                }
+                modes.insert(mode, menus);
+            }
+            products.push(ProductTree { key, flags: product.get_flags().raw(), modes });
+        }
+
+        let mut enumerations = BTreeMap::new();
+        for (enumeration, entry) in self.get_enumerations().iter() {
+            enumerations.insert(enumeration, entry.to_string());
+        }
+
+        let mut keypad_strs = BTreeMap::new();
+        for (num, entry) in self.get_keypad_strs().iter() {
+            keypad_strs.insert(num, entry.to_string());
+        }
+
+        let mut units = BTreeMap::new();
+        for (unit, entry) in self.get_units().iter() {
+            units.insert(unit, TreeText { caption: entry.caption(), tooltip: entry.tooltip() });
+        }
+
+        LanguageTree { products, enumerations, keypad_strs, units }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::characters::read_character_file;
+    use crate::language::read_language_file;
+
+    #[test]
+    fn tree_of_the_fixture_has_the_nested_values() {
+        let maps = read_character_file("CharacterMaps.xml").unwrap();
+        let lang = read_language_file("languageV3.bin", maps).unwrap();
+        let tree = lang.to_tree();
+        assert_eq!(tree.products.len(), lang.get_products().iter().count());
+
+        let (unit, entry) = lang.get_units().iter().next().unwrap();
+        assert_eq!(tree.units[&unit].caption, entry.caption());
+        let (enumeration, entry) = lang.get_enumerations().iter().next().unwrap();
+        assert_eq!(tree.enumerations[&enumeration], entry.to_string());
+
+        // The first product, in file order, with a parameter
+        let (product, mode, menu, param, text) = tree.products.iter()
+            .flat_map(|product| product.modes.iter().map(move |(mode, menus)| (product, *mode, menus)))
+            .flat_map(|(product, mode, menus)| menus.iter().map(move |(menu, tree)| (product, mode, *menu, tree)))
+            .find_map(|(product, mode, menu, tree)| tree.params.iter().next()
+                .map(|(param, tree)| (product, mode, menu, *param, &tree.text)))
+            .unwrap();
+        let resolved = lang.resolve(product.key.product_id, mode, menu, param).unwrap();
+        let expected = match &text.tooltip {
+            Some(tooltip) => format!("{} / {}", text.caption.clone().unwrap(), tooltip.clone().unwrap()),
+            None => text.caption.clone().unwrap(),
+        };
+        assert_eq!(resolved, Ok(expected));
+    }
+}
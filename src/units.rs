@@ -1,10 +1,12 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
 
 use crate::blob::{FileBlob, RawBlob, BlobRegions};
 
 pub struct UnitsIndex 
 {
-    units: HashMap<u16, UnitsIndexEntry>,
+    units: BTreeMap<u16, UnitsIndexEntry>,
 }
 
 pub struct UnitsIndexEntry 
@@ -16,14 +18,14 @@ pub struct UnitsIndexEntry
     blob: RawBlob,
 }
 
-pub struct UnitsIndexIterator 
+pub struct UnitsIndexIterator<'a> 
 {
-    items: Vec<(u16, UnitsIndexEntry)>,
+    items: btree_map::Iter<'a, u16, UnitsIndexEntry>,
 }
 
 impl UnitsIndex {
 
-    pub fn new(units : HashMap<u16, UnitsIndexEntry>) -> UnitsIndex
+    pub fn new(units : BTreeMap<u16, UnitsIndexEntry>) -> UnitsIndex
     {
         let mut hits = HashSet::<u16>::new();
 
@@ -40,42 +42,48 @@ impl UnitsIndex {
         UnitsIndex { units }
     }
 
+    pub fn empty() -> UnitsIndex {
+        UnitsIndex { units: BTreeMap::new() }
+    }
+
 
-    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8) -> UnitsIndex {
+    pub fn from(fp: &mut FileBlob, schema: u16, root_font_family: u8) -> io::Result<UnitsIndex> {
 		
-		let num_entries = fp.read_le_2bytes(BlobRegions::Units);
+		let num_entries = fp.read_le_2bytes(BlobRegions::Units)?;
+		fp.check_entries(BlobRegions::Units, num_entries as u32)?;
 		println!("Num entries {}", num_entries);
         
-		let mut max_str_len = 256;
+		let str_len = fp.max_str_len(BlobRegions::Units, if schema < 4 { 16 } else { 256 });
+		let mut max_str_len = str_len;
 		if schema < 4 {
-        	max_str_len = fp.read_le_2bytes(BlobRegions::Units);
-        	let font_family = fp.read_byte(BlobRegions::Units);
+        	max_str_len = fp.read_le_2bytes(BlobRegions::Units)?;
+        	let font_family = fp.read_byte(BlobRegions::Units)?;
         
 			if root_font_family != font_family {
             	panic!("Mis-match font_family");
         	}
 		}
 
-        let idx_entry_len = fp.read_byte(BlobRegions::Units);
+        let idx_entry_len = fp.read_byte(BlobRegions::Units)?;
         
-		Self::validate_schema(schema, idx_entry_len, max_str_len);
+		Self::validate_schema(schema, idx_entry_len, max_str_len, str_len);
+		fp.add_entry_layout(BlobRegions::Units, idx_entry_len);
 
-        let mut units = HashMap::new();
+        let mut units = BTreeMap::new();
 
         for _i in 0..num_entries {
             let (unit_id, entry) = match schema {
-                2 => UnitsIndexEntry::load_v2(fp),
-                3 => UnitsIndexEntry::load_v3(fp),
-				4 => UnitsIndexEntry::load_v4(fp),
+                2 => UnitsIndexEntry::load_v2(fp, str_len)?,
+                3 => UnitsIndexEntry::load_v3(fp, str_len)?,
+				4 => UnitsIndexEntry::load_v4(fp, str_len)?,
                 _ => panic!("Invalid schema"),
             };
             units.insert(unit_id, entry);
         }
-        UnitsIndex::new(units)
+        Ok(UnitsIndex::new(units))
     }
 
-    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16) {
-		let mut req_str_len = 16;
+    fn validate_schema(schema: u16, idx_entry_len: u8, max_str_len: u16, req_str_len: u16) {
         match schema {
             2 => {
                 if idx_entry_len != 6 {
@@ -91,7 +99,6 @@ impl UnitsIndex {
                 if idx_entry_len != 8 {
                     panic!("V4 UnitsIndexEntry wrong size 8 != {}", idx_entry_len)
                 }
-				req_str_len = 256;
             }
             _ => panic!("Invalid format, schema = {}", schema),
         };
@@ -100,24 +107,39 @@ impl UnitsIndex {
             panic!("Units, max string len should be {} not {}!", req_str_len, max_str_len);
         }
     }
+
+    ///
+    /// The entry of unit unit_id, without iterating the index
+    ///
+    pub fn get(&self, unit_id: u16) -> Option<&UnitsIndexEntry> {
+        self.units.get(&unit_id)
+    }
+
+    ///
+    /// The ids, in order, of the units whose caption is text. Units whose
+    /// caption fails to decode are skipped.
+    ///
+    pub fn find_by_caption(&self, text: &str) -> Vec<u16> {
+        self.iter()
+            .filter(|(_, entry)| entry.caption().is_ok_and(|caption| caption == text))
+            .map(|(unit, _)| unit)
+            .collect()
+    }
+
+    ///
+    /// Iterate over the units in order, borrowing the entries
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &UnitsIndexEntry)> {
+        self.units.iter().map(|(key, entry)| (*key, entry))
+    }
 }
 
-impl IntoIterator for &UnitsIndex {
+impl<'a> IntoIterator for &'a UnitsIndex {
     type Item = (u16, UnitsIndexEntry);
-    type IntoIter = UnitsIndexIterator;
+    type IntoIter = UnitsIndexIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut keys = Vec::new();
-        for key in self.units.keys() {
-            keys.push(*key)
-        }
-        keys.sort();
-        keys.reverse();
-        let mut items = Vec::new();
-        for key in keys {
-            items.push((key, self.units[&key].clone()));
-        }
-        UnitsIndexIterator { items }
+        UnitsIndexIterator { items: self.units.iter() }
     }
 }
 
@@ -130,65 +152,92 @@ impl UnitsIndexEntry {
             caption_off,
             tooltip_off,
 			str_len,
-            blob: fp.freeze()
+            blob: fp.freeze(BlobRegions::Units)
         }
     }
 
+    pub fn get_str_len(&self) -> u16 {
+        self.str_len
+    }
+
     pub fn get_caption_off(&self) -> u32 {
         self.caption_off
     }
 
     pub fn get_tooltip_off(&self) -> u32 {
-        self.caption_off
+        self.tooltip_off
     }
 
     pub fn to_string(&self) -> Result<String, String> {
-        let str1 = match self.blob.get_string(self.caption_off, self.str_len) {
-            Ok(x) => x,
-            Err(x) => return Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
-        };
-        if self.tooltip_off != 0 {
-            let str2 = match self.blob.get_string(self.tooltip_off, self.str_len) {
-                Ok(x) => x,
-                Err(x) => return Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
-            };
-            return Result::Ok(format!("{} / {}", str1, str2));
+        let str1 = self.caption()?;
+        if self.blob.lazy_tooltips() {
+            return Result::Ok(str1);
+        }
+        if let Some(tooltip) = self.tooltip() {
+            return Result::Ok(format!("{} / {}", str1, tooltip?));
         };
         return Result::Ok(str1);
     }
 
-    fn load_v2(fp: &mut FileBlob) -> (u16, UnitsIndexEntry) 
+    pub fn caption(&self) -> Result<String, String> {
+        match self.blob.get_string(self.caption_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.caption_off, x)),
+        }
+    }
+
+    ///
+    /// The tooltip, or None if the entry has no tooltip
+    ///
+    pub fn tooltip(&self) -> Option<Result<String, String>> {
+        if self.tooltip_off == 0 {
+            return None;
+        }
+        Some(match self.blob.get_tooltip(self.tooltip_off, self.str_len) {
+            Ok(x) => Ok(x),
+            Err(x) => Err(format!("Blob offset {} \n\t {}", self.tooltip_off, x)),
+        })
+    }
+
+    ///
+    /// The caption and tooltip kept apart, unlike to_string
+    ///
+    pub fn captions(&self) -> (Result<String, String>, Option<Result<String, String>>) {
+        (self.caption(), self.tooltip())
+    }
+
+    fn load_v2(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, UnitsIndexEntry)> 
 	{
-        let unit_id = fp.read_le_2bytes(BlobRegions::Units);
-        let offset = fp.read_le_4bytes(BlobRegions::Units);
+        let unit_id = fp.read_le_2bytes(BlobRegions::Units)?;
+        let offset = fp.read_le_4bytes(BlobRegions::Units)?;
         if offset == 0 {
             panic! {"Empty slot"};
         };
-        let entry = UnitsIndexEntry::new(unit_id, offset, 0, 16, fp);
-        (unit_id, entry)
+        let entry = UnitsIndexEntry::new(unit_id, offset, 0, str_len, fp);
+        Ok((unit_id, entry))
     }
 
-    fn load_v3(fp: &mut FileBlob) -> (u16, UnitsIndexEntry) 
+    fn load_v3(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, UnitsIndexEntry)> 
 	{
-        let unit_id = fp.read_le_2bytes(BlobRegions::Units);
-        let offset = fp.read_le_3bytes(BlobRegions::Units);
+        let unit_id = fp.read_le_2bytes(BlobRegions::Units)?;
+        let offset = fp.read_le_3bytes(BlobRegions::Units)?;
         if offset == 0 {
             panic! {"Empty slot"};
         };
-        let entry = UnitsIndexEntry::new(unit_id, offset, 0, 16, fp);
-        (unit_id, entry)
+        let entry = UnitsIndexEntry::new(unit_id, offset, 0, str_len, fp);
+        Ok((unit_id, entry))
     }
 
-    fn load_v4(fp: &mut FileBlob) -> (u16, UnitsIndexEntry) 
+    fn load_v4(fp: &mut FileBlob, str_len: u16) -> io::Result<(u16, UnitsIndexEntry)> 
 	{
-        let unit_id = fp.read_le_2bytes(BlobRegions::Units);
-        let caption_off = fp.read_le_3bytes(BlobRegions::Units);
-        let tooltip_off = fp.read_le_3bytes(BlobRegions::Units);
+        let unit_id = fp.read_le_2bytes(BlobRegions::Units)?;
+        let caption_off = fp.read_le_3bytes(BlobRegions::Units)?;
+        let tooltip_off = fp.read_le_3bytes(BlobRegions::Units)?;
         if caption_off == 0 {
             panic! {"Empty slot"};
         };
-        let entry = UnitsIndexEntry::new(unit_id, caption_off, tooltip_off, 256, fp);
-        (unit_id, entry)
+        let entry = UnitsIndexEntry::new(unit_id, caption_off, tooltip_off, str_len, fp);
+        Ok((unit_id, entry))
     }
 }
 
@@ -211,10 +260,61 @@ impl Clone for UnitsIndexEntry {
     }
 }
 
-impl Iterator for UnitsIndexIterator {
+impl Iterator for UnitsIndexIterator<'_> {
     type Item = (u16, UnitsIndexEntry);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.pop()
+        self.items.next().map(|(key, entry)| (*key, entry.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::blob_from_bytes;
+    use crate::options::ParseOptions;
+
+    ///
+    /// A unit captioned "Cap" at 1 with the tooltip "Tip" at 5, and
+    /// whether the tooltip bytes are tagged as text after to_string
+    ///
+    fn to_string_touches_tooltip(options: ParseOptions) -> (String, bool, UnitsIndexEntry) {
+        let mut fp = blob_from_bytes(b"\0Cap\0Tip\0", options);
+        let entry = UnitsIndexEntry::new(1, 1, 5, 32, &mut fp);
+        let text = entry.to_string().unwrap();
+        let touched = fp.get_stats().regions.iter()
+            .any(|(start, end, region)| *region == BlobRegions::Text && (*start..*end).contains(&5));
+        (text, touched, entry)
+    }
+
+    #[test]
+    fn caption_only_decode_leaves_the_tooltip() {
+        let (text, touched, _) = to_string_touches_tooltip(ParseOptions::default());
+        assert_eq!(text, "Cap / Tip");
+        assert!(touched);
+
+        let options = ParseOptions { lazy_tooltips: true, ..ParseOptions::default() };
+        let (text, touched, entry) = to_string_touches_tooltip(options);
+        assert_eq!(text, "Cap");
+        assert!(!touched);
+        assert_eq!(entry.tooltip(), Some(Ok("Tip".to_string())));
+    }
+
+    #[test]
+    fn caption_with_a_slash_is_not_split() {
+        let mut fp = blob_from_bytes(b"\0kg / h\0Rate\0", ParseOptions::default());
+        let entry = UnitsIndexEntry::new(1, 1, 8, 32, &mut fp);
+        assert_eq!(entry.to_string(), Ok("kg / h / Rate".to_string()));
+        assert_eq!(entry.captions(), (Ok("kg / h".to_string()), Some(Ok("Rate".to_string()))));
+
+        let entry = UnitsIndexEntry::new(2, 1, 0, 32, &mut fp);
+        assert_eq!(entry.captions(), (Ok("kg / h".to_string()), None));
+    }
+
+    #[test]
+    fn offset_getters_are_distinct() {
+        let (_, _, entry) = to_string_touches_tooltip(ParseOptions::default());
+        assert_eq!(entry.get_caption_off(), 1);
+        assert_eq!(entry.get_tooltip_off(), 5);
     }
 }